@@ -20,6 +20,32 @@ pub fn init<'a>() -> ArgMatches<'a> {
             .value_name("FILE_PATH")
             .help("Log file path")
             .takes_value(true))
+        .arg(Arg::with_name("status")
+            .long("status")
+            .required(false)
+            .value_name("FILE_PATH")
+            .help("Status report file path, written on SIGUSR1")
+            .takes_value(true))
+        .arg(Arg::with_name("metrics")
+            .long("metrics")
+            .required(false)
+            .value_name("FILE_PATH")
+            .help("Prometheus metrics file path, written periodically and on SIGUSR1")
+            .takes_value(true))
+        .arg(Arg::with_name("netns")
+            .long("netns")
+            .required(false)
+            .value_name("NAME|PATH")
+            .help("Network namespaces to manage, instead of own one (comma separated or repeated)")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .use_delimiter(true))
+        .arg(Arg::with_name("netns-files")
+            .long("netns-files")
+            .required(false)
+            .hidden(true)
+            .help("Files get suffix of the namespace, set for processes of several namespaces"))
         .arg(Arg::with_name("v")
             .required(false)
             .short("v")
@@ -42,6 +68,22 @@ pub fn get_logfile<'a>(matches: &'a ArgMatches<'a>) -> Option<&'a str> {
     matches.value_of("log")
 }
 
+pub fn get_status<'a>(matches: &'a ArgMatches<'a>) -> Option<&'a str> {
+    matches.value_of("status")
+}
+
+pub fn get_metrics<'a>(matches: &'a ArgMatches<'a>) -> Option<&'a str> {
+    matches.value_of("metrics")
+}
+
+pub fn get_netns<'a>(matches: &'a ArgMatches<'a>) -> Vec<&'a str> {
+    matches.values_of("netns").map(|values| values.collect()).unwrap_or_default()
+}
+
+pub fn get_netns_files<'a>(matches: &ArgMatches<'a>) -> bool {
+    matches.is_present("netns-files")
+}
+
 pub fn get_verbosity<'a>(matches: &ArgMatches<'a>) -> u32 {
     matches.occurrences_of("v") as u32
 }
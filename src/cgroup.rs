@@ -0,0 +1,54 @@
+use nftnl::{
+    expr::Expression,
+    nftnl_sys as sys,
+    Rule,
+};
+use std::{
+    fs,
+    io,
+    os::{raw::c_char, unix::fs::MetadataExt},
+    path::Path,
+};
+
+
+// linux/netfilter/nf_tables.h
+const NFT_REG_1: u32 = 1;
+const NFT_SOCKET_CGROUPV2: u32 = 3;
+
+// Mount point of cgroup v2 hierarchy, paths of entries are relative to it
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+
+/// Id of cgroup v2 at given path, as kernel reports it for sockets.
+///
+/// Cgroup id is the inode number of its directory, thus recreated cgroup gets a new one.
+pub fn cgroup_id(path: &str) -> io::Result<u64> {
+    Ok(fs::metadata(Path::new(CGROUP_ROOT).join(path))?.ino())
+}
+
+/// Loads id of ancestor cgroup at `level` of packet socket into register 1.
+///
+/// Packets without local socket (forwarded ones, most of the input) do not match.
+pub struct SocketCgroup {
+    pub level: u32,
+}
+
+impl SocketCgroup {
+    pub fn new(path: &str) -> SocketCgroup {
+        SocketCgroup { level: path.split('/').count() as u32 }
+    }
+}
+
+impl Expression for SocketCgroup {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = sys::nftnl_expr_alloc(b"socket\0".as_ptr() as *const c_char);
+
+            sys::nftnl_expr_set_u32(expr, sys::NFTNL_EXPR_SOCKET_KEY as u16, NFT_SOCKET_CGROUPV2);
+            sys::nftnl_expr_set_u32(expr, sys::NFTNL_EXPR_SOCKET_DREG as u16, NFT_REG_1);
+            sys::nftnl_expr_set_u32(expr, sys::NFTNL_EXPR_SOCKET_LEVEL as u16, self.level);
+
+            expr
+        }
+    }
+}
@@ -1,4 +1,5 @@
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::str::FromStr;
 use std::fmt::{self, Display, Formatter};
@@ -10,7 +11,23 @@ use fancy_regex::Regex;
 use std::time::Duration;
 use byte_unit::{Byte, ByteError};
 use parse_duration;
+use chrono::{DateTime, Datelike, Local, NaiveTime, Weekday};
 use trust_dns_resolver::{Resolver, error::ResolveError, config::*};
+use maxminddb::geoip2;
+use once_cell::sync::Lazy;
+use std::sync::Mutex;
+
+
+const DEFAULT_GEOIP_DB: &str = "/usr/share/GeoIP/GeoLite2-Country.mmdb";
+
+// MaxMind format country database, "geoip <path>" directive. It is read once, into
+// (first, last) address intervals by country code, until reload
+struct GeoDb {
+    path: String,
+    countries: Option<HashMap<String, Vec<(u32, u32)>>>,
+}
+
+static GEOIP_DB: Lazy<Mutex<GeoDb>> = Lazy::new(|| Mutex::new(GeoDb { path: DEFAULT_GEOIP_DB.to_owned(), countries: None }));
 
 
 /// A type that can be converted into a int quota.
@@ -23,13 +40,124 @@ pub trait ToQuota {
 pub mod accnt {
     use super::*;
 
-    pub struct Address { pub value: Vec<Ipv4Network> }
+    #[derive(Clone)]
+    pub struct Address {
+        pub value: Vec<Ipv4Network>,
+        // Set, if addresses were resolved from domain
+        pub domain: Option<String>,
+        // Destination, which is resolved again on reload, like "@file:<path>"
+        pub source: Option<String>,
+        // Domain pattern, addresses are learned from DNS responses at runtime
+        pub snoop: Option<String>,
+        // Domain pattern, flows are tagged by TLS SNI or HTTP Host at runtime
+        pub sni: Option<String>,
+        // Entry is keyed by link layer or interface instead of address
+        pub selector: Option<Selector>,
+    }
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Selector {
+        // Source MAC, "mac:aa:bb:cc:dd:ee:ff"
+        Mac([u8; 6]),
+        // Interface, traffic comes in through, "if:<name>"
+        InIface(String),
+        // Interface, traffic goes out through, "oif:<name>"
+        OutIface(String),
+        // Local user owning the socket, "user:<name|uid>"
+        User(u32),
+        // Cgroup v2 of the socket, relative to its mount point, "cgroup:<path>"
+        Cgroup(String),
+    }
+
+    impl Selector {
+        /// Owner selectors match locally originated traffic, on output hook only.
+        pub fn is_owner(&self) -> bool {
+            matches!(self, Selector::User(_) | Selector::Cgroup(_))
+        }
+    }
+
+    // Uid of local user, numeric ids are taken as is
+    fn user_id(name: &str) -> Option<u32> {
+        if let Ok(uid) = name.parse::<u32>() {
+            return Some(uid);
+        }
+
+        let name = std::ffi::CString::new(name).ok()?;
+
+        // It is init stage, thus non reentrant lookup is fine
+        let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+
+        if passwd.is_null() {
+            None
+        } else {
+            Some(unsafe { (*passwd).pw_uid })
+        }
+    }
+
+    impl FromStr for Selector {
+        type Err = ParseAccntError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let (kind, value) = s.split_once(':').ok_or(ParseAccntError::InvalidHostFormat)?;
+
+            // IFNAMSIZ, terminating NUL included
+            let valid_iface = |name: &str| !name.is_empty() && name.len() < 16
+                && !name.contains(|c: char| c.is_whitespace() || c == '/');
+
+            match kind {
+                "mac" => {
+                    let bytes: Vec<u8> = value.split(':')
+                        .map(|byte| u8::from_str_radix(byte, 16))
+                        .collect::<Result<_, _>>()
+                        .or(Err(ParseAccntError::InvalidHostFormat))?;
+
+                    let mut mac = [0u8; 6];
+
+                    if bytes.len() != mac.len() || value.len() != 17 {
+                        return Err(ParseAccntError::InvalidHostFormat);
+                    }
+
+                    mac.copy_from_slice(&bytes);
+
+                    Ok(Selector::Mac(mac))
+                },
+                "if" if valid_iface(value) => Ok(Selector::InIface(value.to_owned())),
+                "oif" if valid_iface(value) => Ok(Selector::OutIface(value.to_owned())),
+                "user" => user_id(value).map(Selector::User).ok_or(ParseAccntError::InvalidHostFormat),
+                "cgroup" => {
+                    let path = value.trim_matches('/');
+
+                    if path.is_empty() || path.split('/').any(|part| part.is_empty() || part == "." || part == "..") {
+                        return Err(ParseAccntError::InvalidHostFormat);
+                    }
+
+                    Ok(Selector::Cgroup(path.to_owned()))
+                },
+                _ => Err(ParseAccntError::InvalidHostFormat),
+            }
+        }
+    }
+
+    impl Display for Selector {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                Selector::Mac(mac) => {
+                    let bytes: Vec<String> = mac.iter().map(|byte| format!("{:02x}", byte)).collect();
+                    write!(f, "mac:{}", bytes.join(":"))
+                },
+                Selector::InIface(name) => write!(f, "if:{}", name),
+                Selector::OutIface(name) => write!(f, "oif:{}", name),
+                Selector::User(uid) => write!(f, "user:{}", uid),
+                Selector::Cgroup(path) => write!(f, "cgroup:{}", path),
+            }
+        }
+    }
 
     impl FromStr for Address {
         type Err = ResolveError;
 
         fn from_str(s: &str) -> Result<Self, Self::Err> {
-            let mut ip_addrs = Address { value: Vec::new() };
+            let mut ip_addrs = Address { value: Vec::new(), domain: Some(s.to_owned()), source: None, snoop: None, sni: None, selector: None };
 
             // It is init stage, thus resolution is synchronous
             let resolver = Resolver::new(
@@ -37,7 +165,7 @@ pub mod accnt {
                 ResolverOpts::default()).unwrap();
             
             if !s.is_empty() {
-                let response = resolver.lookup_ip(s).unwrap();
+                let response = resolver.lookup_ip(s)?;
 
                 for address in response.iter() {
                     // We're working with IPv4 only
@@ -54,16 +182,707 @@ pub mod accnt {
         }
     }
 
-    pub struct Accounting<T: ToQuota> {
+    /// How sessions of time quota entry are detected.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Tracking {
+        // TCP "SYN & ACK" and "FIN | RST" flags, logged by nftables rules
+        Tcp,
+        // Conntrack NEW and DESTROY events, for any L4 protocol
+        Conntrack,
+    }
+
+    impl FromStr for Tracking {
+        type Err = ParseAccntError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "tcp" => Ok(Tracking::Tcp),
+                "conntrack" | "ct" => Ok(Tracking::Conntrack),
+                _ => Err(ParseAccntError::InvalidOption(s.to_owned())),
+            }
+        }
+    }
+
+    /// Rejection kind for "reject" action.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Rejection {
+        TcpReset,
+        PortUnreachable,
+        HostUnreachable,
+        AdminProhibited,
+        NoRoute,
+    }
+
+    impl FromStr for Rejection {
+        type Err = ParseAccntError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "tcp-reset" => Ok(Rejection::TcpReset),
+                "port-unreachable" => Ok(Rejection::PortUnreachable),
+                "host-unreachable" => Ok(Rejection::HostUnreachable),
+                "admin-prohibited" => Ok(Rejection::AdminProhibited),
+                "no-route" => Ok(Rejection::NoRoute),
+                _ => Err(ParseAccntError::InvalidOption(s.to_owned())),
+            }
+        }
+    }
+
+    /// What happens to traffic of entry, which went over its quota.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Action {
+        Drop,
+        Reject(Rejection),
+        // Soft quota, overflow is only reported
+        Log,
+        // Packets are marked with given meta mark, lower 16 bits only
+        Mark(u32),
+        // Traffic is limited to given rate (bytes per second)
+        Throttle(u64),
+    }
+
+    impl Action {
+        /// Whether action affects traffic at all.
+        pub fn is_enforcing(&self) -> bool {
+            *self != Action::Log
+        }
+    }
+
+    impl FromStr for Action {
+        type Err = ParseAccntError;
+
+        // "drop", "reject", "reject:tcp-reset", "log", "mark:0x10", "throttle:1mbit"
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let (kind, arg) = match s.split_once(':') {
+                Some((kind, arg)) => (kind, Some(arg)),
+                None => (s, None),
+            };
+
+            match (kind, arg) {
+                ("drop", None) => Ok(Action::Drop),
+                ("reject", None) => Ok(Action::Reject(Rejection::PortUnreachable)),
+                ("reject", Some(rejection)) => Ok(Action::Reject(rejection.parse::<Rejection>()?)),
+                ("log", None) => Ok(Action::Log),
+                ("mark", Some(mark)) => {
+                    let mark = match mark.strip_prefix("0x") {
+                        Some(hex) => u32::from_str_radix(hex, 16),
+                        None => mark.parse::<u32>(),
+                    };
+
+                    // Upper half of packet mark holds overlap claims
+                    match mark {
+                        Ok(mark) if mark <= 0xffff => Ok(Action::Mark(mark)),
+                        _ => Err(ParseAccntError::InvalidOption(s.to_owned())),
+                    }
+                },
+                ("throttle", Some(rate)) => Ok(Action::Throttle(parse_rate(rate)?)),
+                _ => Err(ParseAccntError::InvalidOption(s.to_owned())),
+            }
+        }
+    }
+
+    /// Parses rate like "10mbit" or "1mb" into bytes per second.
+    pub fn parse_rate(s: &str) -> Result<u64, ParseAccntError> {
+        let reg_bit_rate = Regex::new(r"^([0-9]+)(kbit|mbit|gbit)$").unwrap();
+
+        if let Some(caps) = reg_bit_rate.captures(s).unwrap() {
+            let value = caps[1].parse::<u64>()
+                .or(Err(ParseAccntError::InvalidOption(s.to_owned())))?;
+
+            let multiplier = match &caps[2] {
+                "kbit" => 1_000,
+                "mbit" => 1_000_000,
+                _ => 1_000_000_000,
+            };
+
+            return Ok(value * multiplier / 8);
+        }
+
+        Ok(Byte::from_str(s)?.get_bytes() as u64)
+    }
+
+    /// Parses tiers like "1gb->10mbit,2gb->1mbit,3gb->drop".
+    ///
+    /// Rate stands for throttling, anything else is parsed as action.
+    pub fn parse_tiers(s: &str) -> Result<Vec<(Byte, Action)>, ParseAccntError> {
+        let mut tiers: Vec<(Byte, Action)> = Vec::new();
+
+        for tier in s.split(',') {
+            let (threshold, action) = tier.split_once("->")
+                .ok_or(ParseAccntError::InvalidOption(tier.to_owned()))?;
+
+            let threshold = Byte::from_str(threshold)?;
+
+            let action = match parse_rate(action) {
+                Ok(rate) => Action::Throttle(rate),
+                Err(_) => action.parse::<Action>()?,
+            };
+
+            // Tiers have to be ascending
+            if let Some((last, _)) = tiers.last() {
+                if last.get_bytes() >= threshold.get_bytes() {
+                    return Err(ParseAccntError::InvalidOption(tier.to_owned()));
+                }
+            }
+
+            tiers.push((threshold, action));
+        }
+
+        Ok(tiers)
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum WindowKind {
+        // Traffic is blocked inside the window
+        Block,
+        // Traffic is allowed only inside the window
+        Allow,
+    }
+
+    /// Time-of-day access window, like "block@22:00-07:00".
+    #[derive(Debug, Clone)]
+    pub struct Window {
+        pub kind: WindowKind,
+        pub start: NaiveTime,
+        pub end: NaiveTime,
+        // Days, on which window starts (Monday first), all if empty
+        pub days: Vec<Weekday>,
+    }
+
+    impl Window {
+        fn starts_on(&self, day: Weekday) -> bool {
+            self.days.is_empty() || self.days.contains(&day)
+        }
+
+        /// Checks if given moment falls into the window.
+        ///
+        /// Evaluated on local wall clock each time, thus DST changes and
+        /// restarts in the middle of the window need no special care.
+        pub fn contains(&self, now: &DateTime<Local>) -> bool {
+            let time = now.time();
+            let today = now.weekday();
+
+            if self.start < self.end {
+                self.starts_on(today) && time >= self.start && time < self.end
+            } else {
+                // Overnight window, part after midnight belongs to the previous day
+                (time >= self.start && self.starts_on(today))
+                    || (time < self.end && self.starts_on(today.pred()))
+            }
+        }
+
+        /// Checks if traffic should be blocked at given moment.
+        pub fn is_blocking(&self, now: &DateTime<Local>) -> bool {
+            match self.kind {
+                WindowKind::Block => self.contains(now),
+                WindowKind::Allow => !self.contains(now),
+            }
+        }
+    }
+
+    impl FromStr for Window {
+        type Err = ParseAccntError;
+
+        // "block@22:00-07:00", "allow@18:00-20:00"
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            let (kind, span) = s.split_once('@').ok_or(ParseAccntError::InvalidQuotaFormat)?;
+
+            let kind = match kind {
+                "block" => WindowKind::Block,
+                "allow" => WindowKind::Allow,
+                _ => return Err(ParseAccntError::InvalidQuotaFormat),
+            };
+
+            let (start, end) = span.split_once('-').ok_or(ParseAccntError::InvalidQuotaFormat)?;
+
+            let (start, end) = match (
+                NaiveTime::parse_from_str(start, "%H:%M"),
+                NaiveTime::parse_from_str(end, "%H:%M")) {
+                (Ok(start), Ok(end)) if start != end => (start, end),
+                _ => return Err(ParseAccntError::InvalidQuotaFormat),
+            };
+
+            Ok(Window { kind, start, end, days: Vec::new() })
+        }
+    }
+
+    /// Parses days like "mon-fri", "sat,sun", "weekdays" or "weekend".
+    pub fn parse_days(s: &str) -> Result<Vec<Weekday>, ParseAccntError> {
+        let err = || ParseAccntError::InvalidOption(s.to_owned());
+        let mut days = Vec::new();
+
+        for part in s.split(',') {
+            let (from, to) = match part {
+                "weekdays" => (Weekday::Mon, Weekday::Fri),
+                "weekend" => (Weekday::Sat, Weekday::Sun),
+                _ => match part.split_once('-') {
+                    Some((from, to)) => (from.parse().or_else(|_| Err(err()))?, to.parse().or_else(|_| Err(err()))?),
+                    None => {
+                        let day: Weekday = part.parse().or_else(|_| Err(err()))?;
+                        (day, day)
+                    },
+                },
+            };
+
+            let mut day = from;
+
+            loop {
+                if !days.contains(&day) {
+                    days.push(day);
+                }
+
+                if day == to {
+                    break;
+                }
+
+                day = day.succ();
+            }
+        }
+
+        Ok(days)
+    }
+
+    /// Traffic, which is neither accounted nor blocked.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Exempt {
+        Net(Ipv4Network),
+        // L4 protocol number and remote port
+        Port(u8, u16),
+    }
+
+    /// Parses exemptions like "10.0.0.0/8,updates.example,tcp:443,port:53".
+    ///
+    /// "port:" stands for both TCP and UDP, domains are resolved right away.
+    pub fn parse_exempt(s: &str) -> Result<Vec<Exempt>, ParseAccntError> {
+        let err = || ParseAccntError::InvalidOption(s.to_owned());
+        let mut exempt = Vec::new();
+
+        for part in s.split(',') {
+            let port = |value: &str| value.parse::<u16>().or_else(|_| Err(err()));
+
+            match part.split_once(':') {
+                Some(("tcp", value)) => exempt.push(Exempt::Port(libc::IPPROTO_TCP as u8, port(value)?)),
+                Some(("udp", value)) => exempt.push(Exempt::Port(libc::IPPROTO_UDP as u8, port(value)?)),
+                Some(("port", value)) => {
+                    exempt.push(Exempt::Port(libc::IPPROTO_TCP as u8, port(value)?));
+                    exempt.push(Exempt::Port(libc::IPPROTO_UDP as u8, port(value)?));
+                },
+                Some(_) => return Err(err()),
+                None => match part.parse::<Ipv4Network>() {
+                    Ok(net) => exempt.push(Exempt::Net(net)),
+                    Err(_) if part.contains(|c: char| c.is_ascii_alphabetic()) => {
+                        for net in part.parse::<Address>()?.value {
+                            exempt.push(Exempt::Net(net));
+                        }
+                    },
+                    Err(_) => return Err(err()),
+                },
+            }
+        }
+
+        Ok(exempt)
+    }
+
+    // Inclusive address interval
+    pub type Range = (u32, u32);
+
+    // Networks of all countries in GeoIP database, read in one pass
+    fn load_geoip(path: &str) -> Result<HashMap<String, Vec<Range>>, ParseAccntError> {
+        let geo_err = |e: maxminddb::MaxMindDBError| ParseAccntError::GeoIp(format!("{}: {}", path, e));
+
+        let reader = maxminddb::Reader::open_readfile(path).or_else(|e| Err(geo_err(e)))?;
+        let mut countries: HashMap<String, Vec<Range>> = HashMap::new();
+
+        for item in reader.within::<geoip2::Country>("0.0.0.0/0".parse().unwrap()).or_else(|e| Err(geo_err(e)))? {
+            let item = item.or_else(|e| Err(geo_err(e)))?;
+
+            let code = match item.info.country.and_then(|country| country.iso_code) {
+                Some(code) => code.to_ascii_uppercase(),
+                None => continue,
+            };
+
+            // Database might be of IPv6 layout, with IPv4 mapped into it
+            if let Ok(net) = item.ip_net.to_string().parse::<Ipv4Network>() {
+                countries.entry(code).or_insert_with(Vec::new).push((net.network().into(), net.broadcast().into()));
+            }
+        }
+
+        Ok(countries)
+    }
+
+    // Networks of given country, as found in GeoIP database
+    fn geo_ranges(country: &str) -> Result<Vec<Range>, ParseAccntError> {
+        let mut db = GEOIP_DB.lock().unwrap();
+
+        if db.countries.is_none() {
+            db.countries = Some(load_geoip(&db.path)?);
+        }
+
+        match db.countries.as_ref().unwrap().get(&country.to_ascii_uppercase()) {
+            Some(ranges) => Ok(ranges.clone()),
+            None => Err(ParseAccntError::GeoIp(format!("no networks of country {}", country))),
+        }
+    }
+
+    fn parse_ranges(item: &str) -> Result<Vec<Range>, ParseAccntError> {
+        if let Some(country) = item.strip_prefix("geo:") {
+            return geo_ranges(country);
+        }
+
+        // Dash of a domain ("my-host.example") does not make a range
+        let range = item.split_once('-')
+            .and_then(|(from, to)| Some((from.parse::<std::net::Ipv4Addr>().ok()?, to)));
+
+        if let Some((from, to)) = range {
+            let from: u32 = from.into();
+            let to: u32 = to.parse::<std::net::Ipv4Addr>().or(Err(ParseAccntError::InvalidHostFormat))?.into();
+
+            if from > to {
+                return Err(ParseAccntError::InvalidHostFormat);
+            }
+
+            return Ok(vec![(from, to)]);
+        }
+
+        match item.parse::<Ipv4Network>() {
+            Ok(net) => Ok(vec![(net.network().into(), net.broadcast().into())]),
+            Err(_) if item.contains(|c: char| c.is_ascii_alphabetic()) => {
+                Ok(item.parse::<Address>()?.value.iter()
+                    .map(|net| (net.network().into(), net.broadcast().into()))
+                    .collect())
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Sorts and joins overlapping or adjacent intervals.
+    pub fn merge_ranges(mut ranges: Vec<Range>) -> Vec<Range> {
+        ranges.sort();
+
+        let mut merged: Vec<Range> = Vec::new();
+
+        for (from, to) in ranges {
+            match merged.last_mut() {
+                Some(last) if from as u64 <= last.1 as u64 + 1 => last.1 = last.1.max(to),
+                _ => merged.push((from, to)),
+            }
+        }
+
+        merged
+    }
+
+    // Smallest set of CIDRs, covering exactly given intervals
+    fn ranges_to_networks(ranges: &[Range]) -> Vec<Ipv4Network> {
+        let mut nets = Vec::new();
+
+        for (from, to) in ranges.iter() {
+            let (mut from, to) = (*from as u64, *to as u64);
+
+            while from <= to {
+                // Largest block, aligned on start and not crossing the end
+                let mut size = if from == 0 { 1u64 << 32 } else { 1u64 << from.trailing_zeros() };
+
+                while from + size - 1 > to {
+                    size >>= 1;
+                }
+
+                let prefix = 32 - size.trailing_zeros() as u8;
+                nets.push(Ipv4Network::new((from as u32).into(), prefix).unwrap());
+
+                from += size;
+            }
+        }
+
+        nets
+    }
+
+    /// Parses destination like "10.0.0.10-10.0.0.50,10.1.0.0/16,!10.1.2.0/24".
+    ///
+    /// Items are CIDRs, ranges, domains or countries ("geo:LT"), negated ones are cut out of the rest
+    /// (or out of the whole address space, if there are no others). Result is
+    /// converted into exact CIDR cover.
+    pub fn parse_selector(s: &str) -> Result<Address, ParseAccntError> {
+        let (mut include, mut exclude) = (Vec::new(), Vec::new());
+
+        for item in s.split(',') {
+            match item.strip_prefix('!') {
+                Some(item) => exclude.extend(parse_ranges(item)?),
+                None => include.extend(parse_ranges(item)?),
+            }
+        }
+
+        if include.is_empty() {
+            include.push((0, u32::MAX));
+        }
+
+        let mut ranges = merge_ranges(include);
+
+        for (ex_from, ex_to) in merge_ranges(exclude) {
+            ranges = ranges.into_iter().flat_map(|(from, to)| {
+                let mut rest = Vec::new();
+
+                if ex_to < from || ex_from > to {
+                    rest.push((from, to));
+                } else {
+                    if ex_from > from {
+                        rest.push((from, ex_from - 1));
+                    }
+
+                    if ex_to < to {
+                        rest.push((ex_to + 1, to));
+                    }
+                }
+
+                rest
+            }).collect();
+        }
+
+        let source = if needs_resolving(s) { Some(s.to_owned()) } else { None };
+
+        Ok(Address { value: ranges_to_networks(&ranges), domain: None, source, snoop: None, sni: None, selector: None })
+    }
+
+    /// Tells if selector names countries or domains, which are resolved again on reload, as
+    /// database gets updated and DNS records change. Addresses, networks and ranges have no letters.
+    pub fn needs_resolving(selector: &str) -> bool {
+        selector.contains(|c: char| c.is_ascii_alphabetic())
+    }
+
+    /// Sets path of GeoIP database, used for "geo:" selectors.
+    pub fn set_geoip_db(path: &str) {
+        let mut db = GEOIP_DB.lock().unwrap();
+
+        db.path = path.to_owned();
+        db.countries = None;
+    }
+
+    /// Forgets networks read from GeoIP database, next "geo:" selector reads it again.
+    pub fn reload_geoip() {
+        GEOIP_DB.lock().unwrap().countries = None;
+    }
+
+    /// Loads addresses from file, holding one CIDR, range or hostname per line.
+    ///
+    /// Empty lines and "#" comments are skipped.
+    pub fn parse_list_file(path: &str) -> Result<Address, ParseAccntError> {
+        let list_err = |line: usize, reason: String| ParseAccntError::ListFile(path.to_owned(), line as u32, reason);
+
+        let file = File::open(path).or_else(|e| Err(list_err(0, e.to_string())))?;
+        let mut ranges = Vec::new();
+
+        for (i, line) in io::BufReader::new(file).lines().enumerate() {
+            let line = line.or_else(|e| Err(list_err(i + 1, e.to_string())))?;
+            let item = line.split('#').next().unwrap_or("").trim();
+
+            if item.is_empty() {
+                continue;
+            }
+
+            ranges.extend(parse_ranges(item).or_else(|e| Err(list_err(i + 1, format!("{}: {}", item, e))))?);
+        }
+
+        Ok(Address {
+            value: ranges_to_networks(&merge_ranges(ranges)),
+            domain: None,
+            source: Some(format!("@file:{}", path)),
+            snoop: None,
+            sni: None,
+            selector: None,
+        })
+    }
+
+    // Address of entry, which is matched by something else than address. It spans
+    // all addresses, thus flushing, exempting or sharing them with pool would hit
+    // unrelated traffic
+    fn wildcard_address(opts: &Options) -> Result<Address, ParseAccntError> {
+        if opts.parent.is_some() {
+            return Err(ParseAccntError::InvalidOption("parent".to_owned()));
+        }
+
+        if opts.kill {
+            return Err(ParseAccntError::InvalidOption("kill".to_owned()));
+        }
+
+        if !opts.exempt.is_empty() {
+            return Err(ParseAccntError::InvalidOption("exempt".to_owned()));
+        }
+
+        Ok(Address {
+            value: vec![Ipv4Network::new(std::net::Ipv4Addr::UNSPECIFIED, 0).unwrap()],
+            domain: None,
+            source: None,
+            snoop: None,
+            sni: None,
+            selector: None,
+        })
+    }
+
+    /// Resolves destination, which might change while running (see `Address::source`).
+    pub fn resolve_source(source: &str) -> Result<Address, ParseAccntError> {
+        match source.strip_prefix("@file:") {
+            Some(path) => parse_list_file(path),
+            None => parse_selector(source),
+        }
+    }
+
+    /// Optional "key=value" settings, following the quota field.
+    #[derive(Debug, Clone)]
+    pub struct Options {
+        pub track: Tracking,
+        // Time quota counts only while packets flow, pausing after this gap
+        pub idle: Option<Duration>,
+        // Flush conntrack entries and reset TCP connections on block
+        pub kill: bool,
+        // Enforcement action, defaults depend on quota type
+        pub action: Option<Action>,
+        // Data thresholds with actions, taking effect before the quota
+        pub tiers: Vec<(Byte, Action)>,
+        // Days of access window
+        pub days: Vec<Weekday>,
+        // Unused allowance is carried over, up to this many periods
+        pub rollover: Option<u32>,
+        // Name of the pool, which traffic of the entry is charged to as well
+        pub parent: Option<String>,
+        // Traffic of the entry, which bypasses accounting and blocking
+        pub exempt: Vec<Exempt>,
+        // Overlapping entry with highest priority is charged for the packet
+        pub priority: i32,
+    }
+
+    impl Default for Options {
+        fn default() -> Self {
+            Options {
+                track: Tracking::Tcp,
+                idle: None,
+                kill: false,
+                action: None,
+                tiers: Vec::new(),
+                days: Vec::new(),
+                rollover: None,
+                parent: None,
+                exempt: Vec::new(),
+                priority: 0,
+            }
+        }
+    }
+
+    pub fn parse_switch(field: &str, value: &str) -> Result<bool, ParseAccntError> {
+        match value {
+            "on" | "yes" | "true" => Ok(true),
+            "off" | "no" | "false" => Ok(false),
+            _ => Err(ParseAccntError::InvalidOption(field.to_owned())),
+        }
+    }
+
+    impl Options {
+        fn parse(fields: &[&str]) -> Result<Options, ParseAccntError> {
+            let mut opts = Options::default();
+
+            for field in fields {
+                let (key, value) = match field.split_once('=') {
+                    Some((key, value)) if !value.is_empty() => (key, value),
+                    _ => return Err(ParseAccntError::InvalidOption(field.to_string())),
+                };
+
+                match key {
+                    "track" => opts.track = value.parse::<Tracking>()?,
+                    "idle" => opts.idle = Some(parse_duration::parse(value)?),
+                    "kill" => opts.kill = parse_switch(field, value)?,
+                    "action" => opts.action = Some(value.parse::<Action>()?),
+                    "tiers" => opts.tiers = parse_tiers(value)?,
+                    "days" => opts.days = parse_days(value)?,
+                    "parent" => opts.parent = Some(value.to_owned()),
+                    "exempt" => opts.exempt = parse_exempt(value)?,
+                    "priority" => opts.priority = value.parse::<i32>()
+                        .or_else(|_| Err(ParseAccntError::InvalidOption(field.to_string())))?,
+                    "rollover" => opts.rollover = match value.parse::<u32>() {
+                        Ok(max) if max > 0 => Some(max),
+                        _ => return Err(ParseAccntError::InvalidOption(field.to_string())),
+                    },
+                    _ => return Err(ParseAccntError::InvalidOption(field.to_string())),
+                }
+            }
+
+            Ok(opts)
+        }
+    }
+
+    /// Reset period of quota, aligned to local calendar.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Period {
+        Never,
+        Day,
+        Week,
+        Month,
+        // Sliding window of given length
+        Rolling(Duration),
+    }
+
+    impl Period {
+        /// Returns id of period, which given moment belongs to.
+        ///
+        /// Quota is reset when id changes, thus it is computed from wall clock.
+        pub fn id(&self, now: &DateTime<Local>) -> Option<i64> {
+            match self {
+                Period::Never => None,
+                Period::Day => Some(now.num_days_from_ce() as i64),
+                Period::Week => Some(now.iso_week().year() as i64 * 100 + now.iso_week().week() as i64),
+                Period::Month => Some(now.year() as i64 * 12 + now.month0() as i64),
+                // Never reset, old consumption ages out of the window
+                Period::Rolling(_) => None,
+            }
+        }
+    }
+
+    impl Display for Period {
+        fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+            match self {
+                Period::Never => write!(f, "total"),
+                Period::Day => write!(f, "day"),
+                Period::Week => write!(f, "week"),
+                Period::Month => write!(f, "month"),
+                Period::Rolling(window) => write!(f, "rolling {}s", window.as_secs()),
+            }
+        }
+    }
+
+    impl FromStr for Period {
+        type Err = ParseAccntError;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "day" | "d" => Ok(Period::Day),
+                "week" | "w" => Ok(Period::Week),
+                "month" | "mo" => Ok(Period::Month),
+                // "rolling24h"
+                _ => match s.strip_prefix("rolling") {
+                    Some(window) => match parse_duration::parse(window)? {
+                        window if window.as_secs() > 0 => Ok(Period::Rolling(window)),
+                        _ => Err(ParseAccntError::InvalidQuotaFormat),
+                    },
+                    None => Err(ParseAccntError::InvalidQuotaFormat),
+                },
+            }
+        }
+    }
+
+    pub struct Accounting<T> {
         // Traffic id
         pub addr: Address,
         // Quota size
         pub quota: T,
+        // Quota reset period
+        pub period: Period,
+        // Entry settings
+        pub opts: Options,
+        // Config line, shared by all quotas of the entry
+        pub line: u32,
     }
 
     pub enum QuotaType {
         Time(Accounting<Duration>),
         Data(Accounting<Byte>),
+        Window(Accounting<Window>),
     }
 
     // impl Address {
@@ -111,6 +930,12 @@ pub mod accnt {
 
         InvalidHostFormat,
         InvalidQuotaFormat,
+        // Unknown or malformed "key=value" option
+        InvalidOption(String),
+        // Address list file (path, line, reason), line is 0 if file is not readable
+        ListFile(String, u32, String),
+        // GeoIP database is missing, broken or has no such country
+        GeoIp(String),
         // Unhandled
         UnknownError
     }
@@ -152,12 +977,104 @@ pub mod accnt {
                 ParseTimeQuota(e) => write!(f, "error parsing time quota: {}", e),
                 DNSError(e) => write!(f, "error in dns resolution: {}", e),
                 ParseIp(e) => write!(f, "error parsing ip addr: {}", e),
+                InvalidOption(o) => write!(f, "invalid option: {}", o),
+                InvalidHostFormat => write!(f, "invalid destination"),
+                InvalidQuotaFormat => write!(f, "invalid quota"),
+                ListFile(path, 0, reason) => write!(f, "error reading {}: {}", path, reason),
+                ListFile(path, line, reason) => write!(f, "error in {}:{}: {}", path, line, reason),
+                GeoIp(e) => write!(f, "geoip error: {}", e),
                 _ => write!(f, "unknown error!"),
             }
         }
     }
 
-    impl FromStr for QuotaType {
+    /// Config line, which might carry several quotas for the same addresses.
+    pub struct Entry {
+        pub quotas: Vec<QuotaType>,
+        // Set for "pool:<name>" lines, addresses are taken from pool members
+        pub pool: Option<String>,
+    }
+
+    impl QuotaType {
+        // Single quota, like "1gb", "2h/day" or "block@22:00-07:00"
+        fn new(s: &str, addr: &Address, opts: &Options) -> Result<Self, ParseAccntError> {
+            let reg_data_quota = Regex::new(r"^[0-9]+(kb|mb|gb|kib|mib|gib)$").unwrap();
+        
+            let reg_time_quota = Regex::new(r"^[0-9]+(s|m|h)$").unwrap();
+
+            let addr = addr.clone();
+            let mut opts = opts.clone();
+
+            let (quota_str, period) = match s.split_once('/') {
+                Some((quota_str, period)) => (quota_str, period.parse::<Period>()?),
+                None => (s, Period::Never),
+            };
+
+            let rolling = matches!(period, Period::Rolling(_));
+
+            if reg_time_quota.is_match(quota_str).unwrap() {
+                let quota = parse_duration::parse(quota_str)?;
+
+                // Sliding window is implemented for data only
+                if rolling {
+                    return Err(ParseAccntError::InvalidQuotaFormat);
+                }
+
+                if !opts.tiers.is_empty() {
+                    return Err(ParseAccntError::InvalidOption("tiers".to_owned()));
+                }
+
+                if opts.rollover.is_some() {
+                    return Err(ParseAccntError::InvalidOption("rollover".to_owned()));
+                }
+
+                return Ok(QuotaType::Time( Accounting {addr, quota, period, opts, line: 0 } ));
+            } else if reg_data_quota.is_match(quota_str).unwrap() {
+                let quota = Byte::from_str(quota_str)?;
+
+                // Last tier may sit at the quota itself, its action is the one of the quota then
+                if opts.tiers.last().map_or(false, |(threshold, _)| threshold.get_bytes() == quota.get_bytes()) {
+                    let (_, action) = opts.tiers.pop().unwrap();
+
+                    if opts.action.map_or(false, |entry_action| entry_action != action) {
+                        return Err(ParseAccntError::InvalidOption("tiers".to_owned()));
+                    }
+
+                    opts.action = Some(action);
+                }
+
+                // Tiers are steps before the quota, not defined for sliding window
+                if (rolling && !opts.tiers.is_empty())
+                    || opts.tiers.iter().any(|(threshold, _)| threshold.get_bytes() >= quota.get_bytes()) {
+                    return Err(ParseAccntError::InvalidOption("tiers".to_owned()));
+                }
+
+                // Nothing to carry without calendar reset
+                if opts.rollover.is_some() && matches!(period, Period::Never | Period::Rolling(_)) {
+                    return Err(ParseAccntError::InvalidOption("rollover".to_owned()));
+                }
+
+                return Ok(QuotaType::Data( Accounting {addr, quota, period, opts, line: 0 } ));
+            } else if quota_str.contains('@') && period == Period::Never {
+                let mut quota = quota_str.parse::<Window>()?;
+                quota.days = opts.days.clone();
+
+                if !opts.tiers.is_empty() {
+                    return Err(ParseAccntError::InvalidOption("tiers".to_owned()));
+                }
+
+                if opts.rollover.is_some() {
+                    return Err(ParseAccntError::InvalidOption("rollover".to_owned()));
+                }
+
+                return Ok(QuotaType::Window( Accounting {addr, quota, period, opts, line: 0 } ));
+            }
+
+            Err(ParseAccntError::InvalidQuotaFormat)
+        }
+    }
+
+    impl FromStr for Entry {
         type Err = ParseAccntError;
 
         // TODO write test for this one
@@ -166,7 +1083,23 @@ pub mod accnt {
             // "94.142.241.111/32 2m"
             // "# <any info>"
             // "youtube.com 20kb"
-            // kb, mb, gb OR s, m, h
+            // "youtube.com 2h track=conntrack"
+            // "chat.example 1h idle=30s"
+            // "10.0.0.0/8 5gb action=throttle:1mbit"
+            // "10.0.0.0/8 3gb tiers=1gb->10mbit,2gb->1mbit"
+            // "94.142.245.189/32 block@22:00-07:00 days=mon-fri"
+            // "192.168.5.137/32 1gb/day,20gb/month,2h/day"
+            // "192.168.5.137/32 500mb/rolling24h"
+            // "192.168.5.137/32 1gb/day rollover=7"
+            // "pool:family 100gb/month"
+            // "192.168.5.137/32 20gb/month parent=family"
+            // "0.0.0.0/0 2gb exempt=10.1.1.5,port:53"
+            // "192.168.5.137/32 1gb priority=10"
+            // "10.0.0.10-10.0.0.50,!10.0.0.32/30 1gb"
+            // "@file:/etc/netcontrol/lists/streaming.txt 5gb"
+            // "geo:CN 1gb", "!geo:LT 10gb/month"
+            // "dns:*.googlevideo.com 5gb"
+            // kb, mb, gb OR s, m, h, optionally per day, week, month or rolling window
 
             let reg_cidr = Regex::new(
                 concat!(
@@ -183,10 +1116,10 @@ pub mod accnt {
                     r"[a-z0-9\-]{0,60}|[a-z0-9-]{1,30}\.[a-z]{2,})$",
                 )
             ).unwrap();
-        
-            let reg_data_quota = Regex::new(r"^[0-9]+(kb|mb|gb|kib|mib|gib)$").unwrap();
-        
-            let reg_time_quota = Regex::new(r"^[0-9]+(s|m|h)$").unwrap();
+
+            let reg_pool = Regex::new(r"^[a-z0-9_-]+$").unwrap();
+
+            let reg_range = Regex::new(r"^[0-9]{1,3}(\.[0-9]{1,3}){3}-[0-9]{1,3}(\.[0-9]{1,3}){3}$").unwrap();
 
             match s.len() {
                 0 => return Err(ParseAccntError::Empty),
@@ -195,55 +1128,261 @@ pub mod accnt {
                         return Err(ParseAccntError::InnactiveEntry);
                     }
 
-                    let v: Vec<_> = s.split_whitespace().collect();
+                    let v: Vec<_> = s.split_whitespace().collect();
+
+                    let (dest_str, quota_str, opts) = match &v[..] {
+                        [dest_str, quota_str, opts @ ..] => (dest_str.to_owned(), quota_str, opts),
+                                    _ => return Err(ParseAccntError::BadLen)
+                    };
+
+                    let opts = Options::parse(opts)?;
+
+                    // Days make sense for access windows only
+                    if !opts.days.is_empty() && !quota_str.contains('@') {
+                        return Err(ParseAccntError::InvalidOption("days".to_owned()));
+                    }
+
+                    let mut addr = Address { value: Vec::new(), domain: None, source: None, snoop: None, sni: None, selector: None };
+                    let mut pool = None;
+                    
+                    // TODO this one is crippled
+                    if let Some(name) = dest_str.strip_prefix("pool:") {
+                        if !reg_pool.is_match(name).unwrap() {
+                            return Err(ParseAccntError::InvalidHostFormat);
+                        }
+
+                        // Pools do not nest
+                        if opts.parent.is_some() {
+                            return Err(ParseAccntError::InvalidOption("parent".to_owned()));
+                        }
+
+                        pool = Some(name.to_owned());
+                    } else if let Some(pattern) = dest_str.strip_prefix("dns:") {
+                        // "*.example.com" or "example.com"
+                        if !reg_domain.is_match(pattern.strip_prefix("*.").unwrap_or(pattern)).unwrap() {
+                            return Err(ParseAccntError::InvalidHostFormat);
+                        }
+
+                        addr.snoop = Some(pattern.to_owned());
+                    } else if let Some(pattern) = dest_str.strip_prefix("sni:") {
+                        if !reg_domain.is_match(pattern.strip_prefix("*.").unwrap_or(pattern)).unwrap() {
+                            return Err(ParseAccntError::InvalidHostFormat);
+                        }
 
-                    let (dest_str, quota_str) = match &v[..] {
-                        [dest_str, quota_str] => (dest_str.to_owned(), quota_str),
-                                    _ => return Err(ParseAccntError::BadLen)
-                    };
+                        // Classified flows are told apart by conntrack mark
+                        addr = wildcard_address(&opts)?;
+                        addr.sni = Some(pattern.to_owned());
+                    } else if ["mac:", "if:", "oif:", "user:", "cgroup:"].iter().any(|kind| dest_str.starts_with(kind)) {
+                        let selector = dest_str.parse::<Selector>()?;
 
-                    let mut addr = Address { value: Vec::new() };
-                    
-                    // TODO this one is crippled
-                    if reg_cidr.is_match(dest_str).unwrap() {
+                        addr = wildcard_address(&opts)?;
+                        addr.selector = Some(selector);
+                    } else if dest_str.starts_with('@') {
+                        addr = resolve_source(dest_str)?;
+                    } else if reg_cidr.is_match(dest_str).unwrap() {
                         addr.value.push(dest_str.parse::<Ipv4Network>()?);
+                    } else if dest_str.contains(',') || dest_str.starts_with('!') || dest_str.starts_with("geo:")
+                        || reg_range.is_match(dest_str).unwrap() {
+                        addr = parse_selector(dest_str)?;
                     } else if reg_domain.is_match(dest_str).unwrap() {
                         addr = dest_str.parse::<Address>()?;
                     } else {
                         return Err(ParseAccntError::InvalidHostFormat);
                     }
 
-                    if reg_time_quota.is_match(quota_str).unwrap() {
-                        let quota = parse_duration::parse(quota_str)?;
-                        return Ok(QuotaType::Time( Accounting {addr, quota } ));
-                    } else if reg_data_quota.is_match(quota_str).unwrap() {
-                        let quota = Byte::from_str(quota_str)?;
-                        return Ok(QuotaType::Data( Accounting {addr, quota } ));
+                    let mut entry = Entry { quotas: Vec::new(), pool };
+
+                    // Whichever of the quotas runs out first, blocks
+                    for quota in quota_str.split(',') {
+                        let quota = QuotaType::new(quota, &addr, &opts)?;
+
+                        // Pool is shared data allowance, flows are classified and selected for data accounting only
+                        if (entry.pool.is_some() || addr.sni.is_some() || addr.selector.is_some())
+                            && !matches!(quota, QuotaType::Data(_)) {
+                            return Err(ParseAccntError::InvalidQuotaFormat);
+                        }
+
+                        entry.quotas.push(quota);
                     }
 
-                    return Err(ParseAccntError::InvalidQuotaFormat);
+                    Ok(entry)
                 }
             }
         }
     }
 }
 
+use accnt::Address;
+use accnt::Entry;
+use accnt::Exempt;
 use accnt::QuotaType;
+use accnt::Window;
 use accnt::Accounting as Acc;
 use accnt::ParseAccntError as AccErr;
 
 
+/// Shared data allowance, which member entries are charged to as well.
+#[derive(Debug, Clone)]
+pub struct Pool {
+    pub name: String,
+    // Config line of the pool entry
+    pub line: u32,
+    // Config lines of member entries
+    pub members: Vec<u32>,
+}
+
+/// Data entry template of LAN clients, which are not listed in config.
+#[derive(Debug, Clone)]
+pub struct Clients {
+    // Quota field and options, as they follow destination on entry line
+    pub template: String,
+    // Prefixes, new clients are taken from
+    pub lans: Vec<Ipv4Network>,
+    // dnsmasq leases file, watched for clients besides conntrack
+    pub leases: Option<String>,
+    // File, which keeps addresses of learned clients across restarts
+    pub state: String,
+}
+
+impl Default for Clients {
+    fn default() -> Clients {
+        Clients { template: String::new(), lans: Vec::new(), leases: None, state: DEFAULT_CLIENT_STATE.to_owned() }
+    }
+}
+
+impl Clients {
+    /// Data quotas of client entry at given address.
+    pub fn entry(&self, addr: std::net::Ipv4Addr) -> Result<Vec<Acc<Byte>>, AccErr> {
+        let entry = format!("{}/32 {}", addr, self.template).parse::<Entry>()?;
+
+        entry.quotas.into_iter()
+            .map(|quota| match quota {
+                // Pools are put together at load, they do not take members later
+                QuotaType::Data(a) if a.opts.parent.is_some() => Err(AccErr::InvalidOption("parent".to_owned())),
+                // Exemptions go ahead of all entry rules, which is set up at start only
+                QuotaType::Data(a) if !a.opts.exempt.is_empty() => Err(AccErr::InvalidOption("exempt".to_owned())),
+                QuotaType::Data(a) => Ok(a),
+                _ => Err(AccErr::InvalidQuotaFormat),
+            })
+            .collect()
+    }
+}
+
 pub struct Config {
     pub data: Vec<Acc<Byte>>,
     pub time: Vec<Acc<Duration>>,
+    pub windows: Vec<Acc<Window>>,
+    pub pools: Vec<Pool>,
+    // Global exemptions, "exempt <list>" directive
+    pub exempt: Vec<Exempt>,
+    // Traffic, which is never blocked, "allow <list>" directive on top of defaults
+    pub allow: Vec<Exempt>,
+    // What to do about overlapping entries of the same priority
+    pub overlap: OverlapMode,
+    // Length of top talker lists of data entries, 0 turns per address counters off
+    pub talkers: usize,
+    // Count traffic, which matches no entry, "unmatched on" directive
+    pub unmatched: bool,
+    // Automatic entries of LAN clients, "default_client_quota <quota> [options]" directive
+    pub clients: Option<Clients>,
+    // Network namespaces to manage, "netns <name|path>,..." directive
+    pub netns: Vec<String>,
+    // Table family and hooks rules are put in, "family ipv4|bridge|netdev <dev>" directive
+    pub family: Family,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Family {
+    // Traffic of this host, input and output hooks
+    Ipv4,
+    // Traffic between bridge ports, forward hook both ways
+    Bridge,
+    // Traffic arriving on the device, ingress hook
+    Netdev(String),
+}
+
+impl FromStr for Family {
+    type Err = AccErr;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("ipv4"), None, _) => Ok(Family::Ipv4),
+            (Some("bridge"), None, _) => Ok(Family::Bridge),
+            // IFNAMSIZ, terminating NUL included
+            (Some("netdev"), Some(dev), None) if dev.len() < 16 => Ok(Family::Netdev(dev.to_owned())),
+            _ => Err(AccErr::InvalidOption(s.to_owned())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverlapMode {
+    Warn,
+    Fail,
+}
+
+// Data and time quotas are charged independently, entries of one kind never overlap the other
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum QuotaKind {
+    Data,
+    Time,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OverlapKind {
+    // Same network is listed twice
+    Duplicate,
+    // Network of the first entry contains network of the second one
+    Contains,
+    // Domain resolves into address of the other entry
+    Domain,
+}
+
+/// Two config lines, which match the same traffic.
+#[derive(Debug, Clone)]
+pub struct Overlap {
+    pub kind: OverlapKind,
+    pub quota: QuotaKind,
+    pub lines: (u32, u32),
+    pub net: Ipv4Network,
+    // Priorities differ, thus it is clear which entry is charged
+    pub resolved: bool,
+}
+
+impl Display for Overlap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let (a, b) = (self.lines.0 + 1, self.lines.1 + 1);
+
+        match self.kind {
+            OverlapKind::Duplicate => write!(f, "lines {} and {} both list {}", a, b, self.net),
+            OverlapKind::Contains => write!(f, "line {} shadows {} of line {}", a, self.net, b),
+            OverlapKind::Domain => write!(f, "lines {} and {} collide on {} through domain resolution", a, b, self.net),
+        }
+    }
 }
 
+const DEFAULT_TALKERS: usize = 5;
+const DEFAULT_CLIENT_STATE: &str = "/var/lib/netcontrol/clients";
+
+// Loopback, DHCP, DNS and NTP
+const DEFAULT_ALLOW: &str = "127.0.0.0/8,udp:67,udp:68,port:53,udp:123";
+
 #[derive(Debug)]
 pub enum ParseConfigError {
     // File not found or whateva ...
     FileError,
     // Parse line error
     EntryError(AccErr,u32),
+    // Member refers to pool, which is not defined
+    UnknownPool(String, u32),
+    // Pool name is used twice
+    DuplicatePool(String, u32),
+    // Entries of the same priority match the same traffic
+    Overlap(Overlap),
+    // Client template is given, but there is no LAN to take clients from
+    NoClientLan,
     // Other error
     UnknownError,
 }
@@ -257,6 +1396,10 @@ impl Display for ParseConfigError {
         match self {
             FileError => write!(f, "empty line entry"),
             EntryError(e,i) => write!(f, "error parsing line {0}: {1}", i, e),
+            UnknownPool(name, i) => write!(f, "error on line {0}: unknown pool {1}", i, name),
+            DuplicatePool(name, i) => write!(f, "error on line {0}: pool {1} is already defined", i, name),
+            Overlap(o) => write!(f, "overlapping entries: {}", o),
+            NoClientLan => write!(f, "default_client_quota needs client_lan"),
             _ => write!(f, "unknown error!"),
         }
     }
@@ -275,21 +1418,208 @@ impl Config {
         Config { 
             data: Vec::new(),
             time: Vec::new(),
+            windows: Vec::new(),
+            pools: Vec::new(),
+            exempt: Vec::new(),
+            allow: accnt::parse_exempt(DEFAULT_ALLOW).unwrap(),
+            overlap: OverlapMode::Warn,
+            talkers: DEFAULT_TALKERS,
+            unmatched: false,
+            clients: None,
+            netns: Vec::new(),
+            family: Family::Ipv4,
+        }
+    }
+
+    // Quota lines of given kind as (line, priority, addresses), pools excluded
+    fn entry_lines(&self, quota: QuotaKind) -> Vec<(u32, i32, &Address)> {
+        let mut lines: Vec<(u32, i32, &Address)> = Vec::new();
+
+        let entries: Vec<(u32, i32, &Address)> = match quota {
+            QuotaKind::Data => self.data.iter().map(|a| (a.line, a.opts.priority, &a.addr)).collect(),
+            QuotaKind::Time => self.time.iter().map(|a| (a.line, a.opts.priority, &a.addr)).collect(),
+        };
+
+        for (line, priority, addr) in entries {
+            // Classified and selected flows are not told apart by address
+            if !lines.iter().any(|entry| entry.0 == line) && !self.pools.iter().any(|pool| pool.line == line)
+                && addr.sni.is_none() && addr.selector.is_none() {
+                lines.push((line, priority, addr));
+            }
+        }
+
+        lines
+    }
+
+    /// Finds quota lines of the same kind, which match the same traffic.
+    pub fn overlaps(&self) -> Vec<Overlap> {
+        let mut overlaps = Vec::new();
+
+        for quota in [QuotaKind::Data, QuotaKind::Time].iter() {
+            overlaps.extend(self.kind_overlaps(*quota));
+        }
+
+        overlaps
+    }
+
+    fn kind_overlaps(&self, quota: QuotaKind) -> Vec<Overlap> {
+        let lines = self.entry_lines(quota);
+        let mut overlaps = Vec::new();
+
+        for (pos, (line_a, prio_a, addr_a)) in lines.iter().enumerate() {
+            for (line_b, prio_b, addr_b) in lines[pos + 1..].iter() {
+                for net_a in addr_a.value.iter() {
+                    for net_b in addr_b.value.iter() {
+                        let (kind, lines, net) = if net_a == net_b {
+                            (OverlapKind::Duplicate, (*line_a, *line_b), *net_a)
+                        } else if net_a.contains(net_b.network()) && net_a.prefix() < net_b.prefix() {
+                            (OverlapKind::Contains, (*line_a, *line_b), *net_b)
+                        } else if net_b.contains(net_a.network()) && net_b.prefix() < net_a.prefix() {
+                            (OverlapKind::Contains, (*line_b, *line_a), *net_a)
+                        } else {
+                            continue;
+                        };
+
+                        let kind = match addr_a.domain.is_some() || addr_b.domain.is_some() {
+                            true => OverlapKind::Domain,
+                            false => kind,
+                        };
+
+                        overlaps.push(Overlap { kind, quota, lines, net, resolved: prio_a != prio_b });
+                    }
+                }
+            }
+        }
+
+        overlaps
+    }
+
+    /// Returns overlapping quota lines of given kind with their networks, in the
+    /// order they claim traffic: by priority, then by position in config.
+    pub fn claims(&self, quota: QuotaKind) -> Vec<(u32, Vec<Ipv4Network>)> {
+        let overlaps = self.kind_overlaps(quota);
+        let mut lines: Vec<(u32, i32, &Address)> = self.entry_lines(quota).into_iter()
+            .filter(|(line, _, _)| overlaps.iter().any(|o| o.lines.0 == *line || o.lines.1 == *line))
+            .collect();
+
+        lines.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+
+        lines.into_iter().map(|(line, _, addr)| (line, addr.value.clone())).collect()
+    }
+
+    // Global settings lines, like "exempt 10.1.1.5,port:53". Returns None for quota entries
+    fn parse_directive(&mut self, line: &str) -> Option<Result<(), AccErr>> {
+        let (key, value) = line.trim().split_once(char::is_whitespace)?;
+
+        match key {
+            "exempt" => Some(accnt::parse_exempt(value.trim()).map(|exempt| self.exempt.extend(exempt))),
+            "allow" => Some(accnt::parse_exempt(value.trim()).map(|allow| self.allow.extend(allow))),
+            // Taken before any entry, see new_from_file
+            "geoip" => Some(Ok(())),
+            "overlap" => {
+                self.overlap = match value.trim() {
+                    "warn" => OverlapMode::Warn,
+                    "fail" => OverlapMode::Fail,
+                    _ => return Some(Err(AccErr::InvalidOption(line.to_owned()))),
+                };
+
+                Some(Ok(()))
+            },
+            "talkers" => match value.trim().parse::<usize>() {
+                Ok(talkers) => {
+                    self.talkers = talkers;
+
+                    Some(Ok(()))
+                },
+                Err(_) => Some(Err(AccErr::InvalidOption(line.to_owned()))),
+            },
+            "unmatched" => Some(accnt::parse_switch(line, value.trim()).map(|unmatched| self.unmatched = unmatched)),
+            "default_client_quota" => {
+                let clients = self.clients.get_or_insert_with(Clients::default);
+
+                clients.template = value.trim().to_owned();
+
+                // Template is checked on an address, which is never a client
+                Some(clients.entry(std::net::Ipv4Addr::UNSPECIFIED).map(|_| ()))
+            },
+            "client_lan" => match value.trim().parse::<Ipv4Network>() {
+                Ok(net) => {
+                    self.clients.get_or_insert_with(Clients::default).lans.push(net);
+
+                    Some(Ok(()))
+                },
+                Err(_) => Some(Err(AccErr::InvalidHostFormat)),
+            },
+            "client_leases" => {
+                self.clients.get_or_insert_with(Clients::default).leases = Some(value.trim().to_owned());
+
+                Some(Ok(()))
+            },
+            "client_state" => {
+                self.clients.get_or_insert_with(Clients::default).state = value.trim().to_owned();
+
+                Some(Ok(()))
+            },
+            "netns" => {
+                self.netns = value.split(',').map(|netns| netns.trim().to_owned()).filter(|netns| !netns.is_empty()).collect();
+
+                Some(Ok(()))
+            },
+            "family" => Some(value.trim().parse::<Family>().map(|family| self.family = family)),
+            _ => None,
         }
     }
 
     // TODO needs to return some Result as well
     pub fn new_from_file(filepath: &str) -> Result<Config, ParseConfigError> {
         let mut conf = Config::new();
+        // (line, pool name, addresses) of pool members
+        let mut members: Vec<(u32, String, Vec<Ipv4Network>)> = Vec::new();
+
+        // Database applies to "geo:" items of all lines, wherever the directive is
+        if let Ok(lines) = Self::read_file(Path::new(filepath)) {
+            for line in lines.flatten() {
+                if let Some(("geoip", path)) = line.trim().split_once(char::is_whitespace) {
+                    accnt::set_geoip_db(path.trim());
+                }
+            }
+        }
 
         if let Ok(lines) = Self::read_file(Path::new(filepath)) {
             for (i, line) in lines.enumerate() {
                 if let Ok(line) = line {
-                    match line.as_str().parse::<QuotaType>() {
+                    match conf.parse_directive(&line) {
+                        Some(Ok(())) => continue,
+                        Some(Err(e)) => return Err(ParseConfigError::EntryError(e, i as u32)),
+                        None => (),
+                    }
+
+                    match line.as_str().parse::<Entry>() {
                         Ok(entry) => {
-                            match entry {
-                                QuotaType::Data(a) => conf.data.push(a),
-                                QuotaType::Time(a) => conf.time.push(a),
+                            if let Some(name) = entry.pool {
+                                if conf.pools.iter().any(|pool| pool.name == name) {
+                                    return Err(ParseConfigError::DuplicatePool(name, i as u32));
+                                }
+
+                                conf.pools.push(Pool { name, line: i as u32, members: Vec::new() });
+                            }
+
+                            let parent = entry.quotas.first().and_then(|quota| match quota {
+                                QuotaType::Data(a) => a.opts.parent.clone().map(|p| (p, a.addr.value.clone())),
+                                QuotaType::Time(a) => a.opts.parent.clone().map(|p| (p, a.addr.value.clone())),
+                                QuotaType::Window(a) => a.opts.parent.clone().map(|p| (p, a.addr.value.clone())),
+                            });
+
+                            if let Some((parent, addrs)) = parent {
+                                members.push((i as u32, parent, addrs));
+                            }
+
+                            for quota in entry.quotas {
+                                match quota {
+                                    QuotaType::Data(mut a) => { a.line = i as u32; conf.data.push(a) },
+                                    QuotaType::Time(mut a) => { a.line = i as u32; conf.time.push(a) },
+                                    QuotaType::Window(mut a) => { a.line = i as u32; conf.windows.push(a) },
+                                }
                             }
                         }
                         Err(AccErr::InnactiveEntry) => continue,
@@ -299,6 +1629,38 @@ impl Config {
             }
         }
 
+        // Pool accounts traffic of all its members
+        for (line, parent, addrs) in members {
+            let pool = match conf.pools.iter_mut().find(|pool| pool.name == parent) {
+                Some(pool) => pool,
+                None => return Err(ParseConfigError::UnknownPool(parent, line)),
+            };
+
+            pool.members.push(line);
+
+            for data in conf.data.iter_mut().filter(|a| a.line == pool.line) {
+                for net in addrs.iter() {
+                    if !data.addr.value.contains(net) {
+                        data.addr.value.push(*net);
+                    }
+                }
+            }
+        }
+
+        if let Some(clients) = conf.clients.as_ref() {
+            if clients.lans.is_empty() {
+                return Err(ParseConfigError::NoClientLan);
+            }
+        }
+
+        // Overlaps of different priorities are intended, the rest are likely mistakes
+        for overlap in conf.overlaps().into_iter().filter(|o| !o.resolved) {
+            match conf.overlap {
+                OverlapMode::Warn => log::warn!("{}, earlier line is charged", overlap),
+                OverlapMode::Fail => return Err(ParseConfigError::Overlap(overlap)),
+            }
+        }
+
         Ok(conf)
     }
 
@@ -361,3 +1723,438 @@ fn regex_test() {
     assert!(reg_time_quota.is_match("11m").unwrap());
     assert!(!reg_time_quota.is_match("5215fgf").unwrap());
 }
+
+#[test]
+fn action_test() {
+    use accnt::{Action, Rejection, parse_rate};
+
+    assert_eq!("drop".parse::<Action>().unwrap(), Action::Drop);
+    assert_eq!("reject".parse::<Action>().unwrap(), Action::Reject(Rejection::PortUnreachable));
+    assert_eq!("reject:tcp-reset".parse::<Action>().unwrap(), Action::Reject(Rejection::TcpReset));
+    assert_eq!("log".parse::<Action>().unwrap(), Action::Log);
+    assert_eq!("mark:0x10".parse::<Action>().unwrap(), Action::Mark(16));
+    assert_eq!("mark:7".parse::<Action>().unwrap(), Action::Mark(7));
+    assert_eq!("throttle:8kbit".parse::<Action>().unwrap(), Action::Throttle(1000));
+
+    assert!("reject:tcp".parse::<Action>().is_err());
+    assert!("mark".parse::<Action>().is_err());
+    assert!("mark:0x10000".parse::<Action>().is_err());
+    assert!("mark:0x10ffff".parse::<Action>().is_err());
+    assert!("drop:now".parse::<Action>().is_err());
+
+    assert_eq!(parse_rate("10mbit").unwrap(), 1_250_000);
+    assert_eq!(parse_rate("1mb").unwrap(), 1_000_000);
+    assert!(parse_rate("fast").is_err());
+}
+
+#[test]
+fn tiers_test() {
+    use accnt::{Action, parse_tiers};
+
+    let tiers = parse_tiers("1gb->10mbit,2gb->1mbit,3gb->drop").unwrap();
+
+    assert_eq!(tiers.len(), 3);
+    assert_eq!(tiers[0].0.get_bytes(), 1_000_000_000);
+    assert_eq!(tiers[0].1, Action::Throttle(1_250_000));
+    assert_eq!(tiers[1].1, Action::Throttle(125_000));
+    assert_eq!(tiers[2].1, Action::Drop);
+
+    assert!(parse_tiers("2gb->1mbit,1gb->10mbit").is_err());
+    assert!(parse_tiers("1gb:10mbit").is_err());
+    assert!(parse_tiers("1gb->slow").is_err());
+
+    // Tier at the quota stands for action of the quota
+    let entry = "10.0.0.0/24 3gb tiers=1gb->10mbit,2gb->1mbit,3gb->drop".parse::<Entry>().unwrap();
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(data)] => {
+            assert_eq!(data.opts.tiers.len(), 2);
+            assert_eq!(data.opts.action, Some(Action::Drop));
+        },
+        _ => panic!("unexpected quotas"),
+    }
+
+    let entry = "10.0.0.0/24 3gb tiers=1gb->10mbit,3gb->1mbit".parse::<Entry>().unwrap();
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(data)] => assert_eq!(data.opts.action, Some(Action::Throttle(125_000))),
+        _ => panic!("unexpected quotas"),
+    }
+
+    assert!("10.0.0.0/24 3gb tiers=1gb->10mbit,3gb->drop action=drop".parse::<Entry>().is_ok());
+    assert!("10.0.0.0/24 3gb tiers=1gb->10mbit,3gb->drop action=log".parse::<Entry>().is_err());
+    assert!("10.0.0.0/24 3gb tiers=1gb->10mbit,4gb->drop".parse::<Entry>().is_err());
+}
+
+#[test]
+fn window_test() {
+    use accnt::{parse_days, WindowKind};
+    use chrono::TimeZone;
+
+    let mut window = "block@22:00-07:00".parse::<Window>().unwrap();
+    window.days = parse_days("mon-fri").unwrap();
+
+    assert_eq!(window.kind, WindowKind::Block);
+
+    // 2021-10-15 is Friday
+    assert!(window.is_blocking(&Local.ymd(2021, 10, 15).and_hms(23, 0, 0)));
+    assert!(window.is_blocking(&Local.ymd(2021, 10, 16).and_hms(6, 59, 0)));
+    assert!(!window.is_blocking(&Local.ymd(2021, 10, 16).and_hms(7, 0, 0)));
+    // Saturday night is not in the window, Monday morning belongs to Sunday
+    assert!(!window.is_blocking(&Local.ymd(2021, 10, 16).and_hms(23, 0, 0)));
+    assert!(!window.is_blocking(&Local.ymd(2021, 10, 18).and_hms(1, 0, 0)));
+
+    // Across midnight on any day: start is inside, end is not
+    let window = "block@22:00-07:00".parse::<Window>().unwrap();
+
+    assert!(!window.contains(&Local.ymd(2021, 10, 15).and_hms(21, 59, 59)));
+    assert!(window.contains(&Local.ymd(2021, 10, 15).and_hms(22, 0, 0)));
+    assert!(window.contains(&Local.ymd(2021, 10, 15).and_hms(23, 59, 59)));
+    assert!(window.contains(&Local.ymd(2021, 10, 16).and_hms(0, 0, 0)));
+    assert!(window.contains(&Local.ymd(2021, 10, 16).and_hms(6, 59, 59)));
+    assert!(!window.contains(&Local.ymd(2021, 10, 16).and_hms(7, 0, 0)));
+    assert!(!window.contains(&Local.ymd(2021, 10, 16).and_hms(12, 0, 0)));
+
+    // Sunday night only: Monday morning is in, Sunday morning and Monday night are not
+    let mut window = "block@23:30-00:30".parse::<Window>().unwrap();
+    window.days = parse_days("sun").unwrap();
+
+    assert!(!window.contains(&Local.ymd(2021, 10, 17).and_hms(0, 15, 0)));
+    assert!(window.contains(&Local.ymd(2021, 10, 17).and_hms(23, 45, 0)));
+    assert!(window.contains(&Local.ymd(2021, 10, 18).and_hms(0, 15, 0)));
+    assert!(!window.contains(&Local.ymd(2021, 10, 18).and_hms(0, 30, 0)));
+    assert!(!window.contains(&Local.ymd(2021, 10, 18).and_hms(23, 45, 0)));
+
+    let window = "allow@18:00-20:00".parse::<Window>().unwrap();
+
+    assert!(window.is_blocking(&Local.ymd(2021, 10, 16).and_hms(17, 59, 0)));
+    assert!(!window.is_blocking(&Local.ymd(2021, 10, 16).and_hms(18, 30, 0)));
+
+    assert_eq!(parse_days("weekend").unwrap().len(), 2);
+    assert_eq!(parse_days("fri-mon").unwrap().len(), 4);
+    assert!(parse_days("someday").is_err());
+    assert!("block@22:00".parse::<Window>().is_err());
+    assert!("never@22:00-07:00".parse::<Window>().is_err());
+    assert!("block@07:00-07:00".parse::<Window>().is_err());
+}
+
+#[test]
+fn entry_test() {
+    use accnt::Period;
+
+    let entry = "192.168.5.137/32 1gb/day,20gb/month,2h/day".parse::<Entry>().unwrap();
+
+    assert_eq!(entry.quotas.len(), 3);
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(daily), QuotaType::Data(monthly), QuotaType::Time(time)] => {
+            assert_eq!(daily.period, Period::Day);
+            assert_eq!(monthly.period, Period::Month);
+            assert_eq!(monthly.quota.get_bytes(), 20_000_000_000);
+            assert_eq!(time.quota, Duration::from_secs(2 * 3600));
+        },
+        _ => panic!("unexpected quotas"),
+    }
+
+    let entry = "192.168.5.137/32 500mb/rolling24h".parse::<Entry>().unwrap();
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(rolling)] => assert_eq!(rolling.period, Period::Rolling(Duration::from_secs(24 * 3600))),
+        _ => panic!("unexpected quotas"),
+    }
+
+    assert!("192.168.5.137/32 2h/rolling24h".parse::<Entry>().is_err());
+    assert!("192.168.5.137/32 1gb/year".parse::<Entry>().is_err());
+    assert!("192.168.5.137/32 block@22:00-07:00/day".parse::<Entry>().is_err());
+
+    let entry = "192.168.5.137/32 1gb/day rollover=7".parse::<Entry>().unwrap();
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(daily)] => assert_eq!(daily.opts.rollover, Some(7)),
+        _ => panic!("unexpected quotas"),
+    }
+
+    assert!("192.168.5.137/32 1gb rollover=7".parse::<Entry>().is_err());
+    assert!("192.168.5.137/32 2h/day rollover=7".parse::<Entry>().is_err());
+    assert!("192.168.5.137/32 1gb/day rollover=0".parse::<Entry>().is_err());
+
+    let entry = "pool:family 100gb/month action=throttle:1mbit".parse::<Entry>().unwrap();
+
+    assert_eq!(entry.pool.as_deref(), Some("family"));
+
+    let entry = "192.168.5.137/32 20gb/month parent=family".parse::<Entry>().unwrap();
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(member)] => assert_eq!(member.opts.parent.as_deref(), Some("family")),
+        _ => panic!("unexpected quotas"),
+    }
+
+    assert!("pool:family 2h/day".parse::<Entry>().is_err());
+
+    let entry = "dns:*.googlevideo.com 5gb".parse::<Entry>().unwrap();
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(snooped)] => {
+            assert_eq!(snooped.addr.snoop.as_deref(), Some("*.googlevideo.com"));
+            assert!(snooped.addr.value.is_empty());
+        },
+        _ => panic!("unexpected quotas"),
+    }
+
+    assert!("dns:*.-bad 5gb".parse::<Entry>().is_err());
+
+    let entry = "sni:*.nflxvideo.net 10gb/month".parse::<Entry>().unwrap();
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(classified)] => assert_eq!(classified.addr.sni.as_deref(), Some("*.nflxvideo.net")),
+        _ => panic!("unexpected quotas"),
+    }
+
+    assert!("sni:netflix.com 2h".parse::<Entry>().is_err());
+    assert!("sni:netflix.com 10gb kill=on".parse::<Entry>().is_err());
+
+    let entry = "mac:aa:bb:cc:dd:ee:0f 5gb".parse::<Entry>().unwrap();
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(device)] => assert_eq!(device.addr.selector, Some(accnt::Selector::Mac([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x0f]))),
+        _ => panic!("unexpected quotas"),
+    }
+
+    let entry = "if:wlan-guest 10gb".parse::<Entry>().unwrap();
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(guest)] => assert_eq!(guest.addr.selector, Some(accnt::Selector::InIface("wlan-guest".to_owned()))),
+        _ => panic!("unexpected quotas"),
+    }
+
+    assert!("mac:aa:bb:cc:dd:ee 5gb".parse::<Entry>().is_err());
+    assert!("mac:aa:bb:cc:dd:ee:zz 5gb".parse::<Entry>().is_err());
+    assert!("if:wlan-guest 2h".parse::<Entry>().is_err());
+    assert!("oif:an-interface-name-too-long 1gb".parse::<Entry>().is_err());
+
+    let entry = "cgroup:/system.slice/backup.service 50gb".parse::<Entry>().unwrap();
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(backup)] => assert_eq!(backup.addr.selector, Some(accnt::Selector::Cgroup("system.slice/backup.service".to_owned()))),
+        _ => panic!("unexpected quotas"),
+    }
+
+    let entry = "user:root 10gb".parse::<Entry>().unwrap();
+
+    match &entry.quotas[..] {
+        [QuotaType::Data(root)] => assert_eq!(root.addr.selector, Some(accnt::Selector::User(0))),
+        _ => panic!("unexpected quotas"),
+    }
+
+    assert!("user:1000 1gb".parse::<Entry>().is_ok());
+    assert!("user:no-such-user-here 1gb".parse::<Entry>().is_err());
+    assert!("cgroup:../escape 1gb".parse::<Entry>().is_err());
+    assert!("pool:family 100gb parent=other".parse::<Entry>().is_err());
+}
+
+#[test]
+fn exempt_test() {
+    use accnt::parse_exempt;
+
+    let exempt = parse_exempt("10.1.1.5,tcp:443,port:53").unwrap();
+
+    assert_eq!(exempt, vec![
+        Exempt::Net("10.1.1.5/32".parse().unwrap()),
+        Exempt::Port(libc::IPPROTO_TCP as u8, 443),
+        Exempt::Port(libc::IPPROTO_TCP as u8, 53),
+        Exempt::Port(libc::IPPROTO_UDP as u8, 53),
+    ]);
+
+    assert!(parse_exempt("icmp:8").is_err());
+    assert!(parse_exempt("tcp:http").is_err());
+    assert!(parse_exempt("10.1.1.300").is_err());
+
+    let mut conf = Config::new();
+
+    assert!(conf.allow.contains(&Exempt::Port(libc::IPPROTO_UDP as u8, 53)));
+    assert!(conf.parse_directive("allow 10.0.0.0/24,tcp:22").unwrap().is_ok());
+    assert!(conf.allow.contains(&Exempt::Net("10.0.0.0/24".parse().unwrap())));
+    assert!(conf.parse_directive("10.0.0.0/24 1gb").is_none());
+
+    assert_eq!(conf.talkers, 5);
+    assert!(conf.parse_directive("talkers 10").unwrap().is_ok());
+    assert_eq!(conf.talkers, 10);
+    assert!(conf.parse_directive("talkers many").unwrap().is_err());
+
+    assert!(conf.parse_directive("unmatched on").unwrap().is_ok());
+    assert!(conf.unmatched);
+    assert!(conf.parse_directive("unmatched maybe").unwrap().is_err());
+
+    assert!(conf.parse_directive("default_client_quota 5gb/day action=throttle:1mbit").unwrap().is_ok());
+    assert!(conf.parse_directive("client_lan 192.168.5.0/24").unwrap().is_ok());
+
+    let clients = conf.clients.as_ref().unwrap();
+    let quotas = clients.entry("192.168.5.20".parse().unwrap()).unwrap();
+
+    assert_eq!(quotas[0].addr.value, vec!["192.168.5.20/32".parse::<Ipv4Network>().unwrap()]);
+    assert_eq!(clients.state, DEFAULT_CLIENT_STATE);
+    assert!(conf.parse_directive("default_client_quota 2h").unwrap().is_err());
+
+    assert!(conf.parse_directive("netns rig1").unwrap().is_ok());
+    assert_eq!(conf.netns, vec!["rig1"]);
+    assert!(conf.parse_directive("netns rig1, /proc/42/ns/net").unwrap().is_ok());
+    assert_eq!(conf.netns, vec!["rig1", "/proc/42/ns/net"]);
+
+    assert_eq!(conf.family, Family::Ipv4);
+    assert!(conf.parse_directive("family netdev eth0").unwrap().is_ok());
+    assert_eq!(conf.family, Family::Netdev("eth0".to_owned()));
+    assert!(conf.parse_directive("family bridge").unwrap().is_ok());
+    assert_eq!(conf.family, Family::Bridge);
+    assert!(conf.parse_directive("family netdev").unwrap().is_err());
+    assert!(conf.parse_directive("family ipv6").unwrap().is_err());
+}
+
+#[test]
+fn overlap_test() {
+    let mut conf = Config::new();
+
+    let lines = ["0.0.0.0/0 10s", "94.142.241.111/32 2m", "94.142.241.111/32 1gb,5m priority=5", "0.0.0.0/0 5gb"];
+
+    for (i, line) in lines.iter().enumerate() {
+        for quota in line.parse::<Entry>().unwrap().quotas {
+            match quota {
+                QuotaType::Data(mut a) => { a.line = i as u32; conf.data.push(a) },
+                QuotaType::Time(mut a) => { a.line = i as u32; conf.time.push(a) },
+                QuotaType::Window(_) => (),
+            }
+        }
+    }
+
+    let overlaps = conf.overlaps();
+
+    assert_eq!(overlaps.len(), 4);
+    assert!(overlaps.iter().any(|o| o.kind == OverlapKind::Contains && o.lines == (0, 1) && !o.resolved));
+    assert!(overlaps.iter().any(|o| o.kind == OverlapKind::Duplicate && o.lines == (1, 2) && o.resolved));
+    assert!(overlaps.iter().any(|o| o.quota == QuotaKind::Data && o.lines == (3, 2) && o.resolved));
+
+    // Time quota of line 0 and data quota of line 3 are charged independently
+    assert!(!overlaps.iter().any(|o| o.lines == (0, 3) || o.lines == (3, 0)));
+
+    let lines = |quota| -> Vec<u32> { conf.claims(quota).iter().map(|(line, _)| *line).collect() };
+
+    assert_eq!(lines(QuotaKind::Time), vec![2, 0, 1]);
+    assert_eq!(lines(QuotaKind::Data), vec![2, 3]);
+}
+
+#[test]
+fn selector_test() {
+    use accnt::parse_selector;
+
+    let nets = |s: &str| -> Vec<String> {
+        parse_selector(s).unwrap().value.iter().map(|net| net.to_string()).collect()
+    };
+
+    assert_eq!(nets("10.0.0.10-10.0.0.17"), vec!["10.0.0.10/31", "10.0.0.12/30", "10.0.0.16/31"]);
+    assert_eq!(nets("10.0.0.0/25,10.0.0.128/25"), vec!["10.0.0.0/24"]);
+    assert_eq!(nets("10.0.0.0/24,!10.0.0.0/25"), vec!["10.0.0.128/25"]);
+    assert_eq!(nets("!128.0.0.0/1"), vec!["0.0.0.0/1"]);
+    assert_eq!(nets("0.0.0.0-255.255.255.255"), vec!["0.0.0.0/0"]);
+
+    assert!(parse_selector("10.0.0.50-10.0.0.10").is_err());
+    assert!(parse_selector("10.0.0.0/33").is_err());
+
+    // Domain with dash is resolved, not taken for a range
+    assert!(matches!(parse_selector("no-such-host.invalid"), Err(AccErr::DNSError(_))));
+    assert!(matches!(parse_selector("10.0.0.1-host.invalid"), Err(AccErr::InvalidHostFormat)));
+
+    // Names anywhere in the list are resolved again on reload
+    assert!(accnt::needs_resolving("10.0.0.0/24,example.com"));
+    assert!(accnt::needs_resolving("!geo:cn"));
+    assert!(!accnt::needs_resolving("10.0.0.0/24,!10.0.0.0/25,10.1.0.1-10.1.0.9"));
+}
+
+#[test]
+fn list_file_test() {
+    use accnt::parse_list_file;
+
+    let path = std::env::temp_dir().join("netcontrol_list_test.txt");
+
+    std::fs::write(&path, "# streaming\n10.0.0.0/25\n\n10.0.0.128-10.0.0.255 # rest\n").unwrap();
+
+    let addr = parse_list_file(path.to_str().unwrap()).unwrap();
+
+    assert_eq!(addr.value, vec!["10.0.0.0/24".parse::<Ipv4Network>().unwrap()]);
+    assert!(addr.source.unwrap().starts_with("@file:"));
+
+    std::fs::write(&path, "10.0.0.0/24\n10.0.0.300\n").unwrap();
+
+    match parse_list_file(path.to_str().unwrap()) {
+        Err(AccErr::ListFile(_, line, _)) => assert_eq!(line, 2),
+        _ => panic!("expected list file error"),
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn geo_test() {
+    use accnt::parse_selector;
+
+    let path = std::env::temp_dir().join("netcontrol_geo_test.conf");
+
+    // Directive applies to lines above it as well
+    std::fs::write(&path, concat!(
+        "geo:CN 1gb\n",
+        "!geo:lt 2h\n",
+        "geoip testdata/geoip/country.mmdb\n",
+    )).unwrap();
+
+    let conf = Config::new_from_file(path.to_str().unwrap()).unwrap();
+    let contains = |nets: &[Ipv4Network], addr: &str| nets.iter().any(|net| net.contains(addr.parse().unwrap()));
+
+    // Adjacent networks of the country are joined
+    assert_eq!(conf.data[0].addr.value, vec!["2.0.0.0/15".parse::<Ipv4Network>().unwrap()]);
+    assert!(conf.data[0].addr.source.is_some());
+
+    assert!(!contains(&conf.time[0].addr.value, "1.0.0.5"));
+    assert!(contains(&conf.time[0].addr.value, "1.0.1.0"));
+    assert!(contains(&conf.time[0].addr.value, "3.3.3.3"));
+
+    assert!(matches!(parse_selector("geo:XX"), Err(AccErr::GeoIp(_))));
+
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn pool_test() {
+    let path = std::env::temp_dir().join("netcontrol_pool_test.conf");
+
+    std::fs::write(&path, concat!(
+        "pool:family 100gb/month action=throttle:1mbit\n",
+        "192.168.5.137/32 20gb/month,2h/day parent=family\n",
+        "192.168.5.138/32,192.168.5.150/32 40gb/month parent=family\n",
+        "192.168.5.140/32 1gb\n",
+    )).unwrap();
+
+    let conf = Config::new_from_file(path.to_str().unwrap()).unwrap();
+
+    assert_eq!(conf.pools.len(), 1);
+    assert_eq!(conf.pools[0].members, vec![1, 2]);
+
+    // Pool block covers every member, members keep their own quotas and actions
+    let pool = conf.data.iter().find(|a| a.line == 0).unwrap();
+    let nets: Vec<String> = pool.addr.value.iter().map(|net| net.to_string()).collect();
+
+    assert_eq!(nets, vec!["192.168.5.137/32", "192.168.5.138/32", "192.168.5.150/32"]);
+    assert_eq!(pool.opts.action, Some(accnt::Action::Throttle(125_000)));
+    assert!(conf.data.iter().filter(|a| a.line == 1 || a.line == 2).all(|a| a.opts.action.is_none()));
+    assert_eq!(conf.time.iter().filter(|a| a.line == 1).count(), 1);
+
+    // Non-member is not blocked by the pool, and pool does not count as overlap of members
+    assert!(!nets.contains(&"192.168.5.140/32".to_owned()));
+    assert!(conf.overlaps().is_empty());
+
+    std::fs::write(&path, "192.168.5.137/32 20gb/month parent=family\n").unwrap();
+
+    match Config::new_from_file(path.to_str().unwrap()) {
+        Err(ParseConfigError::UnknownPool(name, line)) => assert_eq!((name.as_str(), line), ("family", 0)),
+        _ => panic!("expected unknown pool error"),
+    }
+
+    std::fs::remove_file(&path).unwrap();
+}
@@ -0,0 +1,378 @@
+use ipnetwork::Ipv4Network;
+use libc;
+use log::{debug, error, warn};
+use std::{
+    io,
+    net::Ipv4Addr,
+    thread,
+};
+use crate::netlink::{self, attrs, put_attr, NlSocket, NLA_F_NESTED, RECV_BUF_SIZE};
+
+
+// linux/netfilter/nfnetlink.h
+const NFNLGRP_CONNTRACK_NEW: u32 = 1;
+const NFNLGRP_CONNTRACK_DESTROY: u32 = 3;
+const NFNL_SUBSYS_CTNETLINK: u16 = 1;
+
+// linux/netfilter/nfnetlink_conntrack.h
+const IPCTNL_MSG_CT_NEW: u16 = 0;
+const IPCTNL_MSG_CT_GET: u16 = 1;
+const IPCTNL_MSG_CT_DELETE: u16 = 2;
+
+const CTA_TUPLE_ORIG: u16 = 1;
+const CTA_TUPLE_IP: u16 = 1;
+const CTA_TUPLE_PROTO: u16 = 2;
+const CTA_IP_V4_SRC: u16 = 1;
+const CTA_IP_V4_DST: u16 = 2;
+const CTA_PROTO_NUM: u16 = 1;
+const CTA_PROTO_SRC_PORT: u16 = 2;
+const CTA_PROTO_DST_PORT: u16 = 3;
+const CTA_MARK: u16 = 8;
+const CTA_MARK_MASK: u16 = 21;
+
+// Delete requests are sent in buffers of up to this size, well within socket send buffer
+const DELETE_BATCH_SIZE: usize = 16 * 1024;
+
+// Conntrack NEW and DESTROY events
+const CT_EVENT_GROUPS: u32 = (1 << (NFNLGRP_CONNTRACK_NEW - 1)) | (1 << (NFNLGRP_CONNTRACK_DESTROY - 1));
+
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CtEventType {
+    New,
+    Destroy,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CtEvent {
+    pub kind: CtEventType,
+    // L4 protocol number of the flow
+    pub proto: u8,
+    // Original direction tuple, ports are 0 for protocols without them
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub sport: u16,
+    pub dport: u16,
+}
+
+// Conntrack entry, as found in dump or event message
+struct CtEntry {
+    msg: u16,
+    proto: u8,
+    src: Ipv4Addr,
+    dst: Ipv4Addr,
+    sport: u16,
+    dport: u16,
+    // Raw CTA_TUPLE_ORIG payload, identifies entry for deletion
+    tuple: Vec<u8>,
+}
+
+/// Spawns a thread, which reads conntrack events and passes IPv4 ones to callback.
+pub fn listen<F>(mut cb: F) -> io::Result<thread::JoinHandle<()>>
+where F: FnMut(CtEvent) + Send + 'static {
+    let socket = NlSocket::open(CT_EVENT_GROUPS)?;
+
+    Ok(thread::spawn(move || {
+        let mut buf = vec![0u8; RECV_BUF_SIZE];
+
+        loop {
+            let len = match socket.recv(&mut buf[..]) {
+                Ok(len) => len,
+                // Events were dropped by kernel, nothing to do but carry on
+                Err(ref e) if e.raw_os_error() == Some(libc::ENOBUFS) => {
+                    warn!("conntrack event queue overrun, some sessions might be lost");
+                    continue;
+                },
+                Err(e) => {
+                    error!("conntrack socket error: {}", e);
+                    return;
+                },
+            };
+
+            for event in parse_events(&buf[..len]) {
+                debug!("conntrack event: {:?}", event);
+                cb(event);
+            }
+        }
+    }))
+}
+
+// Returns (protocol, source, destination, source port, destination port)
+fn parse_tuple(buf: &[u8]) -> Option<(u8, Ipv4Addr, Ipv4Addr, u16, u16)> {
+    let (mut proto, mut src, mut dst, mut sport, mut dport) = (None, None, None, 0, 0);
+
+    for (kind, data) in attrs(buf) {
+        match kind {
+            CTA_TUPLE_IP => {
+                for (ip_kind, ip_data) in attrs(data) {
+                    if ip_data.len() != 4 {
+                        continue;
+                    }
+
+                    let addr = Ipv4Addr::new(ip_data[0], ip_data[1], ip_data[2], ip_data[3]);
+
+                    match ip_kind {
+                        CTA_IP_V4_SRC => src = Some(addr),
+                        CTA_IP_V4_DST => dst = Some(addr),
+                        // IPv6 tuple, not our business
+                        _ => (),
+                    }
+                }
+            },
+            CTA_TUPLE_PROTO => {
+                for (proto_kind, proto_data) in attrs(data) {
+                    match (proto_kind, proto_data.len()) {
+                        (CTA_PROTO_NUM, 1) => proto = Some(proto_data[0]),
+                        (CTA_PROTO_SRC_PORT, 2) => sport = u16::from_be_bytes([proto_data[0], proto_data[1]]),
+                        (CTA_PROTO_DST_PORT, 2) => dport = u16::from_be_bytes([proto_data[0], proto_data[1]]),
+                        _ => (),
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    Some((proto?, src?, dst?, sport, dport))
+}
+
+// Parses ctnetlink messages, returns entries and whether dump is done
+fn parse_entries(buf: &[u8]) -> (Vec<CtEntry>, bool) {
+    let (msgs, done) = netlink::messages(buf, NFNL_SUBSYS_CTNETLINK);
+    let mut entries = Vec::new();
+
+    for (msg, payload) in msgs {
+        for (attr_kind, data) in attrs(payload) {
+            if attr_kind != CTA_TUPLE_ORIG {
+                continue;
+            }
+
+            if let Some((proto, src, dst, sport, dport)) = parse_tuple(data) {
+                entries.push(CtEntry { msg, proto, src, dst, sport, dport, tuple: data.to_vec() });
+            }
+        }
+    }
+
+    (entries, done)
+}
+
+pub fn parse_events(buf: &[u8]) -> Vec<CtEvent> {
+    parse_entries(buf).0.into_iter()
+        .filter_map(|entry| {
+            let kind = match entry.msg {
+                IPCTNL_MSG_CT_NEW => CtEventType::New,
+                IPCTNL_MSG_CT_DELETE => CtEventType::Destroy,
+                _ => return None,
+            };
+
+            Some(CtEvent { kind, proto: entry.proto, src: entry.src, dst: entry.dst, sport: entry.sport, dport: entry.dport })
+        })
+        .collect()
+}
+
+fn build_msg(msg: u16, flags: u16, attrs: &[(u16, &[u8])]) -> Vec<u8> {
+    netlink::build_msg(NFNL_SUBSYS_CTNETLINK, msg, flags, libc::AF_INET as u8, attrs)
+}
+
+/// Deletes conntrack entries, which have either end in given networks and are not spared.
+///
+/// Next packet of such flow is treated as new one, thus it hits block rules
+/// right away instead of being let through as established.
+pub fn flush<F>(nets: &[Ipv4Network], spare: F) -> io::Result<usize>
+where F: Fn(&CtEvent) -> bool {
+    let socket = NlSocket::open(0)?;
+
+    socket.send(&build_msg(IPCTNL_MSG_CT_GET, libc::NLM_F_DUMP as u16, &[]))?;
+
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let mut victims = Vec::new();
+
+    loop {
+        let len = socket.recv(&mut buf[..])?;
+        let (entries, done) = parse_entries(&buf[..len]);
+
+        victims.extend(entries.into_iter().filter(|entry| is_victim(entry, nets, &spare)));
+
+        if done || len == 0 {
+            break;
+        }
+    }
+
+    for entry in victims.iter() {
+        debug!("flushing conntrack entry {} -> {} (proto {})", entry.src, entry.dst, entry.proto);
+    }
+
+    // Kernel walks all messages of a buffer, thus there is one send per batch, not per entry
+    for batch in delete_batches(&victims) {
+        socket.send(&batch)?;
+    }
+
+    Ok(victims.len())
+}
+
+// Entry has either end in given networks and is not spared. Dumped entries are passed to
+// `spare` as new flows
+fn is_victim<F>(entry: &CtEntry, nets: &[Ipv4Network], spare: &F) -> bool
+where F: Fn(&CtEvent) -> bool {
+    let flow = CtEvent {
+        kind: CtEventType::New,
+        proto: entry.proto,
+        src: entry.src,
+        dst: entry.dst,
+        sport: entry.sport,
+        dport: entry.dport,
+    };
+
+    nets.iter().any(|net| net.contains(entry.src) || net.contains(entry.dst)) && !spare(&flow)
+}
+
+// Delete requests of given entries, packed into buffers of up to DELETE_BATCH_SIZE
+fn delete_batches(entries: &[CtEntry]) -> Vec<Vec<u8>> {
+    let mut batches: Vec<Vec<u8>> = Vec::new();
+
+    for entry in entries.iter() {
+        let msg = build_msg(IPCTNL_MSG_CT_DELETE, 0, &[(CTA_TUPLE_ORIG | NLA_F_NESTED, &entry.tuple[..])]);
+
+        match batches.last_mut() {
+            Some(batch) if batch.len() + msg.len() <= DELETE_BATCH_SIZE => batch.extend(msg),
+            _ => batches.push(msg),
+        }
+    }
+
+    batches
+}
+
+/// Sets bits of `mask` in mark of existing conntrack entry, given by its original direction tuple.
+///
+/// Socket is the caller's, as this runs for every new flow.
+pub fn set_mark(socket: &NlSocket, proto: u8, src: Ipv4Addr, dst: Ipv4Addr, sport: u16, dport: u16, mark: u32, mask: u32) -> io::Result<()> {
+    let mut ip = Vec::new();
+    put_attr(&mut ip, CTA_IP_V4_SRC, &src.octets());
+    put_attr(&mut ip, CTA_IP_V4_DST, &dst.octets());
+
+    let mut l4 = Vec::new();
+    put_attr(&mut l4, CTA_PROTO_NUM, &[proto]);
+    put_attr(&mut l4, CTA_PROTO_SRC_PORT, &sport.to_be_bytes());
+    put_attr(&mut l4, CTA_PROTO_DST_PORT, &dport.to_be_bytes());
+
+    let mut tuple = Vec::new();
+    put_attr(&mut tuple, CTA_TUPLE_IP | NLA_F_NESTED, &ip);
+    put_attr(&mut tuple, CTA_TUPLE_PROTO | NLA_F_NESTED, &l4);
+
+    // NEW without NLM_F_CREATE updates the entry, failing if there is none. Bits outside
+    // of the mask are kept
+    socket.send(&build_msg(
+        IPCTNL_MSG_CT_NEW, 0,
+        &[
+            (CTA_TUPLE_ORIG | NLA_F_NESTED, &tuple[..]),
+            (CTA_MARK, &mark.to_be_bytes()[..]),
+            (CTA_MARK_MASK, &mask.to_be_bytes()[..]),
+        ]))
+}
+
+
+#[test]
+fn parse_events_test() {
+    const CTA_IP_V6_SRC: u16 = 3;
+    const CTA_IP_V6_DST: u16 = 4;
+
+    let tuple = |ip_attrs: &[(u16, &[u8])], proto: u8, sport: u16, dport: u16| {
+        let mut ip = Vec::new();
+        for (kind, data) in ip_attrs {
+            put_attr(&mut ip, *kind, data);
+        }
+
+        let mut l4 = Vec::new();
+        put_attr(&mut l4, CTA_PROTO_NUM, &[proto]);
+        put_attr(&mut l4, CTA_PROTO_SRC_PORT, &sport.to_be_bytes());
+        put_attr(&mut l4, CTA_PROTO_DST_PORT, &dport.to_be_bytes());
+
+        let mut tuple = Vec::new();
+        put_attr(&mut tuple, CTA_TUPLE_IP | NLA_F_NESTED, &ip);
+        put_attr(&mut tuple, CTA_TUPLE_PROTO | NLA_F_NESTED, &l4);
+        tuple
+    };
+
+    let tcp = tuple(&[(CTA_IP_V4_SRC, &[10, 0, 0, 2]), (CTA_IP_V4_DST, &[1, 1, 1, 1])], libc::IPPROTO_TCP as u8, 40000, 443);
+    let udp = tuple(&[(CTA_IP_V4_SRC, &[10, 0, 0, 3]), (CTA_IP_V4_DST, &[8, 8, 8, 8])], libc::IPPROTO_UDP as u8, 40001, 53);
+    let ipv6 = tuple(&[(CTA_IP_V6_SRC, &[0xfe; 16]), (CTA_IP_V6_DST, &[0xfd; 16])], libc::IPPROTO_TCP as u8, 40002, 443);
+
+    let mut buf = Vec::new();
+    buf.extend(build_msg(IPCTNL_MSG_CT_NEW, 0, &[(CTA_TUPLE_ORIG | NLA_F_NESTED, &tcp[..])]));
+    buf.extend(build_msg(IPCTNL_MSG_CT_DELETE, 0, &[(CTA_TUPLE_ORIG | NLA_F_NESTED, &udp[..])]));
+    // Neither an event, nor an IPv4 flow
+    buf.extend(build_msg(IPCTNL_MSG_CT_GET, 0, &[(CTA_TUPLE_ORIG | NLA_F_NESTED, &tcp[..])]));
+    buf.extend(build_msg(IPCTNL_MSG_CT_NEW, 0, &[(CTA_TUPLE_ORIG | NLA_F_NESTED, &ipv6[..])]));
+
+    let events = parse_events(&buf);
+
+    assert_eq!(events.len(), 2);
+    assert_eq!(events[0].kind, CtEventType::New);
+    assert_eq!(events[0].proto, libc::IPPROTO_TCP as u8);
+    assert_eq!((events[0].src, events[0].dst), (Ipv4Addr::new(10, 0, 0, 2), Ipv4Addr::new(1, 1, 1, 1)));
+    assert_eq!((events[0].sport, events[0].dport), (40000, 443));
+    assert_eq!(events[1].kind, CtEventType::Destroy);
+    assert_eq!((events[1].src, events[1].dst), (Ipv4Addr::new(10, 0, 0, 3), Ipv4Addr::new(8, 8, 8, 8)));
+
+    // Truncated message is dropped, the ones before it are kept
+    let first_len = build_msg(IPCTNL_MSG_CT_NEW, 0, &[(CTA_TUPLE_ORIG | NLA_F_NESTED, &tcp[..])]).len();
+    assert_eq!(parse_events(&buf[..first_len + 10]).len(), 1);
+    assert!(parse_events(&[]).is_empty());
+}
+
+#[test]
+fn delete_batches_test() {
+    let entries: Vec<CtEntry> = (0..1000u32)
+        .map(|num| {
+            let (src, dst) = (Ipv4Addr::from(0x0a00_0000 + num), Ipv4Addr::new(1, 1, 1, 1));
+
+            let mut ip = Vec::new();
+            put_attr(&mut ip, CTA_IP_V4_SRC, &src.octets());
+            put_attr(&mut ip, CTA_IP_V4_DST, &dst.octets());
+
+            let mut l4 = Vec::new();
+            put_attr(&mut l4, CTA_PROTO_NUM, &[libc::IPPROTO_TCP as u8]);
+
+            let mut tuple = Vec::new();
+            put_attr(&mut tuple, CTA_TUPLE_IP | NLA_F_NESTED, &ip);
+            put_attr(&mut tuple, CTA_TUPLE_PROTO | NLA_F_NESTED, &l4);
+
+            CtEntry { msg: IPCTNL_MSG_CT_NEW, proto: libc::IPPROTO_TCP as u8, src, dst, sport: 0, dport: 0, tuple }
+        })
+        .collect();
+
+    let batches = delete_batches(&entries);
+
+    assert!(batches.len() > 1 && batches.len() < entries.len());
+    assert!(batches.iter().all(|batch| batch.len() <= DELETE_BATCH_SIZE));
+
+    // Every entry gets its delete request, in order
+    let deleted: Vec<CtEntry> = batches.iter().flat_map(|batch| parse_entries(batch).0).collect();
+
+    assert_eq!(deleted.len(), entries.len());
+    assert!(deleted.iter().all(|entry| entry.msg == IPCTNL_MSG_CT_DELETE));
+    assert!(deleted.iter().zip(entries.iter()).all(|(a, b)| a.src == b.src && a.tuple == b.tuple));
+
+    assert!(delete_batches(&[]).is_empty());
+}
+
+#[test]
+fn is_victim_test() {
+    let nets: Vec<Ipv4Network> = vec!["0.0.0.0/0".parse().unwrap()];
+    let entry = |proto: i32, dport: u16| CtEntry {
+        msg: IPCTNL_MSG_CT_NEW,
+        proto: proto as u8,
+        src: Ipv4Addr::new(10, 0, 0, 2),
+        dst: Ipv4Addr::new(1, 1, 1, 1),
+        sport: 40000,
+        dport,
+        tuple: Vec::new(),
+    };
+    // SSH flows are let through, like allowlist does
+    let spare = |flow: &CtEvent| flow.proto == libc::IPPROTO_TCP as u8 && flow.dport == 22;
+
+    assert!(is_victim(&entry(libc::IPPROTO_TCP, 443), &nets, &spare));
+    assert!(!is_victim(&entry(libc::IPPROTO_TCP, 22), &nets, &spare));
+    assert!(is_victim(&entry(libc::IPPROTO_UDP, 22), &nets, &spare));
+    assert!(!is_victim(&entry(libc::IPPROTO_TCP, 443), &["192.168.0.0/16".parse().unwrap()], &spare));
+}
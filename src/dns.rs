@@ -0,0 +1,106 @@
+use libc;
+use std::net::Ipv4Addr;
+use trust_dns_proto::{
+    op::{Message, MessageType},
+    rr::RData,
+};
+
+
+const IPV4_MIN_HEADER_LEN: usize = 20;
+const UDP_HEADER_LEN: usize = 8;
+
+
+/// Names and IPv4 addresses (with TTL), found in DNS response.
+#[derive(Debug, Default)]
+pub struct DnsAnswer {
+    // Queried names and owners of answer records, CNAME targets included
+    pub names: Vec<String>,
+    pub addrs: Vec<(Ipv4Addr, u32)>,
+}
+
+/// Parses DNS response, carried in IPv4 UDP packet.
+pub fn parse_response(packet: &[u8]) -> Option<DnsAnswer> {
+    if packet.len() < IPV4_MIN_HEADER_LEN || packet[0] >> 4 != 4 || packet[9] != libc::IPPROTO_UDP as u8 {
+        return None;
+    }
+
+    let header_len = (packet[0] & 0x0f) as usize * 4;
+    let message = Message::from_vec(packet.get(header_len + UDP_HEADER_LEN..)?).ok()?;
+
+    if message.message_type() != MessageType::Response {
+        return None;
+    }
+
+    let mut answer = DnsAnswer::default();
+
+    for query in message.queries() {
+        answer.names.push(normalize(&query.name().to_utf8()));
+    }
+
+    for record in message.answers() {
+        answer.names.push(normalize(&record.name().to_utf8()));
+
+        if let RData::A(addr) = record.rdata() {
+            answer.addrs.push((*addr, record.ttl()));
+        }
+    }
+
+    Some(answer)
+}
+
+fn normalize(name: &str) -> String {
+    name.trim_end_matches('.').to_ascii_lowercase()
+}
+
+/// Checks name against pattern, "*.example.com" matches subdomains only.
+pub fn matches(pattern: &str, name: &str) -> bool {
+    let name = normalize(name);
+
+    match pattern.strip_prefix("*.") {
+        Some(suffix) => name.len() > suffix.len() && name.ends_with(suffix)
+            && name.as_bytes()[name.len() - suffix.len() - 1] == b'.',
+        None => name == pattern,
+    }
+}
+
+
+#[test]
+fn matches_test() {
+    assert!(matches("*.googlevideo.com", "r3---sn-4g5e6nzz.googlevideo.com."));
+    assert!(!matches("*.googlevideo.com", "googlevideo.com"));
+    assert!(!matches("*.googlevideo.com", "notgooglevideo.com"));
+    assert!(matches("youtube.com", "YouTube.com."));
+    assert!(!matches("youtube.com", "www.youtube.com"));
+}
+
+#[test]
+fn parse_response_test() {
+    use std::str::FromStr;
+    use trust_dns_proto::{
+        op::Query,
+        rr::{Name, Record, RecordType},
+    };
+
+    let name = Name::from_str("www.youtube.com.").unwrap();
+    let target = Name::from_str("youtube-ui.l.google.com.").unwrap();
+
+    let mut message = Message::new();
+    message.set_message_type(MessageType::Response);
+    message.add_query(Query::query(name.clone(), RecordType::A));
+    message.add_answer(Record::from_rdata(name, 300, RData::CNAME(target.clone())));
+    message.add_answer(Record::from_rdata(target, 120, RData::A(Ipv4Addr::new(142, 250, 74, 14))));
+
+    // IPv4 header without options, UDP header, DNS message
+    let mut packet = vec![0u8; IPV4_MIN_HEADER_LEN + UDP_HEADER_LEN];
+    packet[0] = 0x45;
+    packet[9] = libc::IPPROTO_UDP as u8;
+    packet.extend(message.to_vec().unwrap());
+
+    let answer = parse_response(&packet).unwrap();
+
+    assert!(answer.names.iter().any(|name| matches("*.youtube.com", name)));
+    assert!(answer.names.iter().any(|name| name == "youtube-ui.l.google.com"));
+    assert_eq!(answer.addrs, vec![(Ipv4Addr::new(142, 250, 74, 14), 120)]);
+
+    assert!(parse_response(&packet[..10]).is_none());
+}
@@ -0,0 +1,331 @@
+use ipnetwork::Ipv4Network;
+use nftnl::{
+    expr::Expression,
+    nftnl_sys::{self as sys, libc},
+    MsgType,
+    NlMsg,
+    Rule,
+    Table,
+};
+use std::{
+    ffi::{CStr, CString},
+    net::Ipv4Addr,
+    os::raw::{c_char, c_void},
+    time::Duration,
+};
+
+use crate::config::accnt;
+
+
+// linux/netfilter/nf_tables.h
+const NFT_REG_1: u32 = 1;
+const NFT_DYNSET_OP_UPDATE: u32 = 1;
+const NFT_SET_INTERVAL: u32 = 0x4;
+const NFT_SET_TIMEOUT: u32 = 0x10;
+const NFT_SET_EVAL: u32 = 0x20;
+const NFT_SET_ELEM_INTERVAL_END: u32 = 0x1;
+// nft "ipv4_addr" data type
+const IPV4_ADDR_TYPE: u32 = 7;
+
+// Elements beyond this are not counted, until the set is recreated or flushed
+const HOST_SET_SIZE: u32 = 65535;
+// Intervals per element message, so that each message fits batch page
+const NET_SET_ELEMS_PER_MSG: usize = 512;
+
+
+/// Dynamic set of IPv4 addresses, with packet and byte counter per element.
+///
+/// Elements are added by `Dynset` rules, nftnl has no such set flavour.
+#[derive(Debug)]
+pub struct HostSet {
+    set: *mut sys::nftnl_set,
+    name: CString,
+    table: CString,
+    // Family of the table, set messages carry it in their header
+    family: u16,
+}
+
+impl HostSet {
+    pub fn new(name: &str, table: &Table) -> HostSet {
+        let name = CString::new(name).unwrap();
+        let family = table.get_family() as u16;
+
+        unsafe {
+            let set = sys::nftnl_set_alloc();
+
+            assert!(!set.is_null(), "failed to allocate set");
+
+            sys::nftnl_set_set_u32(set, sys::NFTNL_SET_FAMILY as u16, family as u32);
+            sys::nftnl_set_set_str(set, sys::NFTNL_SET_TABLE as u16, table.get_name().as_ptr());
+            sys::nftnl_set_set_str(set, sys::NFTNL_SET_NAME as u16, name.as_ptr());
+            sys::nftnl_set_set_u32(set, sys::NFTNL_SET_FLAGS as u16, NFT_SET_EVAL);
+            sys::nftnl_set_set_u32(set, sys::NFTNL_SET_KEY_TYPE as u16, IPV4_ADDR_TYPE);
+            sys::nftnl_set_set_u32(set, sys::NFTNL_SET_KEY_LEN as u16, 4);
+            sys::nftnl_set_set_u32(set, sys::NFTNL_SET_DESC_SIZE as u16, HOST_SET_SIZE);
+
+            HostSet { set, name, table: table.get_name().to_owned(), family }
+        }
+    }
+
+    pub fn get_name(&self) -> &CStr {
+        &self.name
+    }
+
+    /// Element message, which deletes all elements along with their counters.
+    pub fn flush(&self) -> SetElems {
+        SetElems { table: self.table.clone(), name: self.name.clone(), family: self.family, elems: Vec::new() }
+    }
+}
+
+impl Drop for HostSet {
+    fn drop(&mut self) {
+        unsafe { sys::nftnl_set_free(self.set) };
+    }
+}
+
+unsafe impl NlMsg for HostSet {
+    fn write(&self, buf: *mut c_void, seq: u32, msg_type: MsgType) {
+        let type_ = match msg_type {
+            MsgType::Add => libc::NFT_MSG_NEWSET,
+            MsgType::Del => libc::NFT_MSG_DELSET,
+        };
+
+        unsafe {
+            let header = sys::nftnl_nlmsg_build_hdr(
+                buf as *mut c_char,
+                type_ as u16,
+                self.family,
+                (libc::NLM_F_CREATE | libc::NLM_F_ACK) as u16,
+                seq);
+
+            sys::nftnl_set_nlmsg_build_payload(header, self.set);
+        }
+    }
+}
+
+/// Adds address from register 1 into the set, or bumps its counter if it is there.
+pub struct Dynset<'a> {
+    pub set: &'a HostSet,
+}
+
+impl Expression for Dynset<'_> {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = sys::nftnl_expr_alloc(b"dynset\0".as_ptr() as *const c_char);
+            let counter = sys::nftnl_expr_alloc(b"counter\0".as_ptr() as *const c_char);
+
+            sys::nftnl_expr_set_u32(expr, sys::NFTNL_EXPR_DYNSET_SREG_KEY as u16, NFT_REG_1);
+            sys::nftnl_expr_set_u32(expr, sys::NFTNL_EXPR_DYNSET_OP as u16, NFT_DYNSET_OP_UPDATE);
+            sys::nftnl_expr_set_str(expr, sys::NFTNL_EXPR_DYNSET_SET_NAME as u16, self.set.name.as_ptr());
+            // Counter is owned by dynset expression from now on
+            sys::nftnl_expr_set(expr, sys::NFTNL_EXPR_DYNSET_EXPR as u16, counter as *const c_void, 0);
+
+            expr
+        }
+    }
+}
+
+/// Named interval set of IPv4 networks, the addresses of an entry.
+///
+/// Rules of the entry look packets up in it, thus networks are swapped without touching the rules.
+/// Set of addresses learned from DNS has element timeouts instead of intervals.
+#[derive(Debug)]
+pub struct NetSet {
+    set: *mut sys::nftnl_set,
+    name: CString,
+    table: CString,
+    family: u16,
+}
+
+impl NetSet {
+    pub fn new(name: &str, table: &Table) -> NetSet {
+        NetSet::with_flags(name, table, NFT_SET_INTERVAL)
+    }
+
+    /// Set of single addresses, which expire on their own.
+    pub fn timed(name: &str, table: &Table) -> NetSet {
+        NetSet::with_flags(name, table, NFT_SET_TIMEOUT)
+    }
+
+    fn with_flags(name: &str, table: &Table, flags: u32) -> NetSet {
+        let name = CString::new(name).unwrap();
+        let family = table.get_family() as u16;
+
+        unsafe {
+            let set = sys::nftnl_set_alloc();
+
+            assert!(!set.is_null(), "failed to allocate set");
+
+            sys::nftnl_set_set_u32(set, sys::NFTNL_SET_FAMILY as u16, family as u32);
+            sys::nftnl_set_set_str(set, sys::NFTNL_SET_TABLE as u16, table.get_name().as_ptr());
+            sys::nftnl_set_set_str(set, sys::NFTNL_SET_NAME as u16, name.as_ptr());
+            sys::nftnl_set_set_u32(set, sys::NFTNL_SET_FLAGS as u16, flags);
+            sys::nftnl_set_set_u32(set, sys::NFTNL_SET_KEY_TYPE as u16, IPV4_ADDR_TYPE);
+            sys::nftnl_set_set_u32(set, sys::NFTNL_SET_KEY_LEN as u16, 4);
+
+            NetSet { set, name, table: table.get_name().to_owned(), family }
+        }
+    }
+
+    pub fn get_name(&self) -> &CStr {
+        &self.name
+    }
+
+    /// Element messages, which add or delete given networks. Adjacent and overlapping
+    /// networks are joined, as intervals of the set must not touch.
+    pub fn elems(&self, nets: &[Ipv4Network]) -> Vec<SetElems> {
+        let ranges = accnt::merge_ranges(nets.iter().map(|net| (net.network().into(), net.broadcast().into())).collect());
+
+        ranges.chunks(NET_SET_ELEMS_PER_MSG)
+            .map(|chunk| self.elems_msg(interval_elems(chunk).into_iter().map(|(key, end)| (key, end, None)).collect()))
+            .collect()
+    }
+
+    /// Element message, which adds addresses with their timeouts, to set created by `timed`.
+    pub fn timed_elems(&self, addrs: &[(Ipv4Addr, Duration)]) -> SetElems {
+        self.elems_msg(addrs.iter().map(|(addr, timeout)| ((*addr).into(), false, Some(*timeout))).collect())
+    }
+
+    /// Element message, which deletes all elements of the set.
+    pub fn flush(&self) -> SetElems {
+        self.elems_msg(Vec::new())
+    }
+
+    fn elems_msg(&self, elems: Vec<Elem>) -> SetElems {
+        SetElems { table: self.table.clone(), name: self.name.clone(), family: self.family, elems }
+    }
+}
+
+impl Drop for NetSet {
+    fn drop(&mut self) {
+        unsafe { sys::nftnl_set_free(self.set) };
+    }
+}
+
+unsafe impl NlMsg for NetSet {
+    fn write(&self, buf: *mut c_void, seq: u32, msg_type: MsgType) {
+        let type_ = match msg_type {
+            MsgType::Add => libc::NFT_MSG_NEWSET,
+            MsgType::Del => libc::NFT_MSG_DELSET,
+        };
+
+        unsafe {
+            let header = sys::nftnl_nlmsg_build_hdr(
+                buf as *mut c_char,
+                type_ as u16,
+                self.family,
+                (libc::NLM_F_CREATE | libc::NLM_F_ACK) as u16,
+                seq);
+
+            sys::nftnl_set_nlmsg_build_payload(header, self.set);
+        }
+    }
+}
+
+// Interval elements as (key, end flag): start of each range and the address past its end,
+// unless the range runs up to the last address
+fn interval_elems(ranges: &[(u32, u32)]) -> Vec<(u32, bool)> {
+    let mut elems = Vec::new();
+
+    for (from, to) in ranges.iter() {
+        elems.push((*from, false));
+
+        if let Some(end) = to.checked_add(1) {
+            elems.push((end, true));
+        }
+    }
+
+    elems
+}
+
+// Set element: key, whether it ends an interval, and timeout
+type Elem = (u32, bool, Option<Duration>);
+
+/// Elements of `NetSet` or `HostSet`, added or deleted in one message. Deleting no elements flushes the set.
+pub struct SetElems {
+    table: CString,
+    name: CString,
+    family: u16,
+    elems: Vec<Elem>,
+}
+
+unsafe impl NlMsg for SetElems {
+    fn write(&self, buf: *mut c_void, seq: u32, msg_type: MsgType) {
+        let (type_, flags) = match msg_type {
+            MsgType::Add => (libc::NFT_MSG_NEWSETELEM, libc::NLM_F_CREATE | libc::NLM_F_ACK),
+            MsgType::Del => (libc::NFT_MSG_DELSETELEM, libc::NLM_F_ACK),
+        };
+
+        unsafe {
+            let set = sys::nftnl_set_alloc();
+
+            assert!(!set.is_null(), "failed to allocate set");
+
+            sys::nftnl_set_set_u32(set, sys::NFTNL_SET_FAMILY as u16, self.family as u32);
+            sys::nftnl_set_set_str(set, sys::NFTNL_SET_TABLE as u16, self.table.as_ptr());
+            sys::nftnl_set_set_str(set, sys::NFTNL_SET_NAME as u16, self.name.as_ptr());
+
+            for (key, end, timeout) in self.elems.iter() {
+                let elem = sys::nftnl_set_elem_alloc();
+                let key = key.to_be_bytes();
+
+                assert!(!elem.is_null(), "failed to allocate set element");
+
+                sys::nftnl_set_elem_set(elem, sys::NFTNL_SET_ELEM_KEY as u16, key.as_ptr() as *const c_void, 4);
+
+                if *end {
+                    sys::nftnl_set_elem_set_u32(elem, sys::NFTNL_SET_ELEM_FLAGS as u16, NFT_SET_ELEM_INTERVAL_END);
+                }
+
+                // In milliseconds
+                if let Some(timeout) = timeout {
+                    sys::nftnl_set_elem_set_u64(elem, sys::NFTNL_SET_ELEM_TIMEOUT as u16, timeout.as_millis() as u64);
+                }
+
+                // Element is owned by the set from now on
+                sys::nftnl_set_elem_add(set, elem);
+            }
+
+            let header = sys::nftnl_nlmsg_build_hdr(
+                buf as *mut c_char,
+                type_ as u16,
+                self.family,
+                flags as u16,
+                seq);
+
+            sys::nftnl_set_elems_nlmsg_build_payload(header, set);
+            sys::nftnl_set_free(set);
+        }
+    }
+}
+
+/// Matches packets, whose address in register 1 is in the set.
+pub struct Lookup<'a> {
+    pub set: &'a NetSet,
+}
+
+impl Expression for Lookup<'_> {
+    fn to_expr(&self, _rule: &Rule) -> *mut sys::nftnl_expr {
+        unsafe {
+            let expr = sys::nftnl_expr_alloc(b"lookup\0".as_ptr() as *const c_char);
+
+            sys::nftnl_expr_set_u32(expr, sys::NFTNL_EXPR_LOOKUP_SREG as u16, NFT_REG_1);
+            sys::nftnl_expr_set_str(expr, sys::NFTNL_EXPR_LOOKUP_SET as u16, self.set.name.as_ptr());
+
+            expr
+        }
+    }
+}
+
+#[test]
+fn interval_elems_test() {
+    let addr = |s: &str| -> u32 { s.parse::<std::net::Ipv4Addr>().unwrap().into() };
+
+    assert_eq!(interval_elems(&[(addr("10.0.0.10"), addr("10.0.0.50"))]),
+        vec![(addr("10.0.0.10"), false), (addr("10.0.0.51"), true)]);
+
+    // Range up to the last address has no end element
+    assert_eq!(interval_elems(&[(0, u32::MAX)]), vec![(0, false)]);
+    assert_eq!(interval_elems(&[(addr("10.0.0.0"), addr("10.255.255.255")), (addr("11.0.0.1"), u32::MAX)]),
+        vec![(addr("10.0.0.0"), false), (addr("11.0.0.0"), true), (addr("11.0.0.1"), false)]);
+}
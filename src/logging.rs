@@ -21,7 +21,7 @@ fn create_log_file(path: &str) -> io::Result<fs::File> {
     fs::File::create(path)
 } 
 
-pub fn init(arguments: &ArgMatches) -> Result<(), SetLoggerError> {
+pub fn init(arguments: &ArgMatches, logfile: Option<&str>) -> Result<(), SetLoggerError> {
     let mut config_builder = Config::builder();
 
     let mut level = log::LevelFilter::Warn;
@@ -43,7 +43,7 @@ pub fn init(arguments: &ArgMatches) -> Result<(), SetLoggerError> {
     
     config_builder = config_builder.appender(Appender::builder().build("stdout", Box::new(stdout)));
 
-    match args::get_logfile(arguments) {
+    match logfile {
         Some(filepath) => {
             file_logging = true;
 
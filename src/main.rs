@@ -1,13 +1,21 @@
 
 mod args;
+mod cgroup;
 mod logging;
 mod config;
+mod conntrack;
+mod dns;
+mod hostset;
 mod netfilter;
+mod netlink;
+mod netns;
+mod schedule;
+mod sni;
 mod timer;
 
 use clap::ArgMatches;
 use log;
-use std::{os::raw::c_int, thread};
+use std::{os::raw::c_int, process::Command, thread};
 use signal_hook::{consts::*, iterator::Signals};
 
 
@@ -24,20 +32,16 @@ impl From<config::ParseConfigError> for StartupErr {
     }
 }
 
+// Signals handled by the daemon, the one of several namespaces passes them on to their processes.
+// Others keep default action, thus resizing terminal or stopping the job does not tear rules down
 const SIGNALS: &[c_int] = &[
-    SIGTERM, SIGQUIT, SIGINT, SIGTSTP, SIGWINCH, SIGHUP, SIGCHLD, SIGCONT,
+    SIGTERM, SIGQUIT, SIGINT, SIGHUP, SIGUSR1,
 ];
 
 
 fn main() {
-    let mut signals = Signals::new(SIGNALS).unwrap();
-
-    thread::spawn(move || {
-        for sig in signals.forever() {
-            netfilter::deinit().unwrap();
-            std::process::exit(0);
-        }
-    });
+    // Signals are held back until handlers are in place, in each process of the daemon
+    mask_signals(libc::SIG_BLOCK);
 
     let arguments = args::init();
 
@@ -60,17 +64,155 @@ fn main() {
 
 
 fn run(arguments: &ArgMatches) -> Result<(), StartupErr> {    
-    let config = config::Config::new_from_file(
+    let mut config = config::Config::new_from_file(
         args::get_config(&arguments)).unwrap();
+
+    // Command line takes precedence over config
+    let namespaces: Vec<String> = match args::get_netns(&arguments) {
+        netns if !netns.is_empty() => netns.iter().map(|netns| netns.to_string()).collect(),
+        _ => config.netns.clone(),
+    };
+
+    // Files of namespaces must not clash, when the process is one of several
+    let file_path = |path: &str| match namespaces.first() {
+        Some(netns) if args::get_netns_files(&arguments) => netns::file_path(path, netns),
+        _ => path.to_owned(),
+    };
+
+    let log_path = args::get_logfile(&arguments).map(file_path);
   
-    logging::init(&arguments)
+    logging::init(&arguments, log_path.as_deref())
         .or_else(|e| Err(
             StartupErr::LoggerError(
                 e.to_string())))?;
     
     log::info!("Starting ...");
 
-    netfilter::init(&config).unwrap();
+    let status_path = args::get_status(&arguments).map(file_path);
+    let metrics_path = args::get_metrics(&arguments).map(file_path);
+
+    if let Some(clients) = config.clients.as_mut() {
+        clients.state = file_path(&clients.state);
+    }
+
+    if namespaces.len() < 2 {
+        return manage(&config, namespaces.first().map(String::as_str), status_path, metrics_path.as_deref());
+    }
+
+    // Signals are passed on to namespace processes, the daemon stops along with the last of them.
+    // Handlers go in place first, so that no exit goes unnoticed
+    let mut signals = Signals::new(SIGNALS.iter().chain([SIGCHLD].iter())).unwrap();
+
+    // Netfilter handle is one per process, thus each namespace gets a process of its own. Processes
+    // are started anew rather than forked, logger and other global state of this one stay behind
+    let exe = std::env::current_exe()
+        .or_else(|e| Err(
+            StartupErr::ConfigErr(
+                format!("Failed to find executable of the daemon: {}", e))))?;
+    let mut children = Vec::new();
+
+    for netns in namespaces.iter() {
+        let mut command = Command::new(&exe);
+
+        command.arg("--config").arg(args::get_config(&arguments))
+            .arg("--netns").arg(netns)
+            .arg("--netns-files");
+
+        let files = [
+            ("--log", args::get_logfile(&arguments)),
+            ("--status", args::get_status(&arguments)),
+            ("--metrics", args::get_metrics(&arguments)),
+        ];
+
+        for (option, path) in files.iter() {
+            if let Some(path) = path {
+                command.arg(option).arg(path);
+            }
+        }
+
+        for _ in 0..args::get_verbosity(&arguments) {
+            command.arg("-v");
+        }
+
+        if args::get_silent(&arguments) {
+            command.arg("--silent");
+        }
+
+        match command.spawn() {
+            Ok(child) => children.push(child.id() as libc::pid_t),
+            Err(e) => return Err(StartupErr::ConfigErr(
+                format!("Failed to start process of network namespace {}: {}", netns, e))),
+        }
+    }
+
+    mask_signals(libc::SIG_UNBLOCK);
+
+    for sig in signals.forever() {
+        if sig != SIGCHLD {
+            for pid in children.iter() {
+                unsafe { libc::kill(*pid, sig) };
+            }
+
+            continue;
+        }
+
+        let mut status = 0;
+
+        loop {
+            let pid = unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) };
+
+            if pid <= 0 {
+                break;
+            }
+
+            log::warn!("Process {} of network namespace exited", pid);
+
+            children.retain(|child| *child != pid);
+        }
+
+        if children.is_empty() {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+// Runs netfilter in given namespace, or in own one. Signal thread is spawned after entering, so
+// that sockets of its handlers are opened in the namespace too
+fn manage(config: &config::Config, netns: Option<&str>, status_path: Option<String>, metrics_path: Option<&str>) -> Result<(), StartupErr> {
+    if let Some(netns) = netns {
+        netns::enter(netns)
+            .or_else(|e| Err(
+                StartupErr::ConfigErr(
+                    format!("Failed to enter network namespace {}: {}", netns, e))))?;
+
+        log::info!("Entered network namespace {}", netns);
+    }
+
+    let mut signals = Signals::new(SIGNALS).unwrap();
+
+    thread::spawn(move || {
+        for sig in signals.forever() {
+            if sig == SIGUSR1 {
+                netfilter::dump_status(status_path.as_deref());
+                netfilter::dump_metrics();
+                continue;
+            }
+
+            if sig == SIGHUP {
+                netfilter::reload();
+                continue;
+            }
+
+            netfilter::deinit().unwrap();
+            std::process::exit(0);
+        }
+    });
+
+    mask_signals(libc::SIG_UNBLOCK);
+
+    netfilter::init(config, metrics_path).unwrap();
 
     // nflog::init(&mut queue).unwrap();
 
@@ -87,7 +229,25 @@ fn run(arguments: &ArgMatches) -> Result<(), StartupErr> {
     //     error!("could not start worker: {:?}", e);
     // }
 
-    netfilter::run();
+    netfilter::run()
+        .or_else(|e| Err(
+            StartupErr::ConfigErr(
+                format!("Failed to run netfilter: {:?}", e))))?;
   
     Ok(())
 }
+
+// Blocks or unblocks handled signals in calling thread, threads spawned later inherit the mask
+fn mask_signals(how: c_int) {
+    unsafe {
+        let mut set: libc::sigset_t = std::mem::zeroed();
+
+        libc::sigemptyset(&mut set);
+
+        for sig in SIGNALS.iter() {
+            libc::sigaddset(&mut set, *sig);
+        }
+
+        libc::pthread_sigmask(how, &set, std::ptr::null_mut());
+    }
+}
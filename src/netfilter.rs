@@ -4,7 +4,7 @@ use ipnetwork::Ipv4Network;
 use log::{debug, error, info, trace, warn};
 use nftnl::{
     nft_expr,
-    nftnl_sys::libc,
+    nftnl_sys::{self as sys, libc},
     Batch,
     Chain,
     ChainType,
@@ -15,22 +15,42 @@ use nftnl::{
     Table,
     Quota,
     QuotaType,
-    expr::TcpFlags as TcpFlags
+    expr::TcpFlags as TcpFlags,
+    expr::Verdict,
+    expr::IcmpCode,
+    expr::Limit,
+    expr::LimitType,
+    expr::LimitUnit,
 };
 use nflog;
 use once_cell::unsync::OnceCell;
+use chrono::{DateTime, Local};
 use std::{
-    collections::HashMap,
-    ffi::CString,
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    ffi::{CStr, CString},
     io,
-    time::Duration,
+    net::Ipv4Addr,
+    path::Path,
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime},
 };
 use crate::{
     config::{
-        accnt::Accounting,
+        accnt::{self, Accounting, Action, Address, Exempt, Period, Rejection, Selector, Tracking, Window},
+        Clients,
         Config,
+        Family,
+        Pool,
+        QuotaKind,
         ToQuota,
     },
+    conntrack::{self, CtEvent, CtEventType},
+    dns,
+    cgroup::{self, SocketCgroup},
+    hostset::{Dynset, HostSet, Lookup, NetSet},
+    netlink::{self, NlSocket, RECV_BUF_SIZE},
+    schedule,
+    sni,
     timer::ConnTimer,
 };
 
@@ -38,18 +58,111 @@ use crate::{
 const TABLE_NAME: &str = "netcontrol";
 const DATA_IN_CHAIN_NAME: &str = "data_qt-in";
 const DATA_OUT_CHAIN_NAME: &str = "data_qt-out";
+// Forwarded traffic, for selector and client entries only
+const DATA_FWD_CHAIN_NAME: &str = "data_qt-fwd-in";
+// Regular chain of selector entries, input, forward and output chains jump to it
+const DATA_SEL_CHAIN_NAME: &str = "data_qt-sel-in";
 const TIME_IN_CHAIN_NAME: &str = "time_qt-in";
 const TIME_OUT_CHAIN_NAME: &str = "time_qt-out";
+const WINDOW_IN_CHAIN_NAME: &str = "window-in";
+const WINDOW_OUT_CHAIN_NAME: &str = "window-out";
+const KILL_IN_CHAIN_NAME: &str = "kill-in";
+const KILL_OUT_CHAIN_NAME: &str = "kill-out";
+const UNMATCHED_IN_CHAIN_NAME: &str = "unmatched-in";
+const UNMATCHED_OUT_CHAIN_NAME: &str = "unmatched-out";
+const CLAIM_IN_CHAIN_NAME: &str = "claim-in";
+const CLAIM_OUT_CHAIN_NAME: &str = "claim-out";
+
+// Overlap claims are settled before any quota chain looks at the packet
+const CLAIM_CHAIN_PRIORITY: i32 = -2;
+// Kill chains go before quota chains, so that resets are sent before drops
+const KILL_CHAIN_PRIORITY: i32 = -1;
+// Unmatched traffic is counted after quota chains had their say
+const UNMATCHED_CHAIN_PRIORITY: i32 = 1;
 
 const DATA_LOG_PREFIX: &str = "dq_";
+const DATA_TIER_SUFFIX: &str = "_t";
+const DATA_HOSTS_SUFFIX: &str = "_hosts";
+const DATA_DESTS_SUFFIX: &str = "_dests";
+// Interval set of entry networks, rules of the entry look addresses up in it
+const NET_SET_SUFFIX: &str = "_nets";
 const TIME_LOG_PREFIX: &str = "tq_";
+const WINDOW_PREFIX: &str = "wq_";
+const UNMATCHED_IN_SET: &str = "unmatched_in";
+const UNMATCHED_OUT_SET: &str = "unmatched_out";
+const UNMATCHED_SKIP_SET: &str = "unmatched_skip";
 const TIME_START_LOG_PREFIX: &str = "start_";
 const TIME_FIN_LOG_PREFIX: &str = "fin_";
+const TIME_ACTIVITY_LOG_PREFIX: &str = "act_";
+
+// Learned addresses are kept at least this long, whatever TTL says
+const DNS_MIN_TTL: Duration = Duration::from_secs(60);
+// Snooped names of unmatched remotes, forgotten all at once beyond this
+const DNS_NAMES_MAX: usize = 65536;
+// Accumulated unmatched remotes, the smaller half is dropped beyond this
+const UNMATCHED_LABELS_MAX: usize = 65536;
+
+// Packet mark bits, which hold id of overlapping entry charged for the packet, a byte
+// per quota kind. Higher id belongs to entry of higher rank
+const CLAIM_MASK: u32 = 0xffff_0000;
+const DATA_CLAIM_SHIFT: u32 = 24;
+const TIME_CLAIM_SHIFT: u32 = 16;
+const CLAIM_MAX: usize = 0xff;
+
+// Conntrack mark of classified flow: id of SNI entry it belongs to (0 for none),
+// and whether the flow was looked at already
+const SNI_MARK_MASK: u32 = 0x0000_ffff;
+const SNI_SEEN: u32 = 0x0001_0000;
+const SNI_PORTS: [u16; 2] = [443, 80];
+
+// Conntrack mark of flow of selector entry: id of the entry, set by the first packet which
+// shows the selector (upload of MAC or interface entry, output of user or cgroup one)
+const TAG_MARK_SHIFT: u32 = 17;
+const TAG_MARK_MASK: u32 = 0xfffe_0000;
+
+// Client entries get config lines from here on, beyond any real config line
+const CLIENT_LINE_BASE: u32 = 1 << 24;
+// Spoofed sources must not exhaust the ruleset
+const CLIENTS_MAX: usize = 1024;
+
+// Sliding window is sampled in this many buckets
+const ROLLING_BUCKETS: u32 = 48;
+
+// linux/netfilter/nf_tables.h
+const NFNL_SUBSYS_NFTABLES: u16 = 10;
+const NFT_MSG_NEWOBJ: u16 = 18;
+const NFT_MSG_GETOBJ: u16 = 19;
+const NFTA_OBJ_TABLE: u16 = 1;
+const NFTA_OBJ_NAME: u16 = 2;
+const NFTA_OBJ_TYPE: u16 = 3;
+const NFTA_OBJ_DATA: u16 = 4;
+const NFT_OBJECT_QUOTA: u32 = 2;
+const NFTA_QUOTA_CONSUMED: u16 = 4;
+const NFT_MSG_NEWSETELEM: u16 = 12;
+const NFT_MSG_GETSETELEM: u16 = 13;
+const NFTA_SET_ELEM_LIST_TABLE: u16 = 1;
+const NFTA_SET_ELEM_LIST_SET: u16 = 2;
+const NFTA_SET_ELEM_LIST_ELEMENTS: u16 = 3;
+const NFTA_LIST_ELEM: u16 = 1;
+const NFTA_SET_ELEM_KEY: u16 = 1;
+const NFTA_SET_ELEM_EXPR: u16 = 9;
+const NFTA_SET_ELEM_EXPRESSIONS: u16 = 11;
+const NFTA_DATA_VALUE: u16 = 1;
+const NFTA_EXPR_DATA: u16 = 2;
+const NFTA_COUNTER_BYTES: u16 = 1;
 
 pub const DATA_QUOTA_NUM: u16 = 0;
 pub const TIME_QUOTA_NUM: u16 = 1;
+pub const TIME_ACTIVITY_NUM: u16 = 2;
+pub const DNS_SNOOP_NUM: u16 = 3;
+pub const SNI_NUM: u16 = 4;
 
-type LimitEntryName<'a> = &'a str;
+// Activity packets are delivered to userspace in batches of this size
+const TIME_ACTIVITY_QTHRESH: u32 = 64;
+// Activity is sampled at this many packets per second and direction, plenty for a clock of seconds
+const TIME_ACTIVITY_RATE: u32 = 1;
+
+type LimitEntryName = String;
 type ChainName<'a> = &'a str;
 
 #[derive(Debug)]
@@ -57,9 +170,34 @@ pub struct NfHandle<'a> {
     pub table: Table,
     pub chains: HashMap<ChainName<'a>, Chain<'a>>,
     pub log: NflogHandle<'a>,
-
-    pub time_entries: HashMap<LimitEntryName<'a>, NfTimeLimit<'a>>,
-    pub data_entries: HashMap<LimitEntryName<'a>, NfDataLimit<'a>>,
+    // Socket for reading quota and set counters, opened once in managed namespace
+    pub query: NlSocket,
+
+    pub time_entries: HashMap<LimitEntryName, NfTimeLimit<'a>>,
+    pub data_entries: HashMap<LimitEntryName, NfDataLimit<'a>>,
+    pub window_entries: HashMap<LimitEntryName, NfWindowLimit<'a>>,
+    pub pools: Vec<Pool>,
+    // Allowlist and global exemptions, flows of killing entries are checked against them
+    pub allow: Vec<Exempt>,
+    pub exempt: Vec<Exempt>,
+    // Claim ids of overlapping entries, by quota kind and config line
+    pub claims: HashMap<(QuotaKind, u32), u32>,
+    // SNI classified entries as (config line, domain pattern, mark id)
+    pub sni_entries: Vec<(u32, String, u32)>,
+    // Conntrack socket of nflog thread, which marks classified flows
+    pub sni_socket: Option<NlSocket>,
+    // Entries keyed by MAC, interface, user or cgroup, by config line
+    pub selectors: HashMap<u32, Selector>,
+    // Conntrack mark ids of selector entries, by config line. Netdev family has no conntrack,
+    // selectors are matched on each frame there
+    pub tags: HashMap<u32, u32>,
+    // Length of top talker lists, 0 if data entries keep no per address counters
+    pub talkers: usize,
+    // Prometheus text file, rewritten on each tick and SIGUSR1
+    pub metrics: Option<String>,
+    // Counters of traffic, which matches no entry
+    pub unmatched: Option<NfUnmatched<'a>>,
+    pub clients: Option<NfClients>,
 }
 
 #[derive(Debug)]
@@ -69,16 +207,167 @@ pub struct NflogHandle<'a> {
 }
 
 impl NfHandle<'_> {
-    fn new(table_name: &str) -> NfHandle {
+    /// Status report of all entries, grouped by config line.
+    ///
+    /// For lines with several quotas, tells which one caused the block.
+    pub fn status(&self) -> String {
+        let mut lines: Vec<(u32, String, bool, Vec<Ipv4Network>)> = Vec::new();
+
+        for (_, limit) in self.data_entries.iter() {
+            lines.push((limit.line, limit.status(), limit.blocked, limit.nets.clone()));
+        }
+
+        for (_, limit) in self.time_entries.iter() {
+            lines.push((limit.line, limit.status(), limit.blocked, limit.nets.clone()));
+        }
+
+        for (_, limit) in self.window_entries.iter() {
+            lines.push((limit.line, limit.status(), limit.blocked, limit.nets.clone()));
+        }
+
+        lines.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
+
+        let mut blocks: BTreeMap<u32, String> = BTreeMap::new();
+
+        for (pos, (line, status, _, addrs)) in lines.iter().enumerate() {
+            let block = blocks.entry(*line).or_insert_with(|| {
+                let pool = self.pools.iter().find(|pool| pool.line == *line);
+
+                let sni = self.sni_entries.iter().find(|entry| entry.0 == *line);
+                let client = self.clients.as_ref().and_then(|clients| clients.client(*line));
+                let selector = self.selectors.get(line);
+
+                let mut block = match (pool, sni, client, selector) {
+                    (Some(pool), _, _, _) => format!("line {}: pool {}\n", line + 1, pool.name),
+                    (_, Some(sni), _, _) => format!("line {}: sni {}\n", line + 1, sni.1),
+                    (_, _, Some(client), _) => format!("client {}\n", client),
+                    (_, _, _, Some(selector)) => format!("line {}: {}\n", line + 1, selector),
+                    _ => {
+                        let addrs: Vec<String> = addrs.iter().map(|ip| ip.to_string()).collect();
+                        format!("line {}: {}\n", line + 1, addrs.join(", "))
+                    },
+                };
+
+                let blockers: Vec<&str> = lines[pos..].iter()
+                    .take_while(|entry| entry.0 == *line)
+                    .filter(|entry| entry.2)
+                    .map(|entry| entry.1.split(' ').next().unwrap_or(""))
+                    .collect();
+
+                if !blockers.is_empty() {
+                    block.push_str(&format!("  blocked by: {}\n", blockers.join(", ")));
+                }
+
+                block
+            });
+
+            block.push_str(&format!("  {}\n", status));
+        }
+
+        let mut report = String::new();
+
+        // Pools go first, with their members indented below
+        for pool in self.pools.iter() {
+            if let Some(block) = blocks.remove(&pool.line) {
+                report.push_str(&block);
+            }
+
+            for member in pool.members.iter() {
+                if let Some(block) = blocks.remove(member) {
+                    for line in block.lines() {
+                        report.push_str(&format!("    {}\n", line));
+                    }
+                }
+            }
+        }
+
+        for (_, block) in blocks {
+            report.push_str(&block);
+        }
+
+        if let Some(unmatched) = self.unmatched.as_ref() {
+            report.push_str(&unmatched.status());
+        }
+
+        report
+    }
+
+    /// Prometheus text exposition of entry state and top talkers.
+    pub fn metrics(&self) -> String {
+        let mut blocked = String::from("# TYPE netcontrol_blocked gauge\n");
+        let mut hosts = String::from("# TYPE netcontrol_host_bytes gauge\n");
+        let mut dests = String::from("# TYPE netcontrol_destination_bytes gauge\n");
+
+        let mut entries: Vec<(&String, u32, bool)> = Vec::new();
+
+        entries.extend(self.data_entries.iter().map(|(name, limit)| (name, limit.line, limit.blocked)));
+        entries.extend(self.time_entries.iter().map(|(name, limit)| (name, limit.line, limit.blocked)));
+        entries.extend(self.window_entries.iter().map(|(name, limit)| (name, limit.line, limit.blocked)));
+
+        entries.sort_by(|a, b| a.1.cmp(&b.1).then(a.0.cmp(b.0)));
+
+        for (name, line, is_blocked) in entries {
+            blocked.push_str(&format!("netcontrol_blocked{{entry=\"{}\",line=\"{}\"}} {}\n", name, line + 1, is_blocked as u8));
+
+            let talkers = self.data_entries.get(name).and_then(|limit| limit.talkers(self.talkers));
+
+            if let Some((top_hosts, top_dests)) = talkers {
+                for (addr, bytes) in top_hosts {
+                    hosts.push_str(&format!("netcontrol_host_bytes{{entry=\"{}\",line=\"{}\",addr=\"{}\"}} {}\n", name, line + 1, addr, bytes));
+                }
+
+                for (addr, bytes) in top_dests {
+                    dests.push_str(&format!("netcontrol_destination_bytes{{entry=\"{}\",line=\"{}\",addr=\"{}\"}} {}\n", name, line + 1, addr, bytes));
+                }
+            }
+        }
+
+        let mut unmatched = String::new();
+
+        if let Some(counters) = self.unmatched.as_ref() {
+            unmatched.push_str("# TYPE netcontrol_unmatched_bytes gauge\n");
+            unmatched.push_str(&format!("netcontrol_unmatched_bytes {}\n", counters.total));
+            unmatched.push_str("# TYPE netcontrol_unmatched_remote_bytes gauge\n");
+
+            for (label, bytes) in counters.top.iter() {
+                unmatched.push_str(&format!("netcontrol_unmatched_remote_bytes{{remote=\"{}\"}} {}\n", label, bytes));
+            }
+        }
+
+        blocked + &hosts + &dests + &unmatched
+    }
+
+    fn new(table_name: &str, family: &Family) -> NfHandle {
+        let family = match family {
+            Family::Ipv4 => ProtoFamily::Ipv4,
+            Family::Bridge => ProtoFamily::Bridge,
+            Family::Netdev(_) => ProtoFamily::NetDev,
+        };
+
         NfHandle {
-            table: Table::new(&CString::new(table_name).unwrap(), ProtoFamily::Ipv4),
+            table: Table::new(&CString::new(table_name).unwrap(), family),
             chains: HashMap::new(),
             log: NflogHandle::new(),
+            query: NlSocket::open(0).unwrap(),
             time_entries: HashMap::new(),
             data_entries: HashMap::new(),
+            window_entries: HashMap::new(),
+            pools: Vec::new(),
+            allow: Vec::new(),
+            exempt: Vec::new(),
+            claims: HashMap::new(),
+            sni_entries: Vec::new(),
+            sni_socket: None,
+            selectors: HashMap::new(),
+            tags: HashMap::new(),
+            talkers: 0,
+            metrics: None,
+            unmatched: None,
+            clients: None,
         }
     }
 
+    /// Handle of the daemon, callers hold `lock_handle()` unless they are nflog loop itself.
     pub fn get() -> &'static mut NfHandle<'static> {
         unsafe { HANDLE_INSTANCE.get_mut().expect("nfhandle is not initialized") }
     }
@@ -111,34 +400,517 @@ struct TimeLimitRuleset<'a> {
     // Rules for "Drop with RST" for input and output traffic
     block_in: Rule<'a>,
     block_out: Rule<'a>,
+    // Rules for logging any packet of the entry (activity mode)
+    activity_in: Rule<'a>,
+    activity_out: Rule<'a>,
 }
 
 #[derive(Debug)]
 struct DataLimitRuleset<'a> {
-    // Rule for accounting quota and blocking afterwards
-    block: Rule<'a>,
-    // Rule for informing userspace for quota overflows
-    log: Rule<'a>,
+    // Rules for accounting quota, informing userspace and enforcing action on overflow, one
+    // per end the entry set is matched at
+    log: Vec<Rule<'a>>,
+    // Rules for enforcing action, replace log rules once quota is exceeded
+    block: Vec<Rule<'a>>,
+}
+
+// Default actions, when entry does not set one
+const DATA_DEFAULT_ACTION: Action = Action::Drop;
+const TIME_DEFAULT_ACTION: Action = Action::Reject(Rejection::PortUnreachable);
+const WINDOW_DEFAULT_ACTION: Action = Action::Reject(Rejection::PortUnreachable);
+
+// Appends expressions, which enforce action on matched packet
+fn add_action_exprs(rule: &mut Rule, action: &Action) {
+    match action {
+        Action::Drop => rule.add_expr(&nft_expr!(verdict drop)),
+        Action::Reject(rejection) => {
+            let rejection_type = match rejection {
+                Rejection::TcpReset => RejectionType::TcpRst,
+                Rejection::PortUnreachable => RejectionType::Icmp(IcmpCode::PortUnreach),
+                Rejection::HostUnreachable => RejectionType::Icmp(IcmpCode::HostUnreach),
+                Rejection::AdminProhibited => RejectionType::Icmp(IcmpCode::AdminProhibited),
+                Rejection::NoRoute => RejectionType::Icmp(IcmpCode::NoRoute),
+            };
+
+            rule.add_expr(&Verdict::Reject(rejection_type));
+        },
+        // Overflow is reported by log rules, nothing to enforce
+        Action::Log => (),
+        // Claim bits are kept, the packet might be charged further on
+        Action::Mark(mark) => {
+            rule.add_expr(&nft_expr!(meta mark));
+            rule.add_expr(&nft_expr!(bitwise mask CLAIM_MASK, xor *mark));
+            rule.add_expr(&nft_expr!(meta mark set));
+        },
+        Action::Throttle(rate) => {
+            // Packets over the rate are dropped
+            rule.add_expr(&Limit::new(*rate, LimitUnit::Second, LimitType::Bytes).inverted());
+            rule.add_expr(&nft_expr!(verdict drop));
+        },
+    }
+}
+
+#[derive(Debug)]
+struct KillRuleset<'a> {
+    // Rules for "Reject with TCP RST" for input and output TCP traffic of blocked entry
+    reset_in: Rule<'a>,
+    reset_out: Rule<'a>,
+    // Own exemptions of entry, exempt flows are not reset and stay in conntrack
+    exempt: Vec<Exempt>,
+}
+
+impl KillRuleset<'_> {
+    fn new<'a>(in_chain: &'a Chain, out_chain: &'a Chain, set: &NetSet, exempt: &[Exempt]) -> KillRuleset<'a> {
+        let mut ruleset = KillRuleset {
+            reset_in: Rule::new(&in_chain),
+            reset_out: Rule::new(&out_chain),
+            exempt: exempt.to_vec(),
+        };
+
+        ruleset.reset_in.add_expr(&nft_expr!(meta l4proto));
+        ruleset.reset_in.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
+        add_set_exprs(&mut ruleset.reset_in, true, set);
+        ruleset.reset_in.add_expr(&Verdict::Reject(RejectionType::TcpRst));
+
+        ruleset.reset_out.add_expr(&nft_expr!(meta l4proto));
+        ruleset.reset_out.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
+        add_set_exprs(&mut ruleset.reset_out, false, set);
+        ruleset.reset_out.add_expr(&Verdict::Reject(RejectionType::TcpRst));
+
+        ruleset
+    }
+}
+
+// Claim of networks of overlapping entry, in claim chains
+#[derive(Debug)]
+struct ClaimRuleset<'a> {
+    claim_in: Rule<'a>,
+    // Time entries are charged on output too
+    claim_out: Option<Rule<'a>>,
+}
+
+impl ClaimRuleset<'_> {
+    fn new(quota: QuotaKind, set: &NetSet, claim: u32) -> ClaimRuleset<'static> {
+        let (in_chain, out_chain) = claim_chains();
+
+        ClaimRuleset {
+            claim_in: claim_rule(in_chain, true, set, quota, claim),
+            claim_out: match quota {
+                QuotaKind::Data => None,
+                QuotaKind::Time => Some(claim_rule(out_chain, false, set, quota, claim)),
+            },
+        }
+    }
+
+    fn apply(&self, batch: &mut Batch, msg: nftnl::MsgType) {
+        batch.add(&self.claim_in, msg);
+
+        if let Some(rule) = self.claim_out.as_ref() {
+            batch.add(rule, msg);
+        }
+    }
+}
+
+// Claims stay in place for the lifetime of the entry, unlike its other rules
+fn add_claims(rules: &Option<ClaimRuleset>) {
+    if let Some(ruleset) = rules.as_ref() {
+        let mut batch = Batch::new();
+
+        ruleset.apply(&mut batch, nftnl::MsgType::Add);
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+    }
+}
+
+// Per address counters of data entry, all rules fall through
+#[derive(Debug)]
+struct CountRuleset<'a> {
+    // (hosts, dests) rules per end the entry set is matched at: addresses of the entry itself
+    // and the other ends, traffic of the entry goes to
+    rules: Vec<(Rule<'a>, Rule<'a>)>,
+}
+
+impl CountRuleset<'_> {
+    fn new<'a>(
+        in_chain: &'a Chain,
+        set: &NetSet,
+        ends: &[bool],
+        sets: &(HostSet, HostSet),
+        matcher: Option<&EntryMatch>) -> CountRuleset<'a> {
+        let rules = ends.iter()
+            .map(|source| {
+                let (mut hosts, mut dests) = (Rule::new(&in_chain), Rule::new(&in_chain));
+
+                add_set_exprs(&mut hosts, *source, set);
+                add_match_exprs(&mut hosts, matcher);
+                hosts.add_expr(&if *source { nft_expr!(payload ipv4 saddr) } else { nft_expr!(payload ipv4 daddr) });
+                hosts.add_expr(&Dynset { set: &sets.0 });
+
+                add_set_exprs(&mut dests, *source, set);
+                add_match_exprs(&mut dests, matcher);
+                dests.add_expr(&if *source { nft_expr!(payload ipv4 daddr) } else { nft_expr!(payload ipv4 saddr) });
+                dests.add_expr(&Dynset { set: &sets.1 });
+
+                (hosts, dests)
+            })
+            .collect();
+
+        CountRuleset { rules }
+    }
+
+    fn apply(&self, batch: &mut Batch, msg: nftnl::MsgType) {
+        for (hosts, dests) in self.rules.iter() {
+            batch.add(hosts, msg);
+            batch.add(dests, msg);
+        }
+    }
+}
+
+// Entry with `kill` option kills its flows on block. Killing flows makes sense only when they
+// are cut off
+fn kills_flows(kill: bool, action: &Action) -> bool {
+    kill && matches!(action, Action::Drop | Action::Reject(_))
+}
+
+// Cuts established flows of blocked entry: TCP peers get reset and conntrack
+// forgets the flows, so that no packet sneaks through as established one.
+// Must be called after block rules are in place.
+fn kill_flows(name: &str, kill_rules: &Option<KillRuleset>, nets: &[Ipv4Network]) {
+    let ruleset = match kill_rules.as_ref() {
+        Some(ruleset) => ruleset,
+        None => return,
+    };
+
+    let mut batch = Batch::new();
+
+    batch.add(&ruleset.reset_in, nftnl::MsgType::Add);
+    batch.add(&ruleset.reset_out, nftnl::MsgType::Add);
+
+    process_netlink(&(batch.finalize()), false).unwrap();
+
+    // Flows, which rules let through, are kept
+    let allow = &NfHandle::get().allow;
+    let exempt: Vec<Exempt> = ruleset.exempt.iter().chain(NfHandle::get().exempt.iter()).cloned().collect();
+
+    match conntrack::flush(nets, |flow| is_allowed_flow(allow, flow) || is_exempt_flow(nets, &exempt, flow)) {
+        Ok(num) => debug!("{}: flushed {} conntrack entries", name, num),
+        Err(e) => warn!("{}: failed to flush conntrack entries: {}", name, e),
+    }
+}
+
+fn unkill_flows(kill_rules: &Option<KillRuleset>, batch: &mut Batch) {
+    if let Some(ruleset) = kill_rules.as_ref() {
+        batch.add(&ruleset.reset_in, nftnl::MsgType::Del);
+        batch.add(&ruleset.reset_out, nftnl::MsgType::Del);
+    }
+}
+
+// Part of entry match, which ties packet to single entry besides its address
+#[derive(Debug, Clone)]
+enum EntryMatch {
+    // Mark bits, in connection mark instead of packet mark if `conntrack` is set
+    Mark { conntrack: bool, mask: u32, value: u32 },
+    // Link layer or interface of the packet, where flows can't be tagged
+    Device(Device),
+}
+
+// Selector of device or network segment, unlike socket owner it shows on the packet itself
+#[derive(Debug, Clone, PartialEq)]
+enum Device {
+    Mac([u8; 6]),
+    InIface(String),
+    OutIface(String),
+}
+
+impl Device {
+    fn of(selector: &Selector) -> Option<Device> {
+        match selector {
+            Selector::Mac(mac) => Some(Device::Mac(*mac)),
+            Selector::InIface(name) => Some(Device::InIface(name.clone())),
+            Selector::OutIface(name) => Some(Device::OutIface(name.clone())),
+            Selector::User(_) | Selector::Cgroup(_) => None,
+        }
+    }
+}
+
+impl EntryMatch {
+    fn claim(quota: QuotaKind, claim: u32) -> EntryMatch {
+        EntryMatch::Mark { conntrack: false, mask: claim_mask(quota), value: claim }
+    }
+
+    fn sni(id: u32) -> EntryMatch {
+        EntryMatch::Mark { conntrack: true, mask: SNI_MARK_MASK, value: id }
+    }
+
+    fn tag(id: u32) -> EntryMatch {
+        EntryMatch::Mark { conntrack: true, mask: TAG_MARK_MASK, value: id << TAG_MARK_SHIFT }
+    }
+}
+
+fn claim_shift(quota: QuotaKind) -> u32 {
+    match quota {
+        QuotaKind::Data => DATA_CLAIM_SHIFT,
+        QuotaKind::Time => TIME_CLAIM_SHIFT,
+    }
+}
+
+fn claim_mask(quota: QuotaKind) -> u32 {
+    (CLAIM_MAX as u32) << claim_shift(quota)
+}
+
+// Overlap claim of entry at given config line, if it has one
+fn claim_match(quota: QuotaKind, line: u32) -> Option<EntryMatch> {
+    NfHandle::get().claims.get(&(quota, line)).map(|claim| EntryMatch::claim(quota, *claim))
+}
+
+// Match of entry at given config line: selector, overlap claim or SNI classification
+fn entry_match(quota: QuotaKind, line: u32, addr: &Address) -> Option<EntryMatch> {
+    let handle = NfHandle::get();
+
+    // Selector shows on packets of one direction only, both of them are told by the flow tag.
+    // Owner entries have no other match, netdev family matches devices frame by frame
+    if let Some(selector) = addr.selector.as_ref() {
+        return handle.tags.get(&line).map(|id| EntryMatch::tag(*id))
+            .or_else(|| Device::of(selector).map(EntryMatch::Device));
+    }
+
+    claim_match(quota, line).or_else(||
+        handle.sni_entries.iter().find(|entry| entry.0 == line).map(|entry| EntryMatch::sni(entry.2)))
+}
+
+// Limits rule to packets of entry networks, remote end is source on input and destination on output
+fn add_set_exprs(rule: &mut Rule, input: bool, set: &NetSet) {
+    rule.add_expr(&if input { nft_expr!(payload ipv4 saddr) } else { nft_expr!(payload ipv4 daddr) });
+    rule.add_expr(&Lookup { set });
+}
+
+// Limits rule to packets of the entry, if address alone does not tell
+fn add_match_exprs(rule: &mut Rule, matcher: Option<&EntryMatch>) {
+    match matcher {
+        Some(EntryMatch::Mark { conntrack, mask, value }) => {
+            rule.add_expr(&if *conntrack { nft_expr!(ct mark) } else { nft_expr!(meta mark) });
+            rule.add_expr(&nft_expr!(bitwise mask *mask, xor 0u32));
+            rule.add_expr(&nft_expr!(cmp == *value));
+        },
+        Some(EntryMatch::Device(device)) => add_device_exprs(rule, device),
+        None => (),
+    }
+}
+
+fn add_device_exprs(rule: &mut Rule, device: &Device) {
+    match device {
+        Device::Mac(mac) => {
+            rule.add_expr(&nft_expr!(payload ethernet saddr));
+            rule.add_expr(&nft_expr!(cmp == &mac[..]));
+        },
+        Device::InIface(name) => {
+            rule.add_expr(&nft_expr!(meta iifname));
+            rule.add_expr(&nft_expr!(cmp == iface_name(name).as_bytes_with_nul()));
+        },
+        Device::OutIface(name) => {
+            rule.add_expr(&nft_expr!(meta oifname));
+            rule.add_expr(&nft_expr!(cmp == iface_name(name).as_bytes_with_nul()));
+        },
+    }
+}
+
+// Tags flows of selector entry with its id, keeping the rest of conntrack mark. Flow keeps the
+// tag of the first entry it matched
+fn tag_rule<'a>(chain: &'a Chain, selector: &Selector, id: u32) -> Result<Rule<'a>, NfError> {
+    let mut rule = Rule::new(&chain);
+
+    rule.add_expr(&nft_expr!(ct mark));
+    rule.add_expr(&nft_expr!(bitwise mask TAG_MARK_MASK, xor 0u32));
+    rule.add_expr(&nft_expr!(cmp == 0u32));
+
+    match selector {
+        Selector::User(uid) => {
+            rule.add_expr(&nft_expr!(meta skuid));
+            rule.add_expr(&nft_expr!(cmp == *uid));
+        },
+        Selector::Cgroup(path) => {
+            let cgroup_id = cgroup::cgroup_id(path)
+                .map_err(|e| NfError::NfTablesError(format!("cgroup {}: {}", path, e)))?;
+
+            rule.add_expr(&SocketCgroup::new(path));
+            // Socket expression loads the id in host byte order
+            rule.add_expr(&nft_expr!(cmp == &cgroup_id.to_ne_bytes()[..]));
+        },
+        Selector::Mac(mac) => add_device_exprs(&mut rule, &Device::Mac(*mac)),
+        Selector::InIface(name) => add_device_exprs(&mut rule, &Device::InIface(name.clone())),
+        Selector::OutIface(name) => add_device_exprs(&mut rule, &Device::OutIface(name.clone())),
+    }
+
+    rule.add_expr(&nft_expr!(ct mark));
+    rule.add_expr(&nft_expr!(bitwise mask !TAG_MARK_MASK, xor (id << TAG_MARK_SHIFT)));
+    rule.add_expr(&nft_expr!(ct mark set));
+
+    Ok(rule)
+}
+
+// Chains, where flows of selector entry get tagged: link layer and incoming interface show on
+// packets from the device, outgoing interface on forward, socket owner on output
+fn tag_chains(family: &Family, selector: &Selector) -> &'static [&'static str] {
+    match (family, selector) {
+        (Family::Ipv4, Selector::Mac(_)) | (Family::Ipv4, Selector::InIface(_)) => &[DATA_IN_CHAIN_NAME, DATA_FWD_CHAIN_NAME],
+        (Family::Ipv4, Selector::OutIface(_)) => &[DATA_FWD_CHAIN_NAME],
+        (Family::Ipv4, _) => &[DATA_OUT_CHAIN_NAME],
+        // Other families hook all chains the same way
+        _ => &[DATA_IN_CHAIN_NAME],
+    }
+}
+
+// Interface names are compared along with terminating NUL, so that "eth1" does not match "eth10"
+fn iface_name(name: &str) -> CString {
+    CString::new(name).unwrap()
+}
+
+// Marks packet of entry networks with claim id, unless entry of higher rank claimed it already,
+// keeping the rest of the mark. Rules of any order thus agree on the claim. Claim of the kind
+// takes one byte of the mark, so masked marks compare the same whatever the byte order
+fn claim_rule<'a>(chain: &'a Chain, input: bool, set: &NetSet, quota: QuotaKind, claim: u32) -> Rule<'a> {
+    let mut rule = Rule::new(&chain);
+    let mask = claim_mask(quota);
+
+    add_set_exprs(&mut rule, input, set);
+
+    rule.add_expr(&nft_expr!(meta mark));
+    rule.add_expr(&nft_expr!(bitwise mask mask, xor 0u32));
+    rule.add_expr(&nft_expr!(cmp < claim));
+
+    rule.add_expr(&nft_expr!(meta mark));
+    rule.add_expr(&nft_expr!(bitwise mask !mask, xor claim));
+    rule.add_expr(&nft_expr!(meta mark set));
+
+    rule
+}
+
+// Copies first payload packet of new web flow to userspace, until the flow gets classified
+fn sni_rule(chain: &Chain, port: u16) -> Rule {
+    let mut rule = Rule::new(&chain);
+
+    rule.add_expr(&nft_expr!(meta l4proto));
+    rule.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
+    rule.add_expr(&nft_expr!(payload tcp dport));
+    rule.add_expr(&nft_expr!(cmp == port.to_be()));
+    rule.add_expr(&nft_expr!(ct mark));
+    rule.add_expr(&nft_expr!(bitwise mask SNI_SEEN, xor 0u32));
+    rule.add_expr(&nft_expr!(cmp == 0u32));
+    rule.add_expr(&nft_expr!(log .group(SNI_NUM)));
+
+    rule
+}
+
+// End of packet, which port of exemption is matched at
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum PortEnd {
+    Source,
+    Destination,
+}
+
+// Accept rule for exempted traffic, placed ahead of accounting and blocking rules.
+// Input rules match remote end by source, output ones by destination
+fn exempt_rule<'a>(chain: &'a Chain, input: bool, set: Option<&NetSet>, exempt: &Exempt) -> Rule<'a> {
+    accept_rule(chain, input, set, exempt, if input { PortEnd::Source } else { PortEnd::Destination })
+}
+
+// Accept rules for allowed traffic, as (port end, rule). Ports are matched at either end, so
+// that services of this host (e.g. inbound SSH) are not locked out, nor sessions it opens
+fn allow_rules<'a>(chain: &'a Chain, input: bool, allow: &Exempt) -> Vec<(Option<PortEnd>, Rule<'a>)> {
+    match allow {
+        Exempt::Net(_) => vec![(None, exempt_rule(chain, input, None, allow))],
+        Exempt::Port(..) => [PortEnd::Source, PortEnd::Destination].iter()
+            .map(|end| (Some(*end), accept_rule(chain, input, None, allow, *end)))
+            .collect(),
+    }
+}
+
+fn accept_rule<'a>(chain: &'a Chain, input: bool, set: Option<&NetSet>, exempt: &Exempt, end: PortEnd) -> Rule<'a> {
+    let mut rule = Rule::new(&chain);
+
+    let addr_expr = if input { nft_expr!(payload ipv4 saddr) } else { nft_expr!(payload ipv4 daddr) };
+
+    // Limited to traffic of single entry
+    if let Some(set) = set {
+        add_set_exprs(&mut rule, input, set);
+    }
+
+    match exempt {
+        Exempt::Net(exempt_net) => {
+            rule.add_expr(&addr_expr);
+            rule.add_expr(&nft_expr!(bitwise mask exempt_net.mask(), xor 0));
+            rule.add_expr(&nft_expr!(cmp == exempt_net.ip()));
+        },
+        Exempt::Port(proto, port) => {
+            rule.add_expr(&nft_expr!(meta l4proto));
+            rule.add_expr(&nft_expr!(cmp == *proto));
+
+            let port_expr = match (*proto as i32, end) {
+                (libc::IPPROTO_TCP, PortEnd::Source) => nft_expr!(payload tcp sport),
+                (libc::IPPROTO_TCP, PortEnd::Destination) => nft_expr!(payload tcp dport),
+                (_, PortEnd::Source) => nft_expr!(payload udp sport),
+                (_, PortEnd::Destination) => nft_expr!(payload udp dport),
+            };
+
+            rule.add_expr(&port_expr);
+            rule.add_expr(&nft_expr!(cmp == port.to_be()));
+        },
+    }
+
+    rule.add_expr(&nft_expr!(verdict accept));
+
+    rule
+}
+
+#[derive(Debug)]
+struct WindowRuleset<'a> {
+    // Rules for blocking input and output traffic of any protocol
+    block_in: Rule<'a>,
+    block_out: Rule<'a>,
+}
+
+impl WindowRuleset<'_> {
+    fn new<'a>(in_chain: &'a Chain, out_chain: &'a Chain, set: &NetSet, action: &Action) -> WindowRuleset<'a> {
+        let mut ruleset = WindowRuleset {
+            block_in: Rule::new(&in_chain),
+            block_out: Rule::new(&out_chain),
+        };
+
+        add_set_exprs(&mut ruleset.block_in, true, set);
+        add_action_exprs(&mut ruleset.block_in, action);
+
+        add_set_exprs(&mut ruleset.block_out, false, set);
+        add_action_exprs(&mut ruleset.block_out, action);
+
+        ruleset
+    }
 }
 
 impl TimeLimitRuleset<'_> {
-    fn new<'a>(out_chain: &'a Chain, in_chain: &'a Chain, ip_: &Ipv4Network, name: &'a str) -> TimeLimitRuleset<'a> {
-        let ip = ip_.clone();
+    fn new<'a>(
+        out_chain: &'a Chain,
+        in_chain: &'a Chain,
+        set: &NetSet,
+        name: &str,
+        track: Tracking,
+        action: &Action,
+        matcher: Option<&EntryMatch>) -> TimeLimitRuleset<'a> {
         let mut ruleset = TimeLimitRuleset {
             start: Rule::new(&in_chain),
             in_fin: Rule::new(&in_chain),
             out_fin: Rule::new(&out_chain),
             block_in: Rule::new(&in_chain),
             block_out: Rule::new(&out_chain),
+            activity_in: Rule::new(&in_chain),
+            activity_out: Rule::new(&out_chain),
         };
 
         // Input rule for connection start
         ruleset.start.add_expr(&nft_expr!(meta l4proto));
         ruleset.start.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
 
-        ruleset.start.add_expr(&nft_expr!(payload ipv4 saddr));
-        ruleset.start.add_expr(&nft_expr!(bitwise mask ip.mask(), xor 0));
-        ruleset.start.add_expr(&nft_expr!(cmp == ip.ip()));
+        add_set_exprs(&mut ruleset.start, true, set);
+        add_match_exprs(&mut ruleset.start, matcher);
 
         ruleset.start.add_expr(&nft_expr!(payload tcp flags));
         ruleset.start.add_expr(&nft_expr!(bitwise mask (TcpFlags::SYN | TcpFlags::ACK), xor (0 as u8)));
@@ -154,9 +926,8 @@ impl TimeLimitRuleset<'_> {
         ruleset.in_fin.add_expr(&nft_expr!(meta l4proto));
         ruleset.in_fin.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
 
-        ruleset.in_fin.add_expr(&nft_expr!(payload ipv4 saddr));
-        ruleset.in_fin.add_expr(&nft_expr!(bitwise mask ip.mask(), xor 0));
-        ruleset.in_fin.add_expr(&nft_expr!(cmp == ip.ip()));
+        add_set_exprs(&mut ruleset.in_fin, true, set);
+        add_match_exprs(&mut ruleset.in_fin, matcher);
 
         ruleset.in_fin.add_expr(&nft_expr!(payload tcp flags));
         ruleset.in_fin.add_expr(&nft_expr!(bitwise mask (TcpFlags::RST | TcpFlags::FIN), xor (0 as u8)));
@@ -172,9 +943,8 @@ impl TimeLimitRuleset<'_> {
         ruleset.out_fin.add_expr(&nft_expr!(meta l4proto));
         ruleset.out_fin.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
 
-        ruleset.out_fin.add_expr(&nft_expr!(payload ipv4 daddr));
-        ruleset.out_fin.add_expr(&nft_expr!(bitwise mask ip.mask(), xor 0));
-        ruleset.out_fin.add_expr(&nft_expr!(cmp == ip.ip()));
+        add_set_exprs(&mut ruleset.out_fin, false, set);
+        add_match_exprs(&mut ruleset.out_fin, matcher);
 
         ruleset.out_fin.add_expr(&nft_expr!(payload tcp flags));
         ruleset.out_fin.add_expr(&nft_expr!(bitwise mask (TcpFlags::RST | TcpFlags::FIN), xor (0 as u8)));
@@ -186,232 +956,1515 @@ impl TimeLimitRuleset<'_> {
             )
         );
 
-        // Input rule for conn block
-        ruleset.block_in.add_expr(&nft_expr!(meta l4proto));
-        ruleset.block_in.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
+        // Input rule for conn block. Conntrack tracked sessions can be of any L4 protocol
+        if track == Tracking::Tcp {
+            ruleset.block_in.add_expr(&nft_expr!(meta l4proto));
+            ruleset.block_in.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
+        }
 
-        ruleset.block_in.add_expr(&nft_expr!(payload ipv4 saddr));
-        ruleset.block_in.add_expr(&nft_expr!(bitwise mask ip.mask(), xor 0));
-        ruleset.block_in.add_expr(&nft_expr!(cmp == ip.ip()));
+        add_set_exprs(&mut ruleset.block_in, true, set);
+        add_match_exprs(&mut ruleset.block_in, matcher);
 
-        ruleset.block_in.add_expr(&nft_expr!(verdict reject));
+        add_action_exprs(&mut ruleset.block_in, action);
 
         // Output rule for conn block
-        ruleset.block_out.add_expr(&nft_expr!(meta l4proto));
-        ruleset.block_out.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
+        if track == Tracking::Tcp {
+            ruleset.block_out.add_expr(&nft_expr!(meta l4proto));
+            ruleset.block_out.add_expr(&nft_expr!(cmp == libc::IPPROTO_TCP as u8));
+        }
+
+        add_set_exprs(&mut ruleset.block_out, false, set);
+        add_match_exprs(&mut ruleset.block_out, matcher);
+
+        add_action_exprs(&mut ruleset.block_out, action);
 
-        ruleset.block_out.add_expr(&nft_expr!(payload ipv4 daddr));
-        ruleset.block_out.add_expr(&nft_expr!(bitwise mask ip.mask(), xor 0));
-        ruleset.block_out.add_expr(&nft_expr!(cmp == ip.ip()));
+        // Input and output rules for activity, only metadata is needed
+        let activity_prefix = CString::new(format!("{}{}", TIME_ACTIVITY_LOG_PREFIX, name.to_owned())).unwrap();
 
-        ruleset.block_out.add_expr(&nft_expr!(verdict reject));
+        add_set_exprs(&mut ruleset.activity_in, true, set);
+        add_match_exprs(&mut ruleset.activity_in, matcher);
+        ruleset.activity_in.add_expr(&Limit::new(TIME_ACTIVITY_RATE as u64, LimitUnit::Second, LimitType::Packets));
+        ruleset.activity_in.add_expr(&nft_expr!(
+            log .group(TIME_ACTIVITY_NUM)
+                .snaplen(0)
+                .prefix(&activity_prefix)
+            )
+        );
 
+        add_set_exprs(&mut ruleset.activity_out, false, set);
+        add_match_exprs(&mut ruleset.activity_out, matcher);
+        ruleset.activity_out.add_expr(&Limit::new(TIME_ACTIVITY_RATE as u64, LimitUnit::Second, LimitType::Packets));
+        ruleset.activity_out.add_expr(&nft_expr!(
+            log .group(TIME_ACTIVITY_NUM)
+                .snaplen(0)
+                .prefix(&activity_prefix)
+            )
+        );
 
         ruleset
     }
 }
 
 impl DataLimitRuleset<'_> {
-    fn new<'a>(in_chain: &'a Chain, ip: &Ipv4Network, quota_obj: &Quota) -> DataLimitRuleset<'a> {
+    fn new<'a>(
+        in_chain: &'a Chain,
+        set: &NetSet,
+        ends: &[bool],
+        quota_obj: &Quota,
+        action: &Action,
+        matcher: Option<&EntryMatch>) -> DataLimitRuleset<'a> {
         let mut ruleset = DataLimitRuleset {
-            block: Rule::new(&in_chain),
-            log: Rule::new(&in_chain),
+            log: Vec::new(),
+            block: Vec::new(),
         };
 
-        // Input rule for quota accounting and blocking when overflow
-        ruleset.block.add_expr(&nft_expr!(payload ipv4 saddr));
-        ruleset.block.add_expr(&nft_expr!(bitwise mask ip.mask(), xor 0));
-        ruleset.block.add_expr(&nft_expr!(cmp == ip.ip()));
-        ruleset.block.add_expr(&nft_expr!(quota quota_obj));
-        ruleset.block.add_expr(&nft_expr!(verdict drop));
-
         let prefix = quota_obj.get_name();
-        // Input rule for quota accounting and starting to send logs when overflows
-        ruleset.log.add_expr(&nft_expr!(payload ipv4 saddr));
-        ruleset.log.add_expr(&nft_expr!(bitwise mask ip.mask(), xor 0));
-        ruleset.log.add_expr(&nft_expr!(cmp == ip.ip()));
-        ruleset.log.add_expr(&nft_expr!(quota quota_obj));
-        ruleset.log.add_expr(&nft_expr!(
-            log .group(DATA_QUOTA_NUM)
-                .snaplen(0)
-                .prefix(&prefix.to_owned()) 
-            )
-        );
+
+        for source in ends.iter() {
+            // Rule for quota accounting, starting to send logs and enforcing when overflows. Packet
+            // matches set at one end only, thus it is accounted once
+            let mut log = Rule::new(&in_chain);
+
+            add_set_exprs(&mut log, *source, set);
+            add_match_exprs(&mut log, matcher);
+            log.add_expr(&nft_expr!(quota quota_obj));
+            log.add_expr(&nft_expr!(
+                log .group(DATA_QUOTA_NUM)
+                    .snaplen(0)
+                    .prefix(&prefix.to_owned()) 
+                )
+            );
+            add_action_exprs(&mut log, action);
+
+            // Rule for blocking, after overflow was reported
+            let mut block = Rule::new(&in_chain);
+
+            add_set_exprs(&mut block, *source, set);
+            add_match_exprs(&mut block, matcher);
+            add_action_exprs(&mut block, action);
+
+            ruleset.log.push(log);
+            ruleset.block.push(block);
+        }
 
         ruleset
     }
-}
-
 
+    // Adds or deletes block rules if `blocked` is set, log rules otherwise
+    fn apply(&self, batch: &mut Batch, msg: nftnl::MsgType, blocked: bool) {
+        for rule in if blocked { self.block.iter() } else { self.log.iter() } {
+            batch.add(rule, msg);
+        }
+    }
 
-// TODO this need some generics ...
-#[derive(Debug)]
-pub struct NfTimeLimit<'a> {
-    timer: ConnTimer<'a>,
+    // Switches from accounting to enforcing
+    fn enforce(&self, batch: &mut Batch) {
+        self.apply(batch, nftnl::MsgType::Del, false);
+        self.apply(batch, nftnl::MsgType::Add, true);
+    }
 
-    rules: HashMap<Ipv4Network, TimeLimitRuleset<'a>>,
+    // Switches from enforcing back to accounting
+    fn release(&self, batch: &mut Batch) {
+        self.apply(batch, nftnl::MsgType::Del, true);
+        self.apply(batch, nftnl::MsgType::Add, false);
+    }
 }
 
-#[derive(Debug)]
-pub struct NfDataLimit<'a> {
-    // Quota object in NF
-    quota: Quota<'a>,
+// Batch operations, which move data entry into tier `idx`: block rules of the tier take place of
+// its log rules. Rules behind the tier (later tiers, main quota and counters) are taken out and
+// added back after them, as appended rules would let packets stopped by the tier be accounted further
+fn tier_ops<'r, 'a>(
+    tiers: &'r [NfDataTier<'a>],
+    idx: usize,
+    main: Vec<&'r Rule<'a>>,
+    count_rules: Option<&'r CountRuleset<'a>>) -> Vec<(&'r Rule<'a>, nftnl::MsgType)> {
+    let tier = &tiers[idx];
+    let behind: Vec<&Rule> = tiers[idx + 1..].iter()
+        .flat_map(|tier| if tier.reached { &tier.rules.block } else { &tier.rules.log })
+        .chain(main)
+        .chain(count_rules.iter().flat_map(|ruleset| ruleset.rules.iter().flat_map(|(hosts, dests)| vec![hosts, dests])))
+        .collect();
+
+    tier.rules.log.iter().map(|rule| (rule, nftnl::MsgType::Del))
+        .chain(behind.iter().map(|rule| (*rule, nftnl::MsgType::Del)))
+        .chain(tier.rules.block.iter().map(|rule| (rule, nftnl::MsgType::Add)))
+        .chain(behind.iter().map(|rule| (*rule, nftnl::MsgType::Add)))
+        .collect()
+}
 
-    rules: HashMap<Ipv4Network, DataLimitRuleset<'a>>
+// Ends of packet the entry set is matched at, source for set of remote networks. Client entries
+// hold the client, which is destination of downloads and source of uploads
+fn set_ends(client: bool) -> &'static [bool] {
+    if client { &[false, true] } else { &[true] }
 }
 
-trait NfAction {
-    fn add(&self);
+// Addresses of entry, learned from DNS responses, with their expiry. Kernel expires them in
+// the entry set, the expiry here only keeps `nets` of the entry for status and flow matching
+#[derive(Debug)]
+pub struct DynamicNets {
+    pattern: String,
+    expiry: HashMap<Ipv4Addr, Instant>,
+}
 
-    fn delete(&self);
+impl DynamicNets {
+    fn new(pattern: &str) -> DynamicNets {
+        DynamicNets { pattern: pattern.to_owned(), expiry: HashMap::new() }
+    }
 
-    fn block(&self);
+    /// Takes addresses from matching answer, returns them with their timeouts.
+    fn learn(&mut self, answer: &dns::DnsAnswer) -> Vec<(Ipv4Addr, Duration)> {
+        if !answer.names.iter().any(|name| dns::matches(&self.pattern, name)) {
+            return Vec::new();
+        }
 
-    fn unblock(&self);
-}
+        let now = Instant::now();
 
-impl<'a> NfAction for NfTimeLimit<'a> {
-    fn add(&self) {
-        let mut batch = Batch::new();
+        self.expiry.retain(|_, expiry| *expiry > now);
 
-        // Adding monitor rules
-        for (_, ruleset) in self.rules.iter() {
-            batch.add(&ruleset.start, nftnl::MsgType::Add);
-            batch.add(&ruleset.in_fin, nftnl::MsgType::Add);
-            batch.add(&ruleset.out_fin, nftnl::MsgType::Add);
-        }
+        answer.addrs.iter().map(|(addr, ttl)| {
+            let timeout = Duration::from_secs(*ttl as u64).max(DNS_MIN_TTL);
 
-        process_netlink(&(batch.finalize()), false).unwrap();
-        
-        unsafe {
-            let callback = Box::new(move || self.block());
+            self.expiry.insert(*addr, now + timeout);
 
-            self.timer.set_callback(callback);
-        }
+            (*addr, timeout)
+        }).collect()
     }
 
-    fn delete(&self) {
-        let mut batch = Batch::new();
-
-        // Clearing monitor and block rules
-        for (_, ruleset) in self.rules.iter() {
-            batch.add(&ruleset.start, nftnl::MsgType::Del);
-            batch.add(&ruleset.in_fin, nftnl::MsgType::Del);
-            batch.add(&ruleset.out_fin, nftnl::MsgType::Del);
-            batch.add(&ruleset.block_in, nftnl::MsgType::Del);
-            batch.add(&ruleset.block_out, nftnl::MsgType::Del);
-        }
+    /// Networks, which have not expired at the last answer.
+    fn nets(&self) -> Vec<Ipv4Network> {
+        self.expiry.keys().map(|addr| Ipv4Network::new(*addr, 32).unwrap()).collect()
+    }
+}
 
-        process_netlink(&(batch.finalize()), false).unwrap();
+// Adds addresses of matching DNS answer to timed entry set, the kernel drops them after their
+// TTL. Element already in the set keeps its old timeout, it is added again by the first answer
+// after it expired
+fn learn_nets(name: &str, set: &NetSet, dynamic: &mut DynamicNets, nets: &mut Vec<Ipv4Network>, answer: &dns::DnsAnswer) {
+    let addrs = dynamic.learn(answer);
 
-        self.timer.clear_callback();
+    if addrs.is_empty() {
+        return;
     }
 
-    fn block(&self) {
-        let mut batch = Batch::new();
+    let mut batch = Batch::new();
 
-        // Adding block rules
-        for (_, ruleset) in self.rules.iter() {
-            batch.add(&ruleset.block_in, nftnl::MsgType::Add);
-            batch.add(&ruleset.block_out, nftnl::MsgType::Add);
-        }
+    batch.add(&set.timed_elems(&addrs), nftnl::MsgType::Add);
+    process_netlink(&(batch.finalize()), false).unwrap();
 
-        process_netlink(&(batch.finalize()), false).unwrap();
+    let learned = dynamic.nets();
+    let (added, removed) = nets_diff(nets, &learned);
+
+    if !added.is_empty() || !removed.is_empty() {
+        info!("{}: {} networks added, {} removed", name, added.len(), removed.len());
     }
 
-    fn unblock(&self) {
-        let mut batch = Batch::new();
+    *nets = learned;
+    unmatched_nets(&added, &removed);
+}
 
-        // Clearing block rules
-        for (_, ruleset) in self.rules.iter() {
-            batch.add(&ruleset.block_in, nftnl::MsgType::Del);
-            batch.add(&ruleset.block_out, nftnl::MsgType::Del);
-        }
+// Set of entry networks. Addresses learned from DNS are single ones with timeouts
+fn entry_set(name: &str, addr: &Address, table: &Table) -> NetSet {
+    let name = format!("{}{}", name, NET_SET_SUFFIX);
 
-        process_netlink(&(batch.finalize()), false).unwrap();
+    match addr.snoop {
+        Some(_) => NetSet::timed(&name, table),
+        None => NetSet::new(&name, table),
     }
 }
 
-impl NfAction for NfDataLimit<'_> {
-    fn add(&self) {
-        let mut batch = Batch::new();
-
-        for (_, ruleset) in self.rules.iter() {
-            batch.add(&ruleset.block, nftnl::MsgType::Add);
-            batch.add(&ruleset.log, nftnl::MsgType::Add);
+/// Catch-all counters of traffic, which no entry matches, by remote address.
+#[derive(Debug)]
+pub struct NfUnmatched<'a> {
+    // Remote addresses of input and output traffic, flushed on each sample
+    sets: (HostSet, HostSet),
+    // Networks of entries, skipped by (input, output) rules. Network might be
+    // listed by several entries, thus they are counted
+    skip_set: NetSet,
+    skip_refs: HashMap<Ipv4Network, u32>,
+    skip: (Rule<'a>, Rule<'a>),
+    // SNI classified flows are matched by conntrack mark, not by address
+    skip_sni: Option<(Rule<'a>, Rule<'a>)>,
+    // Same for flows of selector entries
+    skip_selectors: Vec<(Rule<'a>, Rule<'a>)>,
+    // Final rules, which update the sets
+    count: (Rule<'a>, Rule<'a>),
+    // Names of remote addresses, learned from DNS responses
+    names: HashMap<Ipv4Addr, String>,
+    // Bytes since start by name or /24, the total and top remotes of them
+    totals: HashMap<String, u64>,
+    total: u64,
+    top: Vec<(String, u64)>,
+}
+
+impl<'a> NfUnmatched<'a> {
+    fn new(in_chain: &'a Chain, out_chain: &'a Chain, sni: bool, selectors: &[EntryMatch]) -> NfUnmatched<'a> {
+        let sets = (
+            HostSet::new(UNMATCHED_IN_SET, in_chain.get_table()),
+            HostSet::new(UNMATCHED_OUT_SET, out_chain.get_table()),
+        );
+        let skip_set = NetSet::new(UNMATCHED_SKIP_SET, in_chain.get_table());
+
+        let mut count = (Rule::new(&in_chain), Rule::new(&out_chain));
+
+        count.0.add_expr(&nft_expr!(payload ipv4 saddr));
+        count.0.add_expr(&Dynset { set: &sets.0 });
+        count.1.add_expr(&nft_expr!(payload ipv4 daddr));
+        count.1.add_expr(&Dynset { set: &sets.1 });
+
+        let mut skip = (Rule::new(&in_chain), Rule::new(&out_chain));
+
+        add_set_exprs(&mut skip.0, true, &skip_set);
+        skip.0.add_expr(&nft_expr!(verdict accept));
+        add_set_exprs(&mut skip.1, false, &skip_set);
+        skip.1.add_expr(&nft_expr!(verdict accept));
+
+        let skip_sni = if sni {
+            let mut rules = (Rule::new(&in_chain), Rule::new(&out_chain));
+
+            for rule in [&mut rules.0, &mut rules.1].iter_mut() {
+                rule.add_expr(&nft_expr!(ct mark));
+                rule.add_expr(&nft_expr!(bitwise mask SNI_MARK_MASK, xor 0u32));
+                rule.add_expr(&nft_expr!(cmp != 0u32));
+                rule.add_expr(&nft_expr!(verdict accept));
+            }
+
+            Some(rules)
+        } else {
+            None
+        };
+
+        let skip_selectors = selectors.iter()
+            .map(|selector| {
+                let mut rules = (Rule::new(&in_chain), Rule::new(&out_chain));
+
+                for rule in [&mut rules.0, &mut rules.1].iter_mut() {
+                    add_match_exprs(rule, Some(selector));
+                    rule.add_expr(&nft_expr!(verdict accept));
+                }
+
+                rules
+            })
+            .collect();
+
+        NfUnmatched {
+            sets,
+            skip_set,
+            skip_refs: HashMap::new(),
+            skip,
+            skip_sni,
+            skip_selectors,
+            count,
+            names: HashMap::new(),
+            totals: HashMap::new(),
+            total: 0,
+            top: Vec::new(),
+        }
+    }
+
+    /// Counts networks entries gained or lost, skip set is swapped at once if they changed.
+    fn set_nets(&mut self, added: &[Ipv4Network], removed: &[Ipv4Network]) {
+        if !count_refs(&mut self.skip_refs, added, removed) {
+            return;
+        }
+
+        let nets: Vec<Ipv4Network> = self.skip_refs.keys().cloned().collect();
+        let mut batch = Batch::new();
+
+        batch.add(&self.skip_set.flush(), nftnl::MsgType::Del);
+
+        for elems in self.skip_set.elems(&nets) {
+            batch.add(&elems, nftnl::MsgType::Add);
+        }
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+    }
+
+    /// Adds sets and rules, skip rules go ahead of count ones.
+    fn add(&mut self, nets: &[Ipv4Network]) {
+        let mut batch = Batch::new();
+
+        batch.add(&self.sets.0, nftnl::MsgType::Add);
+        batch.add(&self.sets.1, nftnl::MsgType::Add);
+        batch.add(&self.skip_set, nftnl::MsgType::Add);
+
+        for (rule_in, rule_out) in std::iter::once(&self.skip).chain(self.skip_sni.iter()).chain(self.skip_selectors.iter()) {
+            batch.add(rule_in, nftnl::MsgType::Add);
+            batch.add(rule_out, nftnl::MsgType::Add);
+        }
+
+        batch.add(&self.count.0, nftnl::MsgType::Add);
+        batch.add(&self.count.1, nftnl::MsgType::Add);
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+
+        self.set_nets(nets, &[]);
+    }
+
+    /// Remembers names of answered addresses.
+    fn learn(&mut self, answer: &dns::DnsAnswer) {
+        let name = match answer.names.first() {
+            Some(name) => name,
+            None => return,
+        };
+
+        if self.names.len() >= DNS_NAMES_MAX {
+            self.names.clear();
+        }
+
+        for (addr, _) in answer.addrs.iter() {
+            self.names.insert(*addr, name.clone());
+        }
+    }
+
+    /// Reads counters of both directions into totals, then flushes the sets.
+    fn sample(&mut self, top: usize) {
+        let mut counters = Vec::new();
+
+        for set in [&self.sets.0, &self.sets.1].iter() {
+            match fetch_set_counters(set.get_name()) {
+                Ok(set_counters) => counters.extend(set_counters),
+                Err(e) => {
+                    warn!("{}: failed to read counters: {:?}", set.get_name().to_string_lossy(), e);
+                    return;
+                },
+            }
+        }
+
+        // Sets hold remotes of one tick only, thus they never fill up. Bytes counted between
+        // the read and the flush are lost
+        let mut batch = Batch::new();
+
+        batch.add(&self.sets.0.flush(), nftnl::MsgType::Del);
+        batch.add(&self.sets.1.flush(), nftnl::MsgType::Del);
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+
+        self.total += accumulate(&mut self.totals, &self.names, &counters);
+        self.top = top_labels(&self.totals, top);
+
+        debug!("unmatched traffic: {}b, top: {:?}", self.total, self.top);
+    }
+
+    pub fn status(&self) -> String {
+        let top: Vec<String> = self.top.iter().map(|(label, bytes)| format!("{} {}b", label, bytes)).collect();
+
+        format!("unmatched: {}b\n  top: {}\n", self.total, if top.is_empty() { "-".to_owned() } else { top.join(", ") })
+    }
+}
+
+// Adds and removes references of networks, returns whether networks came or went
+fn count_refs(refs: &mut HashMap<Ipv4Network, u32>, added: &[Ipv4Network], removed: &[Ipv4Network]) -> bool {
+    let mut changed = false;
+
+    for net in removed.iter() {
+        if let Some(count) = refs.get_mut(net) {
+            *count -= 1;
+
+            if *count == 0 {
+                refs.remove(net);
+                changed = true;
+            }
+        }
+    }
+
+    for net in added.iter() {
+        let count = refs.entry(*net).or_insert(0);
+
+        changed |= *count == 0;
+        *count += 1;
+    }
+
+    changed
+}
+
+// Adds counters to totals by name, or by /24 of unnamed remotes, returns their sum. Beyond
+// UNMATCHED_LABELS_MAX labels the smaller half is dropped, it would hardly make the top
+fn accumulate(totals: &mut HashMap<String, u64>, names: &HashMap<Ipv4Addr, String>, counters: &[(Ipv4Addr, u64)]) -> u64 {
+    let mut sum = 0;
+
+    for (addr, bytes) in counters.iter() {
+        let label = match names.get(addr) {
+            Some(name) => name.clone(),
+            None => Ipv4Network::new(*addr, 24).map(|net| format!("{}/24", net.network())).unwrap(),
+        };
+
+        *totals.entry(label).or_insert(0) += bytes;
+        sum += bytes;
+    }
+
+    if totals.len() > UNMATCHED_LABELS_MAX {
+        let kept: HashMap<String, u64> = top_labels(totals, UNMATCHED_LABELS_MAX / 2).into_iter().collect();
+        *totals = kept;
+    }
+
+    sum
+}
+
+// Largest totals, ties by label
+fn top_labels(totals: &HashMap<String, u64>, top: usize) -> Vec<(String, u64)> {
+    let mut labels: Vec<(String, u64)> = totals.iter().map(|(label, bytes)| (label.clone(), *bytes)).collect();
+
+    labels.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+    labels.truncate(top);
+
+    labels
+}
+
+/// LAN clients, which got automatic entries, and the ones waiting for them.
+#[derive(Debug)]
+pub struct NfClients {
+    config: Clients,
+    // Clients in order of appearance, position gives entry names and lines
+    known: Vec<Ipv4Addr>,
+    // Addresses, which have entries in config
+    listed: Vec<Ipv4Addr>,
+    // Seen by conntrack thread, entries are created on next tick
+    pending: Mutex<Vec<Ipv4Addr>>,
+    leases_mtime: Option<SystemTime>,
+}
+
+impl NfClients {
+    fn new(config: &Clients, listed: Vec<Ipv4Addr>) -> NfClients {
+        NfClients {
+            config: config.clone(),
+            known: Vec::new(),
+            listed,
+            pending: Mutex::new(Vec::new()),
+            leases_mtime: None,
+        }
+    }
+
+    fn is_new(&self, addr: &Ipv4Addr) -> bool {
+        self.config.lans.iter().any(|lan| lan.contains(*addr))
+            && !self.known.contains(addr)
+            && !self.listed.contains(addr)
+    }
+
+    // Client of given config line
+    fn client(&self, line: u32) -> Option<&Ipv4Addr> {
+        self.known.get(line.checked_sub(CLIENT_LINE_BASE)? as usize)
+    }
+
+    /// Queues LAN address of new flow. Known and listed ones are sorted out on the tick, as
+    /// they change there.
+    fn seen(&self, addr: &Ipv4Addr) {
+        if !self.config.lans.iter().any(|lan| lan.contains(*addr)) {
+            return;
+        }
+
+        let mut pending = self.pending.lock().unwrap();
+
+        if !pending.contains(addr) {
+            pending.push(*addr);
+        }
+    }
+
+    /// Reads clients, kept by previous run.
+    fn load_state(&self) -> Vec<Ipv4Addr> {
+        match std::fs::read_to_string(&self.config.state) {
+            Ok(state) => state.lines().filter_map(|line| line.trim().parse::<Ipv4Addr>().ok()).collect(),
+            Err(e) => {
+                debug!("No client state at {}: {}", self.config.state, e);
+                Vec::new()
+            },
+        }
+    }
+
+    fn save_state(&self) {
+        let state: String = self.known.iter().map(|addr| format!("{}\n", addr)).collect();
+        let tmp = format!("{}.tmp", self.config.state);
+
+        if let Some(dir) = Path::new(&self.config.state).parent() {
+            let _ = std::fs::create_dir_all(dir);
+        }
+
+        if let Err(e) = std::fs::write(&tmp, state).and_then(|_| std::fs::rename(&tmp, &self.config.state)) {
+            error!("Failed to write client state {}: {}", self.config.state, e);
+        }
+    }
+
+    /// Reads dnsmasq leases ("<expiry> <mac> <ip> <hostname> <client id>"), if file changed.
+    fn leases(&mut self) -> Vec<Ipv4Addr> {
+        let path = match self.config.leases.as_ref() {
+            Some(path) => path,
+            None => return Vec::new(),
+        };
+
+        let mtime = std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+
+        if mtime.is_none() || mtime == self.leases_mtime {
+            return Vec::new();
+        }
+
+        self.leases_mtime = mtime;
+
+        match std::fs::read_to_string(path) {
+            Ok(leases) => leases.lines()
+                .filter_map(|line| line.split_whitespace().nth(2)?.parse::<Ipv4Addr>().ok())
+                .collect(),
+            Err(e) => {
+                warn!("Failed to read leases file {}: {}", path, e);
+                Vec::new()
+            },
+        }
+    }
+}
+
+// Creates data entries of new client from template
+fn add_client(addr: Ipv4Addr) {
+    let clients = NfHandle::get().clients.as_mut().unwrap();
+
+    if !clients.is_new(&addr) {
+        return;
+    }
+
+    if clients.known.len() >= CLIENTS_MAX {
+        warn!("Client {} is ignored, there are {} clients already", addr, CLIENTS_MAX);
+        return;
+    }
+
+    let quotas = match clients.config.entry(addr) {
+        Ok(quotas) => quotas,
+        Err(e) => {
+            error!("Failed to create entry of client {}: {}", addr, e);
+            return;
+        },
+    };
+
+    let idx = clients.known.len();
+
+    for (pos, mut acc_entry) in quotas.into_iter().enumerate() {
+        let name = format!("{}c{}_{}", DATA_LOG_PREFIX, idx, pos);
+
+        acc_entry.line = CLIENT_LINE_BASE + idx as u32;
+
+        // Internet traffic of LAN clients is forwarded
+        let mut limit = NfDataLimit::new(
+            &acc_entry,
+            NfHandle::get().chains.get(DATA_FWD_CHAIN_NAME).unwrap(),
+            kill_chains(),
+            &name
+        );
+
+        add_net_set(&limit.set, &limit.nets);
+        limit.add();
+
+        NfHandle::get().data_entries.insert(name, limit);
+    }
+
+    clients.known.push(addr);
+
+    info!("New client {}, entry created", addr);
+
+    unmatched_nets(&[Ipv4Network::new(addr, 32).unwrap()], &[]);
+}
+
+// Creates entries of clients, which showed up since last tick
+fn update_clients() {
+    let clients = match NfHandle::get().clients.as_mut() {
+        Some(clients) => clients,
+        None => return,
+    };
+
+    let mut addrs: Vec<Ipv4Addr> = clients.pending.lock().unwrap().drain(..).collect();
+    addrs.extend(clients.leases());
+
+    let before = clients.known.len();
+
+    for addr in addrs {
+        add_client(addr);
+    }
+
+    if NfHandle::get().clients.as_ref().map_or(0, |clients| clients.known.len()) != before {
+        NfHandle::get().clients.as_ref().unwrap().save_state();
+    }
+}
+
+// Keeps unmatched counters away from networks entries gained or lost
+fn unmatched_nets(added: &[Ipv4Network], removed: &[Ipv4Network]) {
+    if let Some(unmatched) = NfHandle::get().unmatched.as_mut() {
+        unmatched.set_nets(added, removed);
+    }
+}
+
+// Creates interval set of entry networks. It stays for the lifetime of the entry, unlike
+// its rules, which come and go with periods
+fn add_net_set(set: &NetSet, nets: &[Ipv4Network]) {
+    let mut batch = Batch::new();
+
+    batch.add(set, nftnl::MsgType::Add);
+
+    for elems in set.elems(nets) {
+        batch.add(&elems, nftnl::MsgType::Add);
+    }
+
+    process_netlink(&(batch.finalize()), false).unwrap();
+}
+
+// Networks of `nets`, which are not in `old`, and the other way round. Lists hold
+// thousands of networks, thus they are looked up in hash sets
+fn nets_diff(old: &[Ipv4Network], nets: &[Ipv4Network]) -> (Vec<Ipv4Network>, Vec<Ipv4Network>) {
+    let (old_nets, new_nets): (HashSet<&Ipv4Network>, HashSet<&Ipv4Network>) = (old.iter().collect(), nets.iter().collect());
+
+    (
+        nets.iter().filter(|net| !old_nets.contains(net)).cloned().collect(),
+        old.iter().filter(|net| !new_nets.contains(net)).cloned().collect(),
+    )
+}
+
+// Replaces elements of entry set with given networks, returns (added, removed) ones. Flush and
+// new elements go in one transaction, thus no packet sees a list half way reloaded
+fn swap_nets(set: &NetSet, old: &[Ipv4Network], nets: &[Ipv4Network]) -> (Vec<Ipv4Network>, Vec<Ipv4Network>) {
+    let (added, removed) = nets_diff(old, nets);
+
+    if added.is_empty() && removed.is_empty() {
+        return (added, removed);
+    }
+
+    let mut batch = Batch::new();
+
+    batch.add(&set.flush(), nftnl::MsgType::Del);
+
+    for elems in set.elems(nets) {
+        batch.add(&elems, nftnl::MsgType::Add);
+    }
+
+    process_netlink(&(batch.finalize()), false).unwrap();
+
+    (added, removed)
+}
+
+// TODO this need some generics ...
+#[derive(Debug)]
+pub struct NfTimeLimit<'a> {
+    name: String,
+    // Config line of the entry
+    line: u32,
+
+    quota: Duration,
+    period: Period,
+    // Id of current period, quota is reset when it changes
+    period_id: Option<i64>,
+    // Quota is exceeded and block took place
+    blocked: bool,
+
+    timer: ConnTimer,
+    // How sessions are detected
+    track: Tracking,
+    // Activity mode idle gap
+    idle: Option<Duration>,
+    // What block does to traffic
+    action: Action,
+    // Number of currently open sessions
+    sessions: u32,
+    // Destination to resolve again on reload
+    source: Option<String>,
+    // Addresses learned from DNS, if entry is given by domain pattern
+    dynamic: Option<DynamicNets>,
+    // Own and global exemptions, conntrack tracked sessions are checked against them
+    exempt: Vec<Exempt>,
+
+    // Networks of the entry and interval set, its rules look them up in
+    nets: Vec<Ipv4Network>,
+    set: NetSet,
+
+    rules: TimeLimitRuleset<'a>,
+    // None, unless entry kills flows on block
+    kill_rules: Option<KillRuleset<'a>>,
+    // None, unless entry overlaps other time entries
+    claim_rules: Option<ClaimRuleset<'a>>,
+}
+
+#[derive(Debug)]
+pub struct NfWindowLimit<'a> {
+    name: String,
+    // Config line of the entry
+    line: u32,
+
+    window: Window,
+    blocked: bool,
+    action: Action,
+    // Destination to resolve again on reload
+    source: Option<String>,
+    // Addresses learned from DNS, if entry is given by domain pattern
+    dynamic: Option<DynamicNets>,
+
+    // Networks of the entry and interval set, its rules look them up in
+    nets: Vec<Ipv4Network>,
+    set: NetSet,
+
+    rules: WindowRuleset<'a>,
+    // None, unless entry kills flows on block
+    kill_rules: Option<KillRuleset<'a>>,
+}
+
+// Throttling step, applied before the main quota of data entry
+#[derive(Debug)]
+pub struct NfDataTier<'a> {
+    quota: Quota<'a>,
+    action: Action,
+    reached: bool,
+
+    rules: DataLimitRuleset<'a>,
+}
+
+#[derive(Debug)]
+pub struct NfDataLimit<'a> {
+    // Config line of the entry
+    line: u32,
+
+    // Quota object in NF
+    quota: Quota<'a>,
+    limit: u64,
+    period: Period,
+    // Id of current period, quota is reset when it changes
+    period_id: Option<i64>,
+    // Quota is exceeded and block took place
+    blocked: bool,
+    // Sliding window: (bucket start, consumed bytes) samples and usage in the window
+    samples: VecDeque<(Instant, u64)>,
+    window_usage: u64,
+    // Unused allowance carried into current period, up to `rollover` periods
+    rollover: Option<u32>,
+    carry: u64,
+    action: Action,
+    // Destination to resolve again on reload
+    source: Option<String>,
+    // Addresses learned from DNS, if entry is given by domain pattern
+    dynamic: Option<DynamicNets>,
+
+    // Networks of the entry and interval set, its rules look them up in
+    nets: Vec<Ipv4Network>,
+    set: NetSet,
+
+    rules: DataLimitRuleset<'a>,
+    // Ascending throttling tiers, reached before the quota
+    tiers: Vec<NfDataTier<'a>>,
+    // None, unless entry kills flows on block
+    kill_rules: Option<KillRuleset<'a>>,
+    // None, unless entry overlaps other data entries
+    claim_rules: Option<ClaimRuleset<'a>>,
+    // (hosts, destinations) counter sets, unless top talkers are turned off
+    talkers: Option<(HostSet, HostSet)>,
+    count_rules: Option<CountRuleset<'a>>,
+}
+
+trait NfAction {
+    fn add(&mut self);
+
+    fn delete(&mut self);
+
+    fn block(&mut self);
+
+    fn unblock(&mut self);
+}
+
+impl<'a> NfAction for NfTimeLimit<'a> {
+    fn add(&mut self) {
+        if let Some(idle) = self.idle {
+            let mut batch = Batch::new();
+
+            // Adding activity rules, timer runs all the time and counts only activity
+            batch.add(&self.rules.activity_in, nftnl::MsgType::Add);
+            batch.add(&self.rules.activity_out, nftnl::MsgType::Add);
+
+            process_netlink(&(batch.finalize()), false).unwrap();
+
+            self.timer.set_idle(&idle);
+            self.timer.start();
+        } else if self.track == Tracking::Tcp {
+            // Conntrack tracked sessions need no monitor rules
+            let mut batch = Batch::new();
+
+            // Adding monitor rules
+            batch.add(&self.rules.start, nftnl::MsgType::Add);
+            batch.add(&self.rules.in_fin, nftnl::MsgType::Add);
+            batch.add(&self.rules.out_fin, nftnl::MsgType::Add);
+
+            process_netlink(&(batch.finalize()), false).unwrap();
+        }
+
+        let name = self.name.clone();
+
+        // Timer fires from its own thread, thus entry is looked up by name. Period might
+        // have rolled over, while the lock was waited for
+        self.timer.set_callback(Arc::new(move || {
+            let _handle = lock_handle();
+
+            let limit = NfHandle::get().time_entries.get_mut(&name)
+                .filter(|limit| !limit.blocked && limit.timer.elapsed() >= limit.quota);
+
+            if let Some(limit) = limit {
+                info!("Time quota {} exceeded, blocking", name);
+
+                limit.block();
+            }
+        }));
+    }
+
+    fn delete(&mut self) {
+        let mut batch = Batch::new();
+
+        // Clearing monitor and block rules
+        if self.idle.is_some() {
+            batch.add(&self.rules.activity_in, nftnl::MsgType::Del);
+            batch.add(&self.rules.activity_out, nftnl::MsgType::Del);
+        } else if self.track == Tracking::Tcp {
+            batch.add(&self.rules.start, nftnl::MsgType::Del);
+            batch.add(&self.rules.in_fin, nftnl::MsgType::Del);
+            batch.add(&self.rules.out_fin, nftnl::MsgType::Del);
+        }
+        batch.add(&self.rules.block_in, nftnl::MsgType::Del);
+        batch.add(&self.rules.block_out, nftnl::MsgType::Del);
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+
+        self.timer.clear_callback();
+    }
+
+    fn block(&mut self) {
+        self.blocked = true;
+
+        // Soft quota, nothing to block
+        if !self.action.is_enforcing() {
+            warn!("Time quota {} exceeded, not blocking (log only)", self.name);
+            return;
+        }
+
+        let mut batch = Batch::new();
+
+        // Adding block rules
+        batch.add(&self.rules.block_in, nftnl::MsgType::Add);
+        batch.add(&self.rules.block_out, nftnl::MsgType::Add);
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+
+        kill_flows(&self.name, &self.kill_rules, &self.nets);
+    }
+
+    fn unblock(&mut self) {
+        let mut batch = Batch::new();
+
+        // Clearing block rules
+        batch.add(&self.rules.block_in, nftnl::MsgType::Del);
+        batch.add(&self.rules.block_out, nftnl::MsgType::Del);
+
+        unkill_flows(&self.kill_rules, &mut batch);
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+
+        self.blocked = false;
+    }
+}
+
+impl NfAction for NfDataLimit<'_> {
+    fn add(&mut self) {
+        let mut batch = Batch::new();
+
+        batch.add(&self.quota, nftnl::MsgType::Add);
+
+        if let Some((hosts, dests)) = self.talkers.as_ref() {
+            batch.add(hosts, nftnl::MsgType::Add);
+            batch.add(dests, nftnl::MsgType::Add);
+        }
+
+        // Tier rules go first, so that throttled packets are not accounted further
+        for tier in self.tiers.iter() {
+            batch.add(&tier.quota, nftnl::MsgType::Add);
+            tier.rules.apply(&mut batch, nftnl::MsgType::Add, false);
+        }
+
+        self.rules.apply(&mut batch, nftnl::MsgType::Add, false);
+
+        // Counters do not stop packets, thus it does not matter that block rules come after them
+        if let Some(ruleset) = self.count_rules.as_ref() {
+            ruleset.apply(&mut batch, nftnl::MsgType::Add);
+        }
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+    }
+
+    fn delete(&mut self) {
+        let mut batch = Batch::new();
+
+        for tier in self.tiers.iter() {
+            tier.rules.apply(&mut batch, nftnl::MsgType::Del, tier.reached);
+            batch.add(&tier.quota, nftnl::MsgType::Del);
+        }
+
+        if self.is_rolling() {
+            self.rules.apply(&mut batch, nftnl::MsgType::Del, false);
+
+            if self.blocked {
+                self.rules.apply(&mut batch, nftnl::MsgType::Del, true);
+            }
+        } else {
+            self.rules.apply(&mut batch, nftnl::MsgType::Del, self.blocked);
+        }
+
+        unkill_flows(&self.kill_rules, &mut batch);
+
+        // Sets go away with the period, thus top talkers are per period too
+        if let Some(ruleset) = self.count_rules.as_ref() {
+            ruleset.apply(&mut batch, nftnl::MsgType::Del);
+        }
+
+        if let Some((hosts, dests)) = self.talkers.as_ref() {
+            batch.add(hosts, nftnl::MsgType::Del);
+            batch.add(dests, nftnl::MsgType::Del);
+        }
+
+        batch.add(&self.quota, nftnl::MsgType::Del);
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+    }
+
+    // It is already 
+    fn block(&mut self) {
+        // Log rule might post few more messages, until it is gone
+        if self.blocked {
+            return;
+        }
+
+        let mut batch = Batch::new();
+
+        if self.is_rolling() {
+            // Consumption is still accounted by log rule, block rule goes after it
+            self.rules.apply(&mut batch, nftnl::MsgType::Add, true);
+        } else {
+            // Replacing log rule with plain block one, for it not post anything to netlink
+            self.rules.enforce(&mut batch);
+        }
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+
+        self.blocked = true;
+
+        kill_flows(&self.quota.get_name().to_string_lossy(), &self.kill_rules, &self.nets);
+    }
+
+    fn unblock(&mut self) {
+        // TODO reset quota in NF (yet to be implemented)
+        let mut batch = Batch::new();
+
+        if self.blocked {
+            if self.is_rolling() {
+                self.rules.apply(&mut batch, nftnl::MsgType::Del, true);
+            } else {
+                self.rules.release(&mut batch);
+            }
+        }
+
+        for tier in self.tiers.iter_mut() {
+            if tier.reached {
+                tier.rules.release(&mut batch);
+                tier.reached = false;
+            }
+        }
+
+        unkill_flows(&self.kill_rules, &mut batch);
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+
+        self.blocked = false;
+    }
+}
+
+impl NfAction for NfWindowLimit<'_> {
+    // Block rules come and go with the window, nothing to add upfront
+    fn add(&mut self) {}
+
+    fn delete(&mut self) {
+        if self.blocked {
+            self.unblock();
+        }
+    }
+
+    fn block(&mut self) {
+        let mut batch = Batch::new();
+
+        batch.add(&self.rules.block_in, nftnl::MsgType::Add);
+        batch.add(&self.rules.block_out, nftnl::MsgType::Add);
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+
+        self.blocked = true;
+
+        kill_flows(&self.name, &self.kill_rules, &self.nets);
+    }
+
+    fn unblock(&mut self) {
+        let mut batch = Batch::new();
+
+        batch.add(&self.rules.block_in, nftnl::MsgType::Del);
+        batch.add(&self.rules.block_out, nftnl::MsgType::Del);
+
+        unkill_flows(&self.kill_rules, &mut batch);
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+
+        self.blocked = false;
+    }
+}
+
+impl NfWindowLimit<'_> {
+    pub fn new<'a>(
+        acc_entry: &Accounting<Window>,
+        in_chain: &'a Chain,
+        out_chain: &'a Chain,
+        kill_chains: (&'a Chain, &'a Chain),
+        name: &str) -> NfWindowLimit<'a> {
+        let action = acc_entry.opts.action.unwrap_or(WINDOW_DEFAULT_ACTION);
+        let kill = kills_flows(acc_entry.opts.kill, &action);
+        let set = entry_set(name, &acc_entry.addr, in_chain.get_table());
+
+        NfWindowLimit {
+            name: name.to_owned(),
+            line: acc_entry.line,
+            window: acc_entry.quota.clone(),
+            blocked: false,
+            action,
+            source: acc_entry.addr.source.clone(),
+            dynamic: acc_entry.addr.snoop.as_deref().map(DynamicNets::new),
+            nets: acc_entry.addr.value.clone(),
+            rules: WindowRuleset::new(in_chain, out_chain, &set, &action),
+            kill_rules: if kill { Some(KillRuleset::new(kill_chains.0, kill_chains.1, &set, &[])) } else { None },
+            set,
+        }
+    }
+
+    pub fn status(&self) -> String {
+        format!("{} {:?}@{}-{}: {}",
+            self.name,
+            self.window.kind,
+            self.window.start.format("%H:%M"),
+            self.window.end.format("%H:%M"),
+            if self.blocked { "blocking" } else { "open" })
+    }
+
+    /// Replaces networks of the entry, keeping its state.
+    pub fn set_nets(&mut self, nets: &[Ipv4Network]) {
+        let (added, removed) = swap_nets(&self.set, &self.nets, nets);
+
+        self.nets = nets.to_vec();
+
+        info!("{}: {} networks added, {} removed", self.name, added.len(), removed.len());
+
+        unmatched_nets(&added, &removed);
+    }
+
+    /// Installs or removes block rules, if window edge was crossed.
+    pub fn update(&mut self, now: &DateTime<Local>) {
+        let blocking = self.window.is_blocking(now);
+
+        if blocking && !self.blocked {
+            info!("{}: access window closed, blocking", self.name);
+            self.block();
+        } else if !blocking && self.blocked {
+            info!("{}: access window opened, unblocking", self.name);
+            self.unblock();
+        }
+    }
+}
+
+// Flow is allowed, as allow rules tell for packets: by address or port at either end
+fn is_allowed_flow(allow: &[Exempt], event: &CtEvent) -> bool {
+    let ends = [(event.src, event.sport), (event.dst, event.dport)];
+
+    ends.iter().any(|(addr, port)| allow.iter().any(|allow| match allow {
+        Exempt::Net(net) => net.contains(*addr),
+        Exempt::Port(proto, allow_port) => *proto == event.proto && allow_port == port,
+    }))
+}
+
+// Flow is exempt, as exempt rules tell for packets: by the end of the flow, which
+// belongs to the entry of given networks
+fn is_exempt_flow(nets: &[Ipv4Network], exempt: &[Exempt], event: &CtEvent) -> bool {
+    let ends = [(event.src, event.sport), (event.dst, event.dport)];
+
+    ends.iter()
+        .filter(|(addr, _)| nets.iter().any(|net| net.contains(*addr)))
+        .any(|(addr, port)| exempt.iter().any(|exempt| match exempt {
+            Exempt::Net(net) => net.contains(*addr),
+            Exempt::Port(proto, exempt_port) => *proto == event.proto && exempt_port == port,
+        }))
+}
+
+impl NfTimeLimit<'_> {
+    pub fn new<'a>(
+        acc_entry: &Accounting<Duration>,
+        in_chain: &'a Chain,
+        out_chain: &'a Chain,
+        kill_chains: (&'a Chain, &'a Chain),
+        name: &str) -> NfTimeLimit<'a> {
+        let dur = acc_entry.quota.clone();
+        let matcher = entry_match(QuotaKind::Time, acc_entry.line, &acc_entry.addr);
+        let claim = NfHandle::get().claims.get(&(QuotaKind::Time, acc_entry.line)).copied();
+        let (track, action) = (acc_entry.opts.track, acc_entry.opts.action.unwrap_or(TIME_DEFAULT_ACTION));
+        let set = entry_set(name, &acc_entry.addr, in_chain.get_table());
+        let mut limit = NfTimeLimit {
+            name: name.to_owned(),
+            line: acc_entry.line,
+            quota: dur,
+            period: acc_entry.period,
+            period_id: acc_entry.period.id(&Local::now()),
+            blocked: false,
+            timer: ConnTimer::new(&dur),
+            track,
+            idle: acc_entry.opts.idle,
+            action,
+            sessions: 0,
+            source: acc_entry.addr.source.clone(),
+            dynamic: acc_entry.addr.snoop.as_deref().map(DynamicNets::new),
+            exempt: acc_entry.opts.exempt.clone(),
+            nets: acc_entry.addr.value.clone(),
+            rules: TimeLimitRuleset::new(out_chain, in_chain, &set, name, track, &action, matcher.as_ref()),
+            kill_rules: None,
+            claim_rules: claim.map(|claim| ClaimRuleset::new(QuotaKind::Time, &set, claim)),
+            set,
+        };
+
+        if kills_flows(acc_entry.opts.kill, &action) {
+            limit.kill_rules = Some(KillRuleset::new(kill_chains.0, kill_chains.1, &limit.set, &acc_entry.opts.exempt));
         }
 
-        process_netlink(&(batch.finalize()), false).unwrap();
+        limit
     }
 
-    fn delete(&self) {
-        let mut batch = Batch::new();
+    /// Checks if either end of the flow belongs to this entry.
+    pub fn matches(&self, src: &Ipv4Addr, dst: &Ipv4Addr) -> bool {
+        self.nets.iter().any(|ip| ip.contains(*src) || ip.contains(*dst))
+    }
+
+    /// Checks if conntrack flow is exempt from the entry.
+    pub fn is_exempt(&self, event: &CtEvent) -> bool {
+        is_exempt_flow(&self.nets, &self.exempt, event)
+    }
+
+    pub fn activity(&self) {
+        self.timer.touch();
+    }
+
+    /// Resets used time and unblocks, if new period has begun.
+    pub fn roll(&mut self, now: &DateTime<Local>) {
+        let period_id = self.period.id(now);
 
-        for (_, ruleset) in self.rules.iter() {
-            batch.add(&ruleset.block, nftnl::MsgType::Del);
-            batch.add(&ruleset.log, nftnl::MsgType::Del);
+        if period_id == self.period_id {
+            return;
         }
 
-        process_netlink(&(batch.finalize()), false).unwrap();
+        info!("{}: new {} period, resetting time quota", self.name, self.period);
+
+        self.period_id = period_id;
+        self.timer.reset();
+
+        if self.blocked {
+            self.unblock();
+        }
     }
 
-    // It is already 
-    fn block(&self) {
-        let mut batch = Batch::new();
+    pub fn status(&self) -> String {
+        format!("{} {}s/{}: {}s used{}",
+            self.name,
+            self.quota.as_secs(),
+            self.period,
+            self.timer.elapsed().as_secs(),
+            if self.blocked { ", exceeded" } else { "" })
+    }
+
+    /// Replaces networks of the entry, keeping used time and state.
+    pub fn set_nets(&mut self, nets: &[Ipv4Network]) {
+        let (added, removed) = swap_nets(&self.set, &self.nets, nets);
+
+        self.nets = nets.to_vec();
+
+        info!("{}: {} networks added, {} removed", self.name, added.len(), removed.len());
+
+        unmatched_nets(&added, &removed);
+    }
+
+    pub fn session_start(&mut self) {
+        self.sessions += 1;
+
+        if self.sessions == 1 {
+            debug!("{}: first session started, resuming timer", self.name);
+            self.timer.start();
+        }
+    }
 
-        // Just clearing the log rule, for it not post anything to netlink
-        for (_, ruleset) in self.rules.iter() {
-            batch.add(&ruleset.log, nftnl::MsgType::Del);
+    pub fn session_end(&mut self) {
+        // Sessions opened before daemon start are not known, thus saturating
+        self.sessions = self.sessions.saturating_sub(1);
+
+        if self.sessions == 0 && self.timer.is_active() {
+            debug!("{}: last session ended, pausing timer", self.name);
+            self.timer.stop();
         }
+    }
+}
 
-        process_netlink(&(batch.finalize()), false).unwrap();
+// Puts consumption sample of sliding window into buckets, forgets the ones aged out of
+// the window and returns usage within it
+fn roll_window(samples: &mut VecDeque<(Instant, u64)>, window: Duration, now: Instant, consumed: u64) -> u64 {
+    let bucket = window / ROLLING_BUCKETS;
+
+    if samples.back().map_or(true, |(start, _)| now.duration_since(*start) >= bucket) {
+        samples.push_back((now, consumed));
     }
 
-    fn unblock(&self) {
-        // TODO reset quota in NF (yet to be implemented)
+    // Oldest remaining bucket is the baseline, as long as next one is still in the window
+    while samples.len() > 1 && now.duration_since(samples[1].0) >= window {
+        samples.pop_front();
     }
+
+    consumed.saturating_sub(samples.front().map_or(consumed, |(_, c)| *c))
 }
 
-impl NfTimeLimit<'_> {
-    pub fn new<'a>(
-        acc_entry: &Accounting<Duration>,
-        in_chain: &'a Chain,
-        out_chain: &'a Chain,
-        name: &'a str) -> NfTimeLimit<'a> {
-        let dur = acc_entry.quota.clone();
-        let mut limit = NfTimeLimit {
-            timer: ConnTimer::new(&dur),
-            rules: HashMap::new(),
+// Unused allowance of ending period (limit raised by carry of its own), capped at `max` periods
+fn carry_amount(limit: u64, carry: u64, consumed: u64, max: u32) -> u64 {
+    (limit + carry).saturating_sub(consumed).min(limit.saturating_mul(max as u64))
+}
+
+impl NfDataLimit<'_> {
+    /// Zeroes consumed quota and unblocks, if new period has begun.
+    pub fn roll(&mut self, now: &DateTime<Local>) {
+        let period_id = self.period.id(now);
+
+        if period_id == self.period_id {
+            return;
+        }
+
+        info!("{}: new {} period, resetting data quota", self.quota.get_name().to_string_lossy(), self.period);
+
+        self.period_id = period_id;
+
+        if let Some(max) = self.rollover {
+            self.carry_over(max);
+        }
+
+        // Recreating quota objects is the way to zero their consumption
+        self.delete();
+
+        self.blocked = false;
+
+        for tier in self.tiers.iter_mut() {
+            tier.reached = false;
+        }
+
+        self.add();
+    }
+
+    /// Computes unused remainder of ending period and raises quota limit by it.
+    fn carry_over(&mut self, max: u32) {
+        let name = self.quota.get_name().to_string_lossy().into_owned();
+
+        self.carry = match fetch_quota_consumed(self.quota.get_name()) {
+            Ok(consumed) => carry_amount(self.limit, self.carry, consumed, max),
+            Err(e) => {
+                warn!("{}: failed to read consumed quota, nothing carried over: {:?}", name, e);
+                0
+            },
         };
 
-        for ip in acc_entry.addr.value.iter() {
-            let ruleset = TimeLimitRuleset::new(out_chain, in_chain, ip, name);
+        info!("{}: carrying over {}b, limit is {}b", name, self.carry, self.limit + self.carry);
+
+        self.quota.set_limit(self.limit + self.carry);
+    }
+
+    pub fn status(&self) -> String {
+        let reached = self.tiers.iter().filter(|tier| tier.reached).count();
+
+        format!("{} {}b/{}: {}{}{}{}{}",
+            self.quota.get_name().to_string_lossy(),
+            self.limit,
+            self.period,
+            if self.blocked { "exceeded" } else { "ok" },
+            if reached > 0 { format!(", tier {}", reached) } else { String::new() },
+            if self.is_rolling() { format!(", {}b in window", self.window_usage) } else { String::new() },
+            if self.rollover.is_some() {
+                format!(", {}b carried, {}b effective", self.carry, self.limit + self.carry)
+            } else {
+                String::new()
+            },
+            match self.talkers(NfHandle::get().talkers) {
+                Some((hosts, dests)) => format!("\n    top hosts: {}\n    top destinations: {}",
+                    format_talkers(&hosts), format_talkers(&dests)),
+                None => String::new(),
+            })
+    }
+
+    fn is_rolling(&self) -> bool {
+        matches!(self.period, Period::Rolling(_))
+    }
+
+    /// Reads per address counters, returns top hosts and destinations by bytes.
+    pub fn talkers(&self, top: usize) -> Option<(Vec<(Ipv4Addr, u64)>, Vec<(Ipv4Addr, u64)>)> {
+        let (hosts, dests) = self.talkers.as_ref()?;
+        let mut res = Vec::new();
+
+        for set in [hosts, dests].iter() {
+            let mut counters = match fetch_set_counters(set.get_name()) {
+                Ok(counters) => counters,
+                Err(e) => {
+                    warn!("{}: failed to read counters: {:?}", set.get_name().to_string_lossy(), e);
+                    return None;
+                },
+            };
 
-            limit.rules.insert(ip.clone(), ruleset);
+            counters.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+            counters.truncate(top);
+
+            res.push(counters);
         }
 
-        limit
+        let dests = res.pop()?;
+        let hosts = res.pop()?;
+
+        Some((hosts, dests))
+    }
+
+    /// Samples kernel quota consumption into buckets of sliding window and
+    /// blocks or unblocks, as old buckets age out of it.
+    pub fn sample(&mut self) {
+        let window = match self.period {
+            Period::Rolling(window) => window,
+            _ => return,
+        };
+
+        let consumed = match fetch_quota_consumed(self.quota.get_name()) {
+            Ok(consumed) => consumed,
+            Err(e) => {
+                warn!("{}: failed to sample quota: {:?}", self.quota.get_name().to_string_lossy(), e);
+                return;
+            },
+        };
+
+        self.window_usage = roll_window(&mut self.samples, window, Instant::now(), consumed);
+
+        if self.window_usage > self.limit && !self.blocked {
+            info!("Data quota {} exceeded in sliding window, blocking", self.quota.get_name().to_string_lossy());
+            self.block();
+        } else if self.window_usage <= self.limit && self.blocked {
+            info!("Data quota {} freed in sliding window, unblocking", self.quota.get_name().to_string_lossy());
+            self.unblock();
+        }
+    }
+
+    /// Replaces networks of the entry, keeping consumed quota and state.
+    pub fn set_nets(&mut self, nets: &[Ipv4Network]) {
+        let name = self.quota.get_name().to_string_lossy().into_owned();
+        let (added, removed) = swap_nets(&self.set, &self.nets, nets);
+
+        self.nets = nets.to_vec();
+
+        info!("{}: {} networks added, {} removed", name, added.len(), removed.len());
+
+        unmatched_nets(&added, &removed);
+    }
+
+    /// Moves entry into given (1-based) tier, returns false if it is there already.
+    pub fn reach_tier(&mut self, num: usize) -> bool {
+        match self.tiers.get(num.wrapping_sub(1)) {
+            Some(tier) if !tier.reached => (),
+            _ => return false,
+        };
+
+        let mut batch = Batch::new();
+
+        for (rule, msg) in tier_ops(&self.tiers, num - 1, self.main_rules(), self.count_rules.as_ref()) {
+            batch.add(rule, msg);
+        }
+
+        process_netlink(&(batch.finalize()), false).unwrap();
+
+        self.tiers[num - 1].reached = true;
+
+        true
+    }
+
+    // Rules of the main quota in place: log rule, followed by block rule of rolling entry
+    fn main_rules(&self) -> Vec<&Rule> {
+        if self.is_rolling() {
+            self.rules.log.iter().chain(self.rules.block.iter().filter(|_| self.blocked)).collect()
+        } else if self.blocked {
+            self.rules.block.iter().collect()
+        } else {
+            self.rules.log.iter().collect()
+        }
     }
-}
 
-impl NfDataLimit<'_> {
     pub fn new<'a>(
         acc_entry: &Accounting<Byte>,
         in_chain: &'a Chain,
-        name: &'a str) -> NfDataLimit<'a> {
+        kill_chains: (&'a Chain, &'a Chain),
+        name: &str) -> NfDataLimit<'a> {
         let mut quota = Quota::new(&CString::new(name).unwrap(), in_chain.get_table());
         quota.set_type(QuotaType::Over);
-        quota.set_limit(acc_entry.quota.to_quota() as u64);
+
+        match acc_entry.period {
+            // Kernel quota only counts, window is enforced by sampling it
+            Period::Rolling(_) => quota.set_limit(u64::MAX),
+            _ => quota.set_limit(acc_entry.quota.to_quota() as u64),
+        }
+
+        let action = acc_entry.opts.action.unwrap_or(DATA_DEFAULT_ACTION);
+        let matcher = entry_match(QuotaKind::Data, acc_entry.line, &acc_entry.addr);
+        let claim = NfHandle::get().claims.get(&(QuotaKind::Data, acc_entry.line)).copied();
+        let set = entry_set(name, &acc_entry.addr, in_chain.get_table());
+        let ends = set_ends(acc_entry.line >= CLIENT_LINE_BASE);
 
         let mut limit = NfDataLimit {
+            line: acc_entry.line,
+            limit: acc_entry.quota.to_quota(),
+            period: acc_entry.period,
+            period_id: acc_entry.period.id(&Local::now()),
+            blocked: false,
+            samples: VecDeque::new(),
+            window_usage: 0,
+            rollover: acc_entry.opts.rollover,
+            carry: 0,
+            action,
+            source: acc_entry.addr.source.clone(),
+            dynamic: acc_entry.addr.snoop.as_deref().map(DynamicNets::new),
+            nets: acc_entry.addr.value.clone(),
+            rules: DataLimitRuleset::new(in_chain, &set, ends, &quota, &action, matcher.as_ref()),
+            tiers: Vec::new(),
+            kill_rules: None,
+            claim_rules: claim.map(|claim| ClaimRuleset::new(QuotaKind::Data, &set, claim)),
+            talkers: None,
+            count_rules: None,
             quota,
-            rules: HashMap::new(),
+            set,
         };
 
-        for ip in acc_entry.addr.value.iter() {
-            let ruleset = DataLimitRuleset::new(in_chain, ip, &limit.quota);
+        if NfHandle::get().talkers > 0 {
+            limit.talkers = Some((
+                HostSet::new(&format!("{}{}", name, DATA_HOSTS_SUFFIX), in_chain.get_table()),
+                HostSet::new(&format!("{}{}", name, DATA_DESTS_SUFFIX), in_chain.get_table()),
+            ));
+        }
+
+        for (pos, (threshold, tier_action)) in acc_entry.opts.tiers.iter().enumerate() {
+            let tier_name = format!("{}{}{}", name, DATA_TIER_SUFFIX, pos + 1);
+
+            let mut tier_quota = Quota::new(&CString::new(tier_name).unwrap(), in_chain.get_table());
+            tier_quota.set_type(QuotaType::Over);
+            tier_quota.set_limit(threshold.to_quota() as u64);
 
-            limit.rules.insert(*ip, ruleset);
+            limit.tiers.push(NfDataTier {
+                rules: DataLimitRuleset::new(in_chain, &limit.set, ends, &tier_quota, tier_action, matcher.as_ref()),
+                quota: tier_quota,
+                action: *tier_action,
+                reached: false,
+            });
+        }
+
+        if kills_flows(acc_entry.opts.kill, &action) {
+            limit.kill_rules = Some(KillRuleset::new(kill_chains.0, kill_chains.1, &limit.set, &acc_entry.opts.exempt));
+        }
+
+        if let Some(sets) = limit.talkers.as_ref() {
+            limit.count_rules = Some(CountRuleset::new(in_chain, &limit.set, ends, sets, matcher.as_ref()));
         }
 
         limit
@@ -420,6 +2473,14 @@ impl NfDataLimit<'_> {
 
 static mut HANDLE_INSTANCE: OnceCell<NfHandle> = OnceCell::new();
 
+// Handle is worked on by nflog loop, conntrack, timer, schedule and signal threads,
+// each of them holds this lock for as long as it does
+static HANDLE_LOCK: Mutex<()> = Mutex::new(());
+
+fn lock_handle() -> MutexGuard<'static, ()> {
+    HANDLE_LOCK.lock().unwrap()
+}
+
 #[derive(Debug)]
 pub enum NfError {
     // File not found or whateva ...
@@ -443,7 +2504,30 @@ impl From<nflog::NflogError> for NfError {
 }
 
 fn data_quota_cb(msg: nflog::Message) {
-    debug!("data_quota_cb -> prefix: {}", msg.get_prefix().to_string_lossy());
+    let _handle = lock_handle();
+
+    let prefix = msg.get_prefix().to_string_lossy().into_owned();
+
+    debug!("data_quota_cb -> prefix: {}", prefix);
+
+    // Tier quotas are named "<entry><suffix><tier number>"
+    if let Some((name, num)) = prefix.rsplit_once(DATA_TIER_SUFFIX) {
+        if let (Some(limit), Ok(num)) = (NfHandle::get().data_entries.get_mut(name), num.parse::<usize>()) {
+            if limit.reach_tier(num) {
+                info!("Data quota {} reached tier {}, action: {:?}", name, num, limit.tiers[num - 1].action);
+            }
+        }
+
+        return;
+    }
+
+    if let Some(limit) = NfHandle::get().data_entries.get_mut(&prefix) {
+        if !limit.blocked {
+            info!("Data quota {} exceeded, blocking", prefix);
+        }
+
+        limit.block();
+    }
 
     // println!("Packet received\n");
     // println!(
@@ -467,11 +2551,265 @@ fn data_quota_cb(msg: nflog::Message) {
 
 // This one will call the the "subcallbacks" for time count
 fn time_quota_cb(msg: nflog::Message) {
-    debug!("time_quota_cb -> prefix: {}", msg.get_prefix().to_string_lossy());
+    let _handle = lock_handle();
+
+    let prefix = msg.get_prefix().to_string_lossy().into_owned();
+
+    debug!("time_quota_cb -> prefix: {}", prefix);
+
+    if let Some(name) = prefix.strip_prefix(TIME_START_LOG_PREFIX) {
+        if let Some(limit) = NfHandle::get().time_entries.get_mut(name) {
+            limit.session_start();
+        }
+    } else if let Some(name) = prefix.strip_prefix(TIME_FIN_LOG_PREFIX) {
+        if let Some(limit) = NfHandle::get().time_entries.get_mut(name) {
+            limit.session_end();
+        }
+    }
+}
+
+fn time_activity_cb(msg: nflog::Message) {
+    let _handle = lock_handle();
+
+    let prefix = msg.get_prefix().to_string_lossy();
+
+    trace!("time_activity_cb -> prefix: {}", prefix);
+
+    if let Some(name) = prefix.strip_prefix(TIME_ACTIVITY_LOG_PREFIX) {
+        if let Some(limit) = NfHandle::get().time_entries.get(name) {
+            limit.activity();
+        }
+    }
+}
+
+fn dns_snoop_cb(msg: nflog::Message) {
+    let _handle = lock_handle();
+
+    let answer = match dns::parse_response(msg.get_payload()) {
+        Some(answer) => answer,
+        None => return,
+    };
+
+    trace!("dns_snoop_cb -> {:?}", answer);
+
+    if let Some(unmatched) = NfHandle::get().unmatched.as_mut() {
+        unmatched.learn(&answer);
+    }
+
+    for (_, limit) in NfHandle::get().data_entries.iter_mut() {
+        if let Some(dynamic) = limit.dynamic.as_mut() {
+            learn_nets(&limit.name, &limit.set, dynamic, &mut limit.nets, &answer);
+        }
+    }
+
+    for (_, limit) in NfHandle::get().time_entries.iter_mut() {
+        if let Some(dynamic) = limit.dynamic.as_mut() {
+            learn_nets(&limit.name, &limit.set, dynamic, &mut limit.nets, &answer);
+        }
+    }
+
+    for (_, limit) in NfHandle::get().window_entries.iter_mut() {
+        if let Some(dynamic) = limit.dynamic.as_mut() {
+            learn_nets(&limit.name, &limit.set, dynamic, &mut limit.nets, &answer);
+        }
+    }
+}
+
+fn sni_cb(msg: nflog::Message) {
+    let _handle = lock_handle();
+
+    let (flow, payload) = match sni::parse_packet(msg.get_payload()) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+
+    // Handshake packets carry nothing, wait for the first payload
+    if payload.is_empty() {
+        return;
+    }
+
+    let name = sni::classify(payload);
+
+    trace!("sni_cb -> {:?} {:?}", flow, name);
+
+    let id = name.as_ref()
+        .and_then(|name| NfHandle::get().sni_entries.iter().find(|entry| dns::matches(&entry.1, name)))
+        .map_or(0, |entry| entry.2);
+
+    // Flow is marked as seen even if it is not ours, thus the rest of it stays in kernel
+    let socket = NfHandle::get().sni_socket.as_ref().unwrap();
+
+    if let Err(e) = conntrack::set_mark(socket, libc::IPPROTO_TCP as u8, flow.src, flow.dst, flow.sport, flow.dport, SNI_SEEN | id, SNI_SEEN | SNI_MARK_MASK) {
+        warn!("failed to mark flow {}:{} -> {}:{}: {}", flow.src, flow.sport, flow.dst, flow.dport, e);
+    }
+}
+
+fn conntrack_cb(event: CtEvent) {
+    let _handle = lock_handle();
+
+    if let (CtEventType::New, Some(clients)) = (event.kind, NfHandle::get().clients.as_ref()) {
+        clients.seen(&event.src);
+    }
+
+    for (_, limit) in NfHandle::get().time_entries.iter_mut() {
+        // Activity mode entries do not care about sessions
+        if limit.idle.is_some() || limit.track != Tracking::Conntrack {
+            continue;
+        }
+
+        if !limit.matches(&event.src, &event.dst) || limit.is_exempt(&event) {
+            continue;
+        }
+
+        match event.kind {
+            CtEventType::New => limit.session_start(),
+            CtEventType::Destroy => limit.session_end(),
+        }
+    }
+}
+
+fn schedule_cb(now: &DateTime<Local>) {
+    let _handle = lock_handle();
+
+    for (_, limit) in NfHandle::get().window_entries.iter_mut() {
+        limit.update(now);
+    }
+
+    for (_, limit) in NfHandle::get().data_entries.iter_mut() {
+        limit.roll(now);
+        limit.sample();
+    }
+
+    for (_, limit) in NfHandle::get().time_entries.iter_mut() {
+        limit.roll(now);
+    }
+
+    update_clients();
+
+    let top = NfHandle::get().talkers;
+
+    if let Some(unmatched) = NfHandle::get().unmatched.as_mut() {
+        unmatched.sample(top);
+    }
+
+    write_metrics();
+}
+
+// Hooks chain of given direction in table family. Bridge sees traffic between its ports both
+// ways in forward hook, netdev sees traffic arriving on the device alone, in ingress hook
+fn set_base_chain(chain: &mut Chain, family: &Family, hook: nftnl::Hook, priority: i32) {
+    match family {
+        Family::Ipv4 => chain.set_hook(hook, priority),
+        Family::Bridge => chain.set_hook(nftnl::Hook::Forward, priority),
+        Family::Netdev(dev) => {
+            // NF_NETDEV_INGRESS shares its number with NF_INET_PRE_ROUTING
+            chain.set_hook(nftnl::Hook::PreRouting, priority);
+
+            let dev = CString::new(dev.as_str()).unwrap();
+
+            unsafe { sys::nftnl_chain_set_str(chain.as_mut_ptr(), sys::NFTNL_CHAIN_DEV as u16, dev.as_ptr()) };
+        },
+    }
+
+    chain.set_policy(nftnl::Policy::Accept);
+    chain.set_type(ChainType::Filter);
+}
+
+// Accepts frames other than IPv4 at once, as address matches would read their headers as IPv4 ones
+fn non_ipv4_rule(chain: &Chain) -> Rule {
+    let mut rule = Rule::new(&chain);
+
+    rule.add_expr(&nftnl::expr::Payload::LinkLayer(nftnl::expr::LLHeaderField::EtherType));
+    rule.add_expr(&nft_expr!(cmp != (libc::ETH_P_IP as u16).to_be()));
+    rule.add_expr(&nft_expr!(verdict accept));
+
+    rule
+}
+
+fn kill_chains() -> (&'static Chain<'static>, &'static Chain<'static>) {
+    (
+        NfHandle::get().chains.get(KILL_IN_CHAIN_NAME).unwrap(),
+        NfHandle::get().chains.get(KILL_OUT_CHAIN_NAME).unwrap(),
+    )
+}
+
+fn claim_chains() -> (&'static Chain<'static>, &'static Chain<'static>) {
+    (
+        NfHandle::get().chains.get(CLAIM_IN_CHAIN_NAME).unwrap(),
+        NfHandle::get().chains.get(CLAIM_OUT_CHAIN_NAME).unwrap(),
+    )
+}
+
+// Refuses entries, which the hooks of table family can't serve
+fn check_family(config: &Config) -> Result<(), NfError> {
+    let template = config.clients.as_ref().and_then(|clients| clients.entry(Ipv4Addr::UNSPECIFIED).ok()).unwrap_or_default();
+    let line = |line: u32| format!("line {}", line + 1);
+
+    for entry in config.data.iter() {
+        check_entry_family(&config.family, &line(entry.line), &entry.addr, &entry.opts, DATA_DEFAULT_ACTION)?;
+    }
+
+    for entry in template.iter() {
+        check_entry_family(&config.family, "client template", &entry.addr, &entry.opts, DATA_DEFAULT_ACTION)?;
+    }
+
+    for entry in config.time.iter() {
+        check_entry_family(&config.family, &line(entry.line), &entry.addr, &entry.opts, TIME_DEFAULT_ACTION)?;
+    }
+
+    for entry in config.windows.iter() {
+        check_entry_family(&config.family, &line(entry.line), &entry.addr, &entry.opts, WINDOW_DEFAULT_ACTION)?;
+    }
+
+    Ok(())
+}
+
+// Fails for entry, which rules of the family can't put in place. `place` tells where it comes from
+fn check_entry_family(family: &Family, place: &str, addr: &Address, opts: &accnt::Options, action: Action) -> Result<(), NfError> {
+    // Socket owner is known on local output only
+    if *family != Family::Ipv4 && addr.selector.as_ref().map_or(false, Selector::is_owner) {
+        return Err(NfError::NfTablesError(format!("{}: user and cgroup entries need ipv4 family", place)));
+    }
+
+    if let Family::Netdev(_) = family {
+        // Packets have no conntrack entry yet on ingress, there is no mark to tag flows with
+        if addr.sni.is_some() {
+            return Err(NfError::NfTablesError(format!("{}: sni entries do not work in netdev family", place)));
+        }
+
+        // Nor is there reject expression, flows can't be killed and packets are dropped instead
+        if opts.kill {
+            return Err(NfError::NfTablesError(format!("{}: kill=on does not work in netdev family", place)));
+        }
+
+        let mut actions = vec![opts.action.unwrap_or(action)];
+
+        actions.extend(opts.tiers.iter().map(|(_, action)| *action));
+
+        if actions.iter().any(|action| matches!(action, Action::Reject(_))) {
+            return Err(NfError::NfTablesError(format!("{}: reject does not work in netdev family, set action=drop", place)));
+        }
+    }
+
+    Ok(())
 }
 
-pub fn init<'a>(config: &Config) -> Result<(), NfError> {
-    let mut handle = NfHandle::new(TABLE_NAME);
+pub fn init<'a>(config: &Config, metrics: Option<&str>) -> Result<(), NfError> {
+    check_family(config)?;
+
+    // Claim ids take one byte of packet mark
+    for quota in [QuotaKind::Data, QuotaKind::Time].iter() {
+        if config.claims(*quota).len() > CLAIM_MAX {
+            return Err(NfError::NfTablesError(format!("more than {} overlapping {:?} entries", CLAIM_MAX, quota)));
+        }
+    }
+
+    // Threads spawned below wait for the rest of init
+    let _handle = lock_handle();
+
+    let mut handle = NfHandle::new(TABLE_NAME, &config.family);
+    handle.talkers = config.talkers;
+    handle.metrics = metrics.map(|path| path.to_owned());
     unsafe { HANDLE_INSTANCE.set(handle).unwrap(); }
 
     let mut init_batch = Batch::new();
@@ -485,66 +2823,379 @@ pub fn init<'a>(config: &Config) -> Result<(), NfError> {
             Chain::new(&CString::new(TIME_IN_CHAIN_NAME).unwrap(), &NfHandle::get().table),
             Chain::new(&CString::new(TIME_OUT_CHAIN_NAME).unwrap(), &NfHandle::get().table)
         );
-    
-    dataqt_in_chain.set_hook(nftnl::Hook::In, 0);
-    dataqt_in_chain.set_policy(nftnl::Policy::Accept);
-    dataqt_in_chain.set_type(ChainType::Filter);
-    
-    dataqt_out_chain.set_hook(nftnl::Hook::Out, 0);
-    dataqt_out_chain.set_policy(nftnl::Policy::Accept);
-    dataqt_out_chain.set_type(ChainType::Filter);
-    
-    timeqt_in_chain.set_hook(nftnl::Hook::In, 0);
-    timeqt_in_chain.set_policy(nftnl::Policy::Accept);
-    timeqt_in_chain.set_type(ChainType::Filter);
-    
-    timeqt_out_chain.set_hook(nftnl::Hook::Out, 0);
-    timeqt_out_chain.set_policy(nftnl::Policy::Accept);
-    timeqt_out_chain.set_type(ChainType::Filter);
+
+    let (mut window_in_chain, mut window_out_chain) =
+        (
+            Chain::new(&CString::new(WINDOW_IN_CHAIN_NAME).unwrap(), &NfHandle::get().table),
+            Chain::new(&CString::new(WINDOW_OUT_CHAIN_NAME).unwrap(), &NfHandle::get().table)
+        );
+
+    let (mut kill_in_chain, mut kill_out_chain) =
+        (
+            Chain::new(&CString::new(KILL_IN_CHAIN_NAME).unwrap(), &NfHandle::get().table),
+            Chain::new(&CString::new(KILL_OUT_CHAIN_NAME).unwrap(), &NfHandle::get().table)
+        );
+
+    let (mut dataqt_fwd_chain, dataqt_sel_chain) =
+        (
+            Chain::new(&CString::new(DATA_FWD_CHAIN_NAME).unwrap(), &NfHandle::get().table),
+            Chain::new(&CString::new(DATA_SEL_CHAIN_NAME).unwrap(), &NfHandle::get().table)
+        );
+
+    let (mut unmatched_in_chain, mut unmatched_out_chain) =
+        (
+            Chain::new(&CString::new(UNMATCHED_IN_CHAIN_NAME).unwrap(), &NfHandle::get().table),
+            Chain::new(&CString::new(UNMATCHED_OUT_CHAIN_NAME).unwrap(), &NfHandle::get().table)
+        );
+
+    let (mut claim_in_chain, mut claim_out_chain) =
+        (
+            Chain::new(&CString::new(CLAIM_IN_CHAIN_NAME).unwrap(), &NfHandle::get().table),
+            Chain::new(&CString::new(CLAIM_OUT_CHAIN_NAME).unwrap(), &NfHandle::get().table)
+        );
+
+    set_base_chain(&mut dataqt_in_chain, &config.family, nftnl::Hook::In, 0);
+    set_base_chain(&mut dataqt_out_chain, &config.family, nftnl::Hook::Out, 0);
+    set_base_chain(&mut dataqt_fwd_chain, &config.family, nftnl::Hook::Forward, 0);
+    set_base_chain(&mut timeqt_in_chain, &config.family, nftnl::Hook::In, 0);
+    set_base_chain(&mut timeqt_out_chain, &config.family, nftnl::Hook::Out, 0);
+    set_base_chain(&mut window_in_chain, &config.family, nftnl::Hook::In, 0);
+    set_base_chain(&mut window_out_chain, &config.family, nftnl::Hook::Out, 0);
+    set_base_chain(&mut kill_in_chain, &config.family, nftnl::Hook::In, KILL_CHAIN_PRIORITY);
+    set_base_chain(&mut kill_out_chain, &config.family, nftnl::Hook::Out, KILL_CHAIN_PRIORITY);
+    set_base_chain(&mut unmatched_in_chain, &config.family, nftnl::Hook::In, UNMATCHED_CHAIN_PRIORITY);
+    set_base_chain(&mut unmatched_out_chain, &config.family, nftnl::Hook::Out, UNMATCHED_CHAIN_PRIORITY);
+    set_base_chain(&mut claim_in_chain, &config.family, nftnl::Hook::In, CLAIM_CHAIN_PRIORITY);
+    set_base_chain(&mut claim_out_chain, &config.family, nftnl::Hook::Out, CLAIM_CHAIN_PRIORITY);
 
     init_batch.add(&dataqt_in_chain, nftnl::MsgType::Add);
     init_batch.add(&dataqt_out_chain, nftnl::MsgType::Add);
+    init_batch.add(&dataqt_fwd_chain, nftnl::MsgType::Add);
+    init_batch.add(&dataqt_sel_chain, nftnl::MsgType::Add);
     init_batch.add(&timeqt_in_chain, nftnl::MsgType::Add);
     init_batch.add(&timeqt_out_chain, nftnl::MsgType::Add);
+    init_batch.add(&window_in_chain, nftnl::MsgType::Add);
+    init_batch.add(&window_out_chain, nftnl::MsgType::Add);
+    init_batch.add(&kill_in_chain, nftnl::MsgType::Add);
+    init_batch.add(&kill_out_chain, nftnl::MsgType::Add);
+    init_batch.add(&unmatched_in_chain, nftnl::MsgType::Add);
+    init_batch.add(&unmatched_out_chain, nftnl::MsgType::Add);
+    init_batch.add(&claim_in_chain, nftnl::MsgType::Add);
+    init_batch.add(&claim_out_chain, nftnl::MsgType::Add);
 
     NfHandle::get().chains.insert(DATA_IN_CHAIN_NAME, dataqt_in_chain);
     NfHandle::get().chains.insert(DATA_OUT_CHAIN_NAME, dataqt_out_chain);
+    NfHandle::get().chains.insert(DATA_FWD_CHAIN_NAME, dataqt_fwd_chain);
+    NfHandle::get().chains.insert(DATA_SEL_CHAIN_NAME, dataqt_sel_chain);
     NfHandle::get().chains.insert(TIME_IN_CHAIN_NAME, timeqt_in_chain);
     NfHandle::get().chains.insert(TIME_OUT_CHAIN_NAME, timeqt_out_chain);
+    NfHandle::get().chains.insert(WINDOW_IN_CHAIN_NAME, window_in_chain);
+    NfHandle::get().chains.insert(WINDOW_OUT_CHAIN_NAME, window_out_chain);
+    NfHandle::get().chains.insert(KILL_IN_CHAIN_NAME, kill_in_chain);
+    NfHandle::get().chains.insert(KILL_OUT_CHAIN_NAME, kill_out_chain);
+    NfHandle::get().chains.insert(UNMATCHED_IN_CHAIN_NAME, unmatched_in_chain);
+    NfHandle::get().chains.insert(UNMATCHED_OUT_CHAIN_NAME, unmatched_out_chain);
+    NfHandle::get().chains.insert(CLAIM_IN_CHAIN_NAME, claim_in_chain);
+    NfHandle::get().chains.insert(CLAIM_OUT_CHAIN_NAME, claim_out_chain);
 
     // Process messages with little portions, not to overflow nl sokcet
     process_netlink(&(init_batch.finalize()), false).unwrap();
 
-    // Process data quota entries
+    NfHandle::get().pools = config.pools.clone();
+    NfHandle::get().allow = config.allow.clone();
+    NfHandle::get().exempt = config.exempt.clone();
+
+    // Allowlist and exemptions go first, rules added later on are appended after them
+    let mut exempt_batch = Batch::new();
+    let chains = &NfHandle::get().chains;
+    let (data_in, data_fwd, time_in, time_out) = (
+        chains.get(DATA_IN_CHAIN_NAME).unwrap(),
+        chains.get(DATA_FWD_CHAIN_NAME).unwrap(),
+        chains.get(TIME_IN_CHAIN_NAME).unwrap(),
+        chains.get(TIME_OUT_CHAIN_NAME).unwrap(),
+    );
+    let (kill_in, kill_out) = kill_chains();
+
+    // Bridge and netdev hooks see any frame, not IPv4 packets only
+    if config.family != Family::Ipv4 {
+        for (name, chain) in chains.iter() {
+            if *name != DATA_SEL_CHAIN_NAME {
+                exempt_batch.add(&non_ipv4_rule(chain), nftnl::MsgType::Add);
+            }
+        }
+    }
+
+    let snoop = config.data.iter().any(|entry| entry.addr.snoop.is_some())
+        || config.time.iter().any(|entry| entry.addr.snoop.is_some())
+        || config.windows.iter().any(|entry| entry.addr.snoop.is_some());
+
+    // DNS responses are copied to userspace, before allowlist accepts them.
+    // Unmatched counters take names of remotes from them as well
+    if snoop || config.unmatched {
+        let mut rule = Rule::new(data_in);
+
+        rule.add_expr(&nft_expr!(meta l4proto));
+        rule.add_expr(&nft_expr!(cmp == libc::IPPROTO_UDP as u8));
+        rule.add_expr(&nft_expr!(payload udp sport));
+        rule.add_expr(&nft_expr!(cmp == 53u16.to_be()));
+        rule.add_expr(&nft_expr!(log .group(DNS_SNOOP_NUM)));
+
+        exempt_batch.add(&rule, nftnl::MsgType::Add);
+    }
+
+    // SNI entries are tagged with ids, which their rules match in conntrack mark
+    for (pos, data_entry) in config.data.iter().filter(|entry| entry.addr.sni.is_some()).enumerate() {
+        let pattern = data_entry.addr.sni.clone().unwrap();
+
+        NfHandle::get().sni_entries.push((data_entry.line, pattern, pos as u32 + 1));
+    }
+
+    let sni = !NfHandle::get().sni_entries.is_empty();
+
+    // First payload packets of web flows, own and forwarded ones, are copied to userspace, before
+    // allowlist accepts them
+    if sni {
+        let data_out = chains.get(DATA_OUT_CHAIN_NAME).unwrap();
+
+        NfHandle::get().sni_socket = Some(NlSocket::open(0).unwrap());
+
+        for chain in [data_out, data_fwd].iter() {
+            for port in SNI_PORTS.iter() {
+                exempt_batch.add(&sni_rule(chain, *port), nftnl::MsgType::Add);
+            }
+        }
+    }
+
+    for data_entry in config.data.iter() {
+        if let Some(selector) = data_entry.addr.selector.as_ref() {
+            NfHandle::get().selectors.insert(data_entry.line, selector.clone());
+        }
+    }
+
+    // Flows of selector entries are tagged by the packet, which shows the selector, packets of
+    // both directions are charged by the tag. Netdev family has no conntrack to keep tags in
+    if !matches!(config.family, Family::Netdev(_)) {
+        let tagged = config.data.iter().filter(|entry| entry.addr.selector.is_some());
+
+        for (pos, data_entry) in tagged.enumerate() {
+            let id = pos as u32 + 1;
+            let selector = data_entry.addr.selector.as_ref().unwrap();
+
+            for chain in tag_chains(&config.family, selector).iter() {
+                exempt_batch.add(&tag_rule(chains.get(chain).unwrap(), selector, id)?, nftnl::MsgType::Add);
+            }
+
+            NfHandle::get().tags.insert(data_entry.line, id);
+        }
+    }
+
+    // Allowed traffic is accepted in every chain, no entry can lock it out
+    for allow in config.allow.iter() {
+        for (name, chain) in chains.iter() {
+            let input = name.ends_with("-in");
+
+            for (_, rule) in allow_rules(chain, input, allow) {
+                exempt_batch.add(&rule, nftnl::MsgType::Add);
+            }
+        }
+    }
+
+    for exempt in config.exempt.iter() {
+        exempt_batch.add(&exempt_rule(data_in, true, None, exempt), nftnl::MsgType::Add);
+        exempt_batch.add(&exempt_rule(data_fwd, true, None, exempt), nftnl::MsgType::Add);
+        exempt_batch.add(&exempt_rule(time_in, true, None, exempt), nftnl::MsgType::Add);
+        exempt_batch.add(&exempt_rule(time_out, false, None, exempt), nftnl::MsgType::Add);
+        exempt_batch.add(&exempt_rule(kill_in, true, None, exempt), nftnl::MsgType::Add);
+        exempt_batch.add(&exempt_rule(kill_out, false, None, exempt), nftnl::MsgType::Add);
+    }
+
+    // Overlapping entries claim their packets in claim chains, before any of them is accounted.
+    // Entries of each kind claim on their own, first of them gets the highest id
+    for quota in [QuotaKind::Data, QuotaKind::Time].iter() {
+        let claims = config.claims(*quota);
+
+        for (pos, (line, _)) in claims.iter().enumerate() {
+            let claim = ((claims.len() - pos) as u32) << claim_shift(*quota);
+
+            NfHandle::get().claims.insert((*quota, *line), claim);
+        }
+    }
+
+    // Entries and their sets are created ahead of the rules, own exemptions of entry look its set up
     for (pos, data_entry) in config.data.iter().enumerate() {
 
         let name = format!("{}{}", DATA_LOG_PREFIX, pos.to_string());
 
+        let chain = match data_entry.addr.selector {
+            Some(_) => DATA_SEL_CHAIN_NAME,
+            None => DATA_IN_CHAIN_NAME,
+        };
+
         let limit = NfDataLimit::new(
             data_entry,
-            NfHandle::get().chains.get(DATA_IN_CHAIN_NAME).unwrap(),
+            NfHandle::get().chains.get(chain).unwrap(),
+            kill_chains(),
             &name
         );
 
-        limit.add();
+        add_net_set(&limit.set, &limit.nets);
+
+        for exempt in data_entry.opts.exempt.iter() {
+            exempt_batch.add(&exempt_rule(data_in, true, Some(&limit.set), exempt), nftnl::MsgType::Add);
+
+            if data_entry.opts.kill {
+                exempt_batch.add(&exempt_rule(kill_in, true, Some(&limit.set), exempt), nftnl::MsgType::Add);
+                exempt_batch.add(&exempt_rule(kill_out, false, Some(&limit.set), exempt), nftnl::MsgType::Add);
+            }
+        }
 
-        NfHandle::get().data_entries.insert(&name, limit);
+        NfHandle::get().data_entries.insert(name, limit);
     }
 
-    // Process time quota entries
     for (pos, time_entry) in config.time.iter().enumerate() {
         let name = format!("{}{}", TIME_LOG_PREFIX, pos.to_string());
 
-        let limit = NfTimeLimit::new(
+        let mut limit = NfTimeLimit::new(
             time_entry,
             NfHandle::get().chains.get(TIME_IN_CHAIN_NAME).unwrap(),
             NfHandle::get().chains.get(TIME_OUT_CHAIN_NAME).unwrap(),
+            kill_chains(),
+            &name
+        );
+
+        limit.exempt.extend(config.exempt.iter().cloned());
+        add_net_set(&limit.set, &limit.nets);
+
+        for exempt in time_entry.opts.exempt.iter() {
+            exempt_batch.add(&exempt_rule(time_in, true, Some(&limit.set), exempt), nftnl::MsgType::Add);
+            exempt_batch.add(&exempt_rule(time_out, false, Some(&limit.set), exempt), nftnl::MsgType::Add);
+
+            if time_entry.opts.kill {
+                exempt_batch.add(&exempt_rule(kill_in, true, Some(&limit.set), exempt), nftnl::MsgType::Add);
+                exempt_batch.add(&exempt_rule(kill_out, false, Some(&limit.set), exempt), nftnl::MsgType::Add);
+            }
+        }
+
+        NfHandle::get().time_entries.insert(name, limit);
+    }
+
+    for (pos, window_entry) in config.windows.iter().enumerate() {
+        let name = format!("{}{}", WINDOW_PREFIX, pos.to_string());
+
+        let limit = NfWindowLimit::new(
+            window_entry,
+            NfHandle::get().chains.get(WINDOW_IN_CHAIN_NAME).unwrap(),
+            NfHandle::get().chains.get(WINDOW_OUT_CHAIN_NAME).unwrap(),
+            kill_chains(),
             &name
         );
 
+        add_net_set(&limit.set, &limit.nets);
+
+        NfHandle::get().window_entries.insert(name, limit);
+    }
+
+    let selectors: Vec<EntryMatch> = config.data.iter()
+        .filter(|entry| entry.addr.selector.is_some())
+        .filter_map(|entry| entry_match(QuotaKind::Data, entry.line, &entry.addr))
+        .collect();
+
+    // Selector entries see input, forwarded and output traffic alike, quota is charged once as packet takes
+    // one of the hooks. Jumps go after tag rules, thus the packet, which tags the flow, is charged too
+    if config.data.iter().any(|entry| entry.addr.selector.is_some()) {
+        let data_out = chains.get(DATA_OUT_CHAIN_NAME).unwrap();
+        // Other families hook all chains the same way, one jump is enough there
+        let jumps: &[&Chain] = if config.family == Family::Ipv4 { &[data_in, data_fwd, data_out] } else { &[data_in] };
+
+        for chain in jumps.iter() {
+            let mut rule = Rule::new(chain);
+
+            rule.add_expr(&Verdict::Jump { chain: CString::new(DATA_SEL_CHAIN_NAME).unwrap() });
+
+            exempt_batch.add(&rule, nftnl::MsgType::Add);
+        }
+    }
+
+    process_netlink(&(exempt_batch.finalize()), false).unwrap();
+
+    // Process data quota entries, claims of each go ahead of its rules
+    for pos in 0..config.data.len() {
+        let limit = NfHandle::get().data_entries.get_mut(&format!("{}{}", DATA_LOG_PREFIX, pos)).unwrap();
+
+        add_claims(&limit.claim_rules);
+        limit.add();
+    }
+
+    // Process time quota entries
+    for pos in 0..config.time.len() {
+        let limit = NfHandle::get().time_entries.get_mut(&format!("{}{}", TIME_LOG_PREFIX, pos)).unwrap();
+
+        add_claims(&limit.claim_rules);
         limit.add();
+    }
 
-        NfHandle::get().time_entries.insert(&name, limit);
+    // Process access window entries
+    for pos in 0..config.windows.len() {
+        NfHandle::get().window_entries.get_mut(&format!("{}{}", WINDOW_PREFIX, pos)).unwrap().add();
+    }
+
+    // Clients of previous run get their entries back at once, new ones are taken on ticks
+    if let Some(clients) = config.clients.as_ref() {
+        let listed: Vec<Ipv4Addr> = config.data.iter().map(|entry| &entry.addr)
+            .chain(config.time.iter().map(|entry| &entry.addr))
+            .chain(config.windows.iter().map(|entry| &entry.addr))
+            .flat_map(|addr| addr.value.iter())
+            .filter(|net| net.prefix() == 32)
+            .map(|net| net.ip())
+            .collect();
+
+        let clients = NfClients::new(clients, listed);
+        let state = clients.load_state();
+
+        NfHandle::get().clients = Some(clients);
+
+        for addr in state {
+            add_client(addr);
+        }
+    }
+
+    // Unmatched counters skip networks of all entries, but SNI ones, which span everything
+    if config.unmatched {
+        let unmatched = NfUnmatched::new(
+            NfHandle::get().chains.get(UNMATCHED_IN_CHAIN_NAME).unwrap(),
+            NfHandle::get().chains.get(UNMATCHED_OUT_CHAIN_NAME).unwrap(),
+            sni,
+            &selectors);
+
+        // Wildcard entries are matched by something else than address
+        let wildcard: Vec<u32> = config.data.iter()
+            .filter(|entry| entry.addr.sni.is_some() || entry.addr.selector.is_some())
+            .map(|entry| entry.line)
+            .collect();
+        let mut nets: Vec<Ipv4Network> = Vec::new();
+
+        nets.extend(NfHandle::get().data_entries.values()
+            .filter(|limit| !wildcard.contains(&limit.line))
+            .flat_map(|limit| limit.nets.iter().cloned()));
+        nets.extend(NfHandle::get().time_entries.values().flat_map(|limit| limit.nets.iter().cloned()));
+        nets.extend(NfHandle::get().window_entries.values().flat_map(|limit| limit.nets.iter().cloned()));
+
+        // Skip rules need the handle to find their chains
+        NfHandle::get().unmatched = Some(unmatched);
+        NfHandle::get().unmatched.as_mut().unwrap().add(&nets);
+    }
+
+    // Windows are evaluated right away, thus restart in the middle of one blocks at once
+    if !config.windows.is_empty() || snoop || metrics.is_some() || config.unmatched || config.clients.is_some()
+        || config.data.iter().any(|entry| entry.period != Period::Never)
+        || config.time.iter().any(|entry| entry.period != Period::Never) {
+        schedule::start(schedule::SCHEDULE_TICK, schedule_cb);
+    }
+
+    // Conntrack events are needed only if some entry tracks sessions with it, or to spot new clients
+    if config.time.iter().any(|entry| entry.opts.idle.is_none() && entry.opts.track == Tracking::Conntrack)
+        || config.clients.is_some() {
+        conntrack::listen(conntrack_cb)?;
     }
 
 
@@ -567,10 +3218,42 @@ pub fn init<'a>(config: &Config) -> Result<(), NfError> {
     NfHandle::get().log.groups.push(data_quota_group);
     NfHandle::get().log.groups.push(time_quota_group);
 
+    if config.time.iter().any(|entry| entry.opts.idle.is_some()) {
+        let mut activity_group = NfHandle::get().log.queue.bind_group(TIME_ACTIVITY_NUM).unwrap();
+
+        activity_group.set_mode(nflog::CopyMode::Meta, 0xffff);
+        activity_group.set_qthresh(TIME_ACTIVITY_QTHRESH);
+        activity_group.set_callback(Box::new(time_activity_cb));
+
+        NfHandle::get().log.groups.push(activity_group);
+    }
+
+    if snoop || config.unmatched {
+        let mut dns_group = NfHandle::get().log.queue.bind_group(DNS_SNOOP_NUM).unwrap();
+
+        // Whole packet is needed to parse the answer
+        dns_group.set_mode(nflog::CopyMode::Packet, 0xffff);
+        dns_group.set_callback(Box::new(dns_snoop_cb));
+
+        NfHandle::get().log.groups.push(dns_group);
+    }
+
+    if sni {
+        let mut sni_group = NfHandle::get().log.queue.bind_group(SNI_NUM).unwrap();
+
+        // Whole packet is needed to parse ClientHello or request header
+        sni_group.set_mode(nflog::CopyMode::Packet, 0xffff);
+        sni_group.set_callback(Box::new(sni_cb));
+
+        NfHandle::get().log.groups.push(sni_group);
+    }
+
     Ok(())
 }
 
 pub fn deinit() -> Result<(), NfError> {
+    let _handle = lock_handle();
+
     // TODO check if initialised
     let mut batch = Batch::new();
 
@@ -580,9 +3263,252 @@ pub fn deinit() -> Result<(), NfError> {
     Ok(())
 }
 
-pub fn run() {
-    // TODO check if initialised
-    NfHandle::get().log.queue.run_loop();
+/// Logs status report and writes it into given file, if any.
+pub fn dump_status(path: Option<&str>) {
+    let _handle = lock_handle();
+
+    // Not initialized yet
+    if unsafe { HANDLE_INSTANCE.get().is_none() } {
+        return;
+    }
+
+    let report = NfHandle::get().status();
+
+    info!("Status:\n{}", report);
+
+    if let Some(path) = path {
+        if let Err(e) = std::fs::write(path, &report) {
+            error!("Failed to write status file {}: {}", path, e);
+        }
+    }
+}
+
+/// Writes metrics file, if it was asked for.
+pub fn dump_metrics() {
+    let _handle = lock_handle();
+
+    // Not initialized yet
+    if unsafe { HANDLE_INSTANCE.get().is_none() } {
+        return;
+    }
+
+    write_metrics();
+}
+
+fn write_metrics() {
+    if let Some(path) = NfHandle::get().metrics.as_ref() {
+        // Written aside and renamed, so that collector never reads half of it
+        let tmp = format!("{}.tmp", path);
+
+        if let Err(e) = std::fs::write(&tmp, NfHandle::get().metrics()).and_then(|_| std::fs::rename(&tmp, path)) {
+            error!("Failed to write metrics file {}: {}", path, e);
+        }
+    }
+}
+
+// Resolves source again, returns None (keeping the old networks) on failure
+fn resolve_nets(name: &str, source: &Option<String>) -> Option<Vec<Ipv4Network>> {
+    let source = source.as_ref()?;
+
+    match accnt::resolve_source(source) {
+        Ok(addr) => Some(addr.value),
+        Err(e) => {
+            error!("{}: failed to reload {}, keeping previous networks: {}", name, source, e);
+            None
+        },
+    }
+}
+
+/// Re-reads address lists of entries, which have them.
+pub fn reload() {
+    let _handle = lock_handle();
+
+    // Not initialized yet
+    if unsafe { HANDLE_INSTANCE.get().is_none() } {
+        return;
+    }
+
+    info!("Reloading address lists");
+
+    // Updated GeoIP database is read once, for all entries
+    accnt::reload_geoip();
+
+    for (name, limit) in NfHandle::get().data_entries.iter_mut() {
+        if let Some(nets) = resolve_nets(name, &limit.source) {
+            limit.set_nets(&nets);
+        }
+    }
+
+    for (name, limit) in NfHandle::get().time_entries.iter_mut() {
+        if let Some(nets) = resolve_nets(name, &limit.source) {
+            limit.set_nets(&nets);
+        }
+    }
+
+    for (name, limit) in NfHandle::get().window_entries.iter_mut() {
+        if let Some(nets) = resolve_nets(name, &limit.source) {
+            limit.set_nets(&nets);
+        }
+    }
+}
+
+/// Runs nflog loop, which passes logged packets to callbacks. Fails, if called before `init`.
+pub fn run() -> Result<(), NfError> {
+    let queue = {
+        let _handle = lock_handle();
+
+        // Not initialized yet
+        if unsafe { HANDLE_INSTANCE.get().is_none() } {
+            return Err(NfError::NfTablesError("netfilter is not initialized".to_owned()));
+        }
+
+        // Queue is set up by init and never replaced, nor touched by other threads
+        &mut NfHandle::get().log.queue
+    };
+
+    // Lock is released for the loop, callbacks take it themselves
+    queue.run_loop();
+
+    Ok(())
+}
+
+fn format_talkers(talkers: &[(Ipv4Addr, u64)]) -> String {
+    if talkers.is_empty() {
+        return "-".to_owned();
+    }
+
+    talkers.iter().map(|(addr, bytes)| format!("{} {}b", addr, bytes)).collect::<Vec<String>>().join(", ")
+}
+
+/// Reads (address, bytes) counters of dynamic set elements from kernel.
+pub fn fetch_set_counters(name: &CStr) -> Result<Vec<(Ipv4Addr, u64)>, NfError> {
+    let socket = &NfHandle::get().query;
+
+    let table = CString::new(TABLE_NAME).unwrap();
+
+    socket.send(&netlink::build_msg(
+        NFNL_SUBSYS_NFTABLES, NFT_MSG_GETSETELEM, libc::NLM_F_DUMP as u16, table_family(),
+        &[
+            (NFTA_SET_ELEM_LIST_TABLE, table.as_bytes_with_nul()),
+            (NFTA_SET_ELEM_LIST_SET, name.to_bytes_with_nul()),
+        ]))?;
+
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let mut counters = Vec::new();
+
+    loop {
+        let len = socket.recv(&mut buf[..])?;
+        let (msgs, done) = netlink::messages(&buf[..len], NFNL_SUBSYS_NFTABLES);
+
+        for (msg, payload) in msgs {
+            if msg != NFT_MSG_NEWSETELEM {
+                continue;
+            }
+
+            for (kind, elems) in netlink::attrs(payload) {
+                if kind != NFTA_SET_ELEM_LIST_ELEMENTS {
+                    continue;
+                }
+
+                counters.extend(netlink::attrs(elems).into_iter()
+                    .filter(|(elem_kind, _)| *elem_kind == NFTA_LIST_ELEM)
+                    .filter_map(|(_, elem)| parse_counter_elem(elem)));
+            }
+        }
+
+        if done || len == 0 {
+            break;
+        }
+    }
+
+    Ok(counters)
+}
+
+// Set element of address key and counter expression
+fn parse_counter_elem(elem: &[u8]) -> Option<(Ipv4Addr, u64)> {
+    let (mut addr, mut bytes) = (None, None);
+
+    for (kind, data) in netlink::attrs(elem) {
+        match kind {
+            NFTA_SET_ELEM_KEY => {
+                addr = netlink::attrs(data).into_iter()
+                    .find(|(key_kind, key)| *key_kind == NFTA_DATA_VALUE && key.len() == 4)
+                    .map(|(_, key)| Ipv4Addr::new(key[0], key[1], key[2], key[3]));
+            },
+            // Newer kernels nest single expression into a list
+            NFTA_SET_ELEM_EXPR | NFTA_SET_ELEM_EXPRESSIONS => {
+                let exprs = if kind == NFTA_SET_ELEM_EXPR {
+                    vec![data]
+                } else {
+                    netlink::attrs(data).into_iter().map(|(_, expr)| expr).collect()
+                };
+
+                for expr in exprs {
+                    for (expr_kind, expr_data) in netlink::attrs(expr) {
+                        if expr_kind != NFTA_EXPR_DATA {
+                            continue;
+                        }
+
+                        for (counter_kind, counter_data) in netlink::attrs(expr_data) {
+                            if counter_kind == NFTA_COUNTER_BYTES {
+                                bytes = netlink::read_be_u64(counter_data);
+                            }
+                        }
+                    }
+                }
+            },
+            _ => (),
+        }
+    }
+
+    Some((addr?, bytes?))
+}
+
+// Family of netcontrol table, raw netlink requests carry it in their header
+fn table_family() -> u8 {
+    NfHandle::get().table.get_family() as u8
+}
+
+/// Reads consumed bytes of quota object from kernel.
+pub fn fetch_quota_consumed(name: &CStr) -> Result<u64, NfError> {
+    let socket = &NfHandle::get().query;
+
+    let table = CString::new(TABLE_NAME).unwrap();
+    let obj_type = NFT_OBJECT_QUOTA.to_be_bytes();
+
+    socket.send(&netlink::build_msg(
+        NFNL_SUBSYS_NFTABLES, NFT_MSG_GETOBJ, 0, table_family(),
+        &[
+            (NFTA_OBJ_TABLE, table.as_bytes_with_nul()),
+            (NFTA_OBJ_NAME, name.to_bytes_with_nul()),
+            (NFTA_OBJ_TYPE, &obj_type[..]),
+        ]))?;
+
+    let mut buf = vec![0u8; RECV_BUF_SIZE];
+    let len = socket.recv(&mut buf[..])?;
+
+    let (msgs, _) = netlink::messages(&buf[..len], NFNL_SUBSYS_NFTABLES);
+
+    for (msg, payload) in msgs {
+        if msg != NFT_MSG_NEWOBJ {
+            continue;
+        }
+
+        for (kind, data) in netlink::attrs(payload) {
+            if kind != NFTA_OBJ_DATA {
+                continue;
+            }
+
+            for (quota_kind, quota_data) in netlink::attrs(data) {
+                if quota_kind == NFTA_QUOTA_CONSUMED {
+                    return netlink::read_be_u64(quota_data)
+                        .ok_or(NfError::NfTablesError("malformed quota consumed attribute".to_owned()));
+                }
+            }
+        }
+    }
+
+    Err(NfError::NfTablesError(format!("quota {} not found", name.to_string_lossy())))
 }
 
 fn process_netlink(batch: &FinalizedBatch, ack_wait: bool) -> Result<(), NfError> {
@@ -637,3 +3563,314 @@ fn socket_recv<'a>(socket: &mnl::Socket, buf: &'a mut [u8]) -> Result<Option<&'a
     }
 }
 
+// Expressions of rule, as libnftnl prints them ("[ ct load mark => reg 1 ] ...")
+#[cfg(test)]
+fn rule_exprs(rule: &Rule) -> String {
+    let mut buf = vec![0u8; 4096];
+    let len = unsafe {
+        sys::nftnl_rule_snprintf(buf.as_mut_ptr() as *mut libc::c_char, buf.len(), rule.as_ptr(), sys::NFTNL_OUTPUT_DEFAULT, 0)
+    };
+
+    buf.truncate((len.max(0) as usize).min(buf.len() - 1));
+
+    String::from_utf8(buf).unwrap()
+}
+
+
+#[test]
+fn roll_window_test() {
+    let (window, start) = (Duration::from_secs(48 * 60), Instant::now());
+    let at = |mins: u64, secs: u64| start + Duration::from_secs(mins * 60 + secs);
+    let mut samples = VecDeque::new();
+
+    assert_eq!(roll_window(&mut samples, window, at(0, 0), 0), 0);
+
+    // Samples within a bucket do not open a new one
+    assert_eq!(roll_window(&mut samples, window, at(0, 30), 100), 100);
+    assert_eq!(samples.len(), 1);
+
+    assert_eq!(roll_window(&mut samples, window, at(1, 0), 200), 200);
+    assert_eq!(roll_window(&mut samples, window, at(48, 0), 300), 300);
+
+    // First bucket ages out, the second one is the baseline now
+    assert_eq!(roll_window(&mut samples, window, at(49, 0), 300), 100);
+    assert_eq!(samples.front().map(|(_, consumed)| *consumed), Some(200));
+
+    // Nothing was consumed within the window
+    assert_eq!(roll_window(&mut samples, window, at(200, 0), 300), 0);
+    assert_eq!(samples.len(), 2);
+}
+
+#[test]
+fn carry_amount_test() {
+    // Unused part of the period is carried
+    assert_eq!(carry_amount(1000, 0, 400, 7), 600);
+    // Carried allowance is spent first, the rest of it goes on
+    assert_eq!(carry_amount(1000, 600, 1500, 7), 100);
+    // Period without traffic adds up to the cap
+    assert_eq!(carry_amount(1000, 1000, 0, 1), 1000);
+    assert_eq!(carry_amount(1000, 6500, 0, 7), 7000);
+    // Overrun of the period carries nothing
+    assert_eq!(carry_amount(1000, 0, 1200, 7), 0);
+    assert_eq!(carry_amount(1000, 0, 0, 0), 0);
+}
+
+#[test]
+fn is_exempt_flow_test() {
+    let nets: Vec<Ipv4Network> = vec!["94.142.241.0/24".parse().unwrap()];
+    let exempt = [Exempt::Net("94.142.241.111/32".parse().unwrap()), Exempt::Port(libc::IPPROTO_UDP as u8, 123)];
+
+    let event = |src: &str, dst: &str, proto: i32, sport: u16, dport: u16| CtEvent {
+        kind: CtEventType::New,
+        proto: proto as u8,
+        src: src.parse().unwrap(),
+        dst: dst.parse().unwrap(),
+        sport,
+        dport,
+    };
+
+    // Exempt address of the entry, either way
+    assert!(is_exempt_flow(&nets, &exempt, &event("10.0.0.2", "94.142.241.111", libc::IPPROTO_TCP, 40000, 443)));
+    assert!(is_exempt_flow(&nets, &exempt, &event("94.142.241.111", "10.0.0.2", libc::IPPROTO_TCP, 40000, 22)));
+    assert!(!is_exempt_flow(&nets, &exempt, &event("10.0.0.2", "94.142.241.112", libc::IPPROTO_TCP, 40000, 443)));
+
+    // Exempt port is the one of the entry end
+    assert!(is_exempt_flow(&nets, &exempt, &event("10.0.0.2", "94.142.241.5", libc::IPPROTO_UDP, 40000, 123)));
+    assert!(!is_exempt_flow(&nets, &exempt, &event("10.0.0.2", "94.142.241.5", libc::IPPROTO_TCP, 40000, 123)));
+    assert!(!is_exempt_flow(&nets, &exempt, &event("10.0.0.2", "94.142.241.5", libc::IPPROTO_UDP, 123, 40000)));
+
+    // Flow of other entry
+    assert!(!is_exempt_flow(&nets, &exempt, &event("10.0.0.2", "1.1.1.1", libc::IPPROTO_UDP, 40000, 123)));
+}
+
+#[test]
+fn is_allowed_flow_test() {
+    let event = |src: &str, dst: &str, proto: i32, sport: u16, dport: u16| CtEvent {
+        kind: CtEventType::New,
+        proto: proto as u8,
+        src: src.parse().unwrap(),
+        dst: dst.parse().unwrap(),
+        sport,
+        dport,
+    };
+    let allow = [Exempt::Net("192.168.1.0/24".parse().unwrap()), Exempt::Port(libc::IPPROTO_TCP as u8, 22)];
+
+    // SSH sessions to and from this host, and flows of allowed networks survive killing
+    assert!(is_allowed_flow(&allow, &event("10.0.0.2", "1.1.1.1", libc::IPPROTO_TCP, 40000, 22)));
+    assert!(is_allowed_flow(&allow, &event("1.1.1.1", "10.0.0.2", libc::IPPROTO_TCP, 40000, 22)));
+    assert!(is_allowed_flow(&allow, &event("1.1.1.1", "192.168.1.5", libc::IPPROTO_UDP, 40000, 53)));
+    assert!(!is_allowed_flow(&allow, &event("10.0.0.2", "1.1.1.1", libc::IPPROTO_UDP, 40000, 22)));
+    assert!(!is_allowed_flow(&allow, &event("10.0.0.2", "1.1.1.1", libc::IPPROTO_TCP, 40000, 443)));
+}
+
+#[test]
+fn allow_rules_test() {
+    let table = Table::new(&CString::new(TABLE_NAME).unwrap(), ProtoFamily::Ipv4);
+    let chain = Chain::new(&CString::new(TIME_IN_CHAIN_NAME).unwrap(), &table);
+    let ssh = Exempt::Port(libc::IPPROTO_TCP as u8, 22);
+
+    // Inbound SSH comes to destination port 22 on input, replies to sessions of this host from source port
+    for input in [true, false].iter() {
+        let rules = allow_rules(&chain, *input, &ssh);
+        let ends: Vec<Option<PortEnd>> = rules.iter().map(|(end, _)| *end).collect();
+
+        assert_eq!(ends, vec![Some(PortEnd::Source), Some(PortEnd::Destination)]);
+
+        // Source port sits at offset 0 of TCP header, destination port at 2
+        let (sport, dport) = (rule_exprs(&rules[0].1), rule_exprs(&rules[1].1));
+
+        assert!(sport.contains("transport header + 0 ") && !sport.contains("transport header + 2 "));
+        assert!(dport.contains("transport header + 2 ") && !dport.contains("transport header + 0 "));
+        assert!(sport.contains("accept") && dport.contains("accept"));
+    }
+
+    let net = Exempt::Net("10.0.0.0/24".parse().unwrap());
+
+    assert_eq!(allow_rules(&chain, true, &net).len(), 1);
+}
+
+#[test]
+fn nets_diff_test() {
+    let nets = |list: &[&str]| -> Vec<Ipv4Network> { list.iter().map(|net| net.parse().unwrap()).collect() };
+
+    let old = nets(&["10.0.0.0/8", "192.168.1.0/24", "1.1.1.1/32"]);
+    let new = nets(&["1.1.1.1/32", "10.0.0.0/8", "172.16.0.0/12"]);
+
+    assert_eq!(nets_diff(&old, &new), (nets(&["172.16.0.0/12"]), nets(&["192.168.1.0/24"])));
+    // Same networks in other order change nothing
+    assert_eq!(nets_diff(&old, &nets(&["1.1.1.1/32", "192.168.1.0/24", "10.0.0.0/8"])), (vec![], vec![]));
+    assert_eq!(nets_diff(&[], &old), (old.clone(), vec![]));
+}
+
+#[test]
+fn count_refs_test() {
+    let net = |net: &str| -> Ipv4Network { net.parse().unwrap() };
+    let mut refs = HashMap::new();
+
+    assert!(count_refs(&mut refs, &[net("10.0.0.0/8"), net("1.1.1.1/32")], &[]));
+    // Second entry listing the same network does not change the set
+    assert!(!count_refs(&mut refs, &[net("10.0.0.0/8")], &[]));
+    assert!(!count_refs(&mut refs, &[], &[net("10.0.0.0/8")]));
+    assert!(count_refs(&mut refs, &[], &[net("10.0.0.0/8")]));
+    assert_eq!(refs.keys().collect::<Vec<_>>(), vec![&net("1.1.1.1/32")]);
+}
+
+#[test]
+fn accumulate_test() {
+    let addr = |addr: &str| -> Ipv4Addr { addr.parse().unwrap() };
+    let mut totals = HashMap::new();
+    let mut names = HashMap::new();
+
+    names.insert(addr("1.1.1.1"), "one.one.one.one".to_owned());
+
+    // Counters of flushed sets add up over samples, unnamed remotes go by /24
+    assert_eq!(accumulate(&mut totals, &names, &[(addr("1.1.1.1"), 100), (addr("8.8.8.8"), 50), (addr("8.8.8.4"), 20)]), 170);
+    assert_eq!(accumulate(&mut totals, &names, &[(addr("1.1.1.1"), 10), (addr("9.9.9.9"), 5)]), 15);
+
+    assert_eq!(top_labels(&totals, 2), vec![("one.one.one.one".to_owned(), 110), ("8.8.8.0/24".to_owned(), 70)]);
+    assert_eq!(top_labels(&totals, 5).len(), 3);
+}
+
+#[test]
+fn client_rules_test() {
+    let table = Table::new(&CString::new(TABLE_NAME).unwrap(), ProtoFamily::Ipv4);
+    let chain = Chain::new(&CString::new(DATA_FWD_CHAIN_NAME).unwrap(), &table);
+    let set = NetSet::new("dq_c0_0_nets", &table);
+    let quota = Quota::new(&CString::new("dq_c0_0").unwrap(), &table);
+
+    // Client is destination of downloads and source of uploads, both are charged
+    let ruleset = DataLimitRuleset::new(&chain, &set, set_ends(true), &quota, &Action::Drop, None);
+
+    assert_eq!((ruleset.log.len(), ruleset.block.len()), (2, 2));
+
+    // Set is looked up by destination address (offset 16 of IPv4 header) on download, by source
+    // address (offset 12) on upload
+    for rules in [&ruleset.log, &ruleset.block].iter() {
+        let (download, upload) = (rule_exprs(&rules[0]), rule_exprs(&rules[1]));
+
+        assert!(download.contains("network header + 16 ") && !download.contains("network header + 12 "));
+        assert!(upload.contains("network header + 12 ") && !upload.contains("network header + 16 "));
+        assert!(download.contains("set dq_c0_0_nets") && upload.contains("set dq_c0_0_nets"));
+    }
+
+    let ruleset = DataLimitRuleset::new(&chain, &set, set_ends(false), &quota, &Action::Drop, None);
+
+    assert_eq!((ruleset.log.len(), ruleset.block.len()), (1, 1));
+}
+
+#[test]
+fn tier_ops_test() {
+    let table = Table::new(&CString::new(TABLE_NAME).unwrap(), ProtoFamily::Ipv4);
+    let chain = Chain::new(&CString::new(DATA_IN_CHAIN_NAME).unwrap(), &table);
+    let set = NetSet::new("dq_0_nets", &table);
+    let quota = Quota::new(&CString::new("dq_0").unwrap(), &table);
+    let tiers: Vec<NfDataTier> = ["dq_0_t1", "dq_0_t2"].iter()
+        .map(|name| {
+            let quota = Quota::new(&CString::new(*name).unwrap(), &table);
+            let rules = DataLimitRuleset::new(&chain, &set, set_ends(false), &quota, &Action::Drop, None);
+
+            NfDataTier { quota, action: Action::Drop, reached: false, rules }
+        })
+        .collect();
+    let main = DataLimitRuleset::new(&chain, &set, set_ends(false), &quota, &Action::Drop, None);
+
+    // Chain as laid out by add(): tiers, then main quota
+    let mut rules: Vec<&Rule> = vec![&tiers[0].rules.log[0], &tiers[1].rules.log[0], &main.log[0]];
+
+    for (rule, msg) in tier_ops(&tiers, 0, main.log.iter().collect(), None) {
+        match msg {
+            nftnl::MsgType::Add => rules.push(rule),
+            nftnl::MsgType::Del => rules.retain(|r| !std::ptr::eq(*r, rule)),
+        }
+    }
+
+    // Block rule of the tier stays ahead of rules, which would account packets it stops
+    let expected: Vec<&Rule> = vec![&tiers[0].rules.block[0], &tiers[1].rules.log[0], &main.log[0]];
+
+    assert_eq!(rules.len(), expected.len());
+    assert!(rules.iter().zip(expected.iter()).all(|(a, b)| std::ptr::eq(*a, *b)));
+}
+
+#[test]
+fn tag_rules_test() {
+    let table = Table::new(&CString::new(TABLE_NAME).unwrap(), ProtoFamily::Ipv4);
+    let chain = Chain::new(&CString::new(DATA_IN_CHAIN_NAME).unwrap(), &table);
+    let mac = Selector::Mac([0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0x0f]);
+    let iface = Selector::InIface("wlan-guest".to_owned());
+    let oiface = Selector::OutIface("wan".to_owned());
+    let user = Selector::User(1000);
+
+    for selector in [&mac, &iface, &oiface, &user].iter() {
+        assert!(tag_rule(&chain, selector, 1).is_ok());
+    }
+
+    // Device shows itself on uploads, to this host and forwarded alike
+    assert_eq!(tag_chains(&Family::Ipv4, &mac), &[DATA_IN_CHAIN_NAME, DATA_FWD_CHAIN_NAME]);
+    assert_eq!(tag_chains(&Family::Ipv4, &iface), &[DATA_IN_CHAIN_NAME, DATA_FWD_CHAIN_NAME]);
+    assert_eq!(tag_chains(&Family::Ipv4, &oiface), &[DATA_FWD_CHAIN_NAME]);
+    assert_eq!(tag_chains(&Family::Ipv4, &user), &[DATA_OUT_CHAIN_NAME]);
+    assert_eq!(tag_chains(&Family::Bridge, &mac), &[DATA_IN_CHAIN_NAME]);
+
+    // Untagged flow gets the id into upper bits of ct mark, the rest of it is kept
+    let exprs = rule_exprs(&tag_rule(&chain, &mac, 3).unwrap());
+
+    assert!(exprs.contains("ct load mark"));
+    assert!(exprs.contains("& 0xfffe0000"));
+    assert!(exprs.contains("cmp eq reg 1 0x00000000"));
+    assert!(exprs.contains("link header"));
+    assert!(exprs.contains("& 0x0001ffff"));
+    assert!(exprs.contains("^ 0x00060000"));
+    assert!(exprs.find("ct load mark") < exprs.find("ct set mark"));
+
+    // Both directions are charged by the tag alone, the device shows on uploads only
+    assert!(matches!(EntryMatch::tag(3), EntryMatch::Mark { conntrack: true, mask: TAG_MARK_MASK, value } if value == 3 << TAG_MARK_SHIFT));
+
+    let sel_chain = Chain::new(&CString::new(DATA_SEL_CHAIN_NAME).unwrap(), &table);
+    let set = NetSet::new("dq_0_nets", &table);
+    let quota = Quota::new(&CString::new("dq_0").unwrap(), &table);
+    let ruleset = DataLimitRuleset::new(&sel_chain, &set, set_ends(false), &quota, &Action::Drop, Some(&EntryMatch::tag(3)));
+
+    for rule in ruleset.log.iter().chain(ruleset.block.iter()) {
+        let exprs = rule_exprs(rule);
+
+        assert!(exprs.contains("ct load mark"));
+        assert!(exprs.contains("& 0xfffe0000"));
+        assert!(exprs.contains("cmp eq reg 1 0x00060000"));
+        assert!(!exprs.contains("link header") && !exprs.contains("iifname"));
+    }
+}
+
+#[test]
+fn device_test() {
+    // Socket owner does not show on the packet, its entries are matched by flow tag alone
+    assert_eq!(Device::of(&Selector::User(1000)), None);
+    assert_eq!(Device::of(&Selector::Cgroup("system.slice".to_owned())), None);
+    assert_eq!(Device::of(&Selector::InIface("eth1".to_owned())), Some(Device::InIface("eth1".to_owned())));
+}
+
+#[test]
+fn check_family_test() {
+    let netdev = Family::Netdev("eth0".to_owned());
+    let check = |family: &Family, line: &str| -> Result<(), NfError> {
+        line.parse::<accnt::Entry>().unwrap().quotas.iter().try_for_each(|quota| match quota {
+            accnt::QuotaType::Data(entry) => check_entry_family(family, "line 1", &entry.addr, &entry.opts, DATA_DEFAULT_ACTION),
+            accnt::QuotaType::Time(entry) => check_entry_family(family, "line 1", &entry.addr, &entry.opts, TIME_DEFAULT_ACTION),
+            accnt::QuotaType::Window(entry) => check_entry_family(family, "line 1", &entry.addr, &entry.opts, WINDOW_DEFAULT_ACTION),
+        })
+    };
+
+    assert!(check(&netdev, "10.0.0.0/8 1gb").is_ok());
+    assert!(check(&netdev, "10.0.0.0/8 2h action=drop").is_ok());
+    assert!(check(&netdev, "10.0.0.0/8 1gb kill=on").is_err());
+    assert!(check(&netdev, "10.0.0.0/8 1gb action=reject").is_err());
+    assert!(check(&netdev, "10.0.0.0/8 1gb tiers=500mb->reject:tcp-reset").is_err());
+    // Time quota and window reject by default
+    assert!(check(&netdev, "10.0.0.0/8 2h").is_err());
+    assert!(check(&netdev, "10.0.0.0/8 block@22:00-07:00").is_err());
+    assert!(check(&netdev, "user:1000 1gb").is_err());
+
+    // Bridge family has both, but no socket owner
+    assert!(check(&Family::Bridge, "10.0.0.0/8 1gb kill=on action=reject").is_ok());
+    assert!(check(&Family::Bridge, "10.0.0.0/8 2h").is_ok());
+    assert!(check(&Family::Bridge, "user:1000 1gb").is_err());
+}
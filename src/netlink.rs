@@ -0,0 +1,192 @@
+use libc;
+use std::{
+    io,
+    mem,
+};
+
+
+pub const NLA_F_NESTED: u16 = 1 << 15;
+const NLA_TYPE_MASK: u16 = !((1 << 15) | (1 << 14));
+const NLMSG_HDRLEN: usize = 16;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const NFGENMSG_LEN: usize = 4;
+pub const RECV_BUF_SIZE: usize = 64 * 1024;
+
+
+/// Raw NETLINK_NETFILTER socket, for the messages nftnl/mnl do not cover.
+#[derive(Debug)]
+pub struct NlSocket {
+    fd: libc::c_int,
+}
+
+impl Drop for NlSocket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd); }
+    }
+}
+
+impl NlSocket {
+    /// Opens socket, subscribed to given multicast groups bitmask.
+    pub fn open(groups: u32) -> io::Result<NlSocket> {
+        let fd = unsafe {
+            libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, libc::NETLINK_NETFILTER)
+        };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let socket = NlSocket { fd };
+
+        let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as u16;
+        addr.nl_groups = groups;
+
+        let ret = unsafe {
+            libc::bind(
+                fd,
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(socket)
+    }
+
+    pub fn send(&self, msg: &[u8]) -> io::Result<()> {
+        let ret = unsafe {
+            libc::send(self.fd, msg.as_ptr() as *const libc::c_void, msg.len(), 0)
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        let ret = unsafe {
+            libc::recv(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0)
+        };
+
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(ret as usize)
+    }
+}
+
+pub fn read_u16(buf: &[u8], pos: usize) -> u16 {
+    u16::from_ne_bytes([buf[pos], buf[pos + 1]])
+}
+
+pub fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_ne_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
+
+// Netfilter attributes carry numbers in network byte order
+pub fn read_be_u64(buf: &[u8]) -> Option<u64> {
+    if buf.len() < 8 {
+        return None;
+    }
+
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[..8]);
+
+    Some(u64::from_be_bytes(bytes))
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Iterates over netlink attributes, returning (type, payload) pairs.
+pub fn attrs(buf: &[u8]) -> Vec<(u16, &[u8])> {
+    let mut res = Vec::new();
+    let mut pos = 0;
+
+    while pos + 4 <= buf.len() {
+        let len = read_u16(buf, pos) as usize;
+
+        if len < 4 || pos + len > buf.len() {
+            break;
+        }
+
+        res.push((read_u16(buf, pos + 2) & NLA_TYPE_MASK, &buf[pos + 4..pos + len]));
+
+        pos += align(len);
+    }
+
+    res
+}
+
+/// Splits buffer into nfnetlink messages of given subsystem.
+///
+/// Returns (message type, attributes payload) pairs and whether the end of
+/// dump (or an error) was seen.
+pub fn messages(buf: &[u8], subsys: u16) -> (Vec<(u16, &[u8])>, bool) {
+    let mut res = Vec::new();
+    let mut done = false;
+    let mut pos = 0;
+
+    while pos + NLMSG_HDRLEN <= buf.len() {
+        let len = read_u32(buf, pos) as usize;
+
+        if len < NLMSG_HDRLEN || pos + len > buf.len() {
+            break;
+        }
+
+        let msg_type = read_u16(buf, pos + 4);
+        let payload_start = pos + NLMSG_HDRLEN + NFGENMSG_LEN;
+
+        if msg_type == NLMSG_DONE || msg_type == NLMSG_ERROR {
+            done = true;
+        } else if msg_type >> 8 == subsys && payload_start <= pos + len {
+            res.push((msg_type & 0xff, &buf[payload_start..pos + len]));
+        }
+
+        pos += align(len);
+    }
+
+    (res, done)
+}
+
+/// Appends attribute to buffer, nested ones are built in separate buffer first.
+pub fn put_attr(buf: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    buf.extend_from_slice(&((data.len() + 4) as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(data);
+    buf.resize(align(buf.len()), 0);
+}
+
+/// Builds nfnetlink request of given subsystem, message type and family.
+pub fn build_msg(subsys: u16, msg: u16, flags: u16, family: u8, attrs: &[(u16, &[u8])]) -> Vec<u8> {
+    let mut buf = Vec::new();
+
+    // nlmsghdr, length is patched afterwards
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    buf.extend_from_slice(&((subsys << 8) | msg).to_ne_bytes());
+    buf.extend_from_slice(&(libc::NLM_F_REQUEST as u16 | flags).to_ne_bytes());
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+
+    // nfgenmsg
+    buf.push(family);
+    buf.push(0);
+    buf.extend_from_slice(&0u16.to_be_bytes());
+
+    for (kind, data) in attrs {
+        put_attr(&mut buf, *kind, data);
+    }
+
+    let len = buf.len() as u32;
+    buf[..4].copy_from_slice(&len.to_ne_bytes());
+
+    buf
+}
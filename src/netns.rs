@@ -0,0 +1,51 @@
+use libc;
+use std::{
+    fs::File,
+    io,
+    os::unix::io::AsRawFd,
+    path::{Path, PathBuf},
+};
+
+
+// Named namespaces of "ip netns add" are bind mounted in here
+const NETNS_RUN_DIR: &str = "/var/run/netns";
+
+
+/// Path of network namespace, given by iproute2 name or by path (e.g. "/proc/<pid>/ns/net").
+pub fn path(netns: &str) -> PathBuf {
+    if netns.contains('/') {
+        PathBuf::from(netns)
+    } else {
+        Path::new(NETNS_RUN_DIR).join(netns)
+    }
+}
+
+/// File of given namespace, when several of them are managed: "<path>.<namespace>", slashes
+/// of namespace path turned into underscores.
+pub fn file_path(path: &str, netns: &str) -> String {
+    format!("{}.{}", path, netns.trim_matches('/').replace('/', "_"))
+}
+
+/// Moves calling thread into network namespace.
+///
+/// Sockets belong to namespace of the thread which creates them, and threads
+/// spawned afterwards inherit it, thus this is to be called before any socket is
+/// opened or thread is spawned.
+pub fn enter(netns: &str) -> io::Result<()> {
+    let file = File::open(path(netns))?;
+
+    if unsafe { libc::setns(file.as_raw_fd(), libc::CLONE_NEWNET) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[test]
+fn path_test() {
+    assert_eq!(path("rig1"), PathBuf::from("/var/run/netns/rig1"));
+    assert_eq!(path("/proc/42/ns/net"), PathBuf::from("/proc/42/ns/net"));
+
+    assert_eq!(file_path("/run/netcontrol.status", "rig1"), "/run/netcontrol.status.rig1");
+    assert_eq!(file_path("/run/netcontrol.status", "/proc/42/ns/net"), "/run/netcontrol.status.proc_42_ns_net");
+}
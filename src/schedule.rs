@@ -0,0 +1,24 @@
+use chrono::{DateTime, Local};
+use std::{
+    thread,
+    time::Duration,
+};
+
+
+// How often scheduled state is re-evaluated
+pub const SCHEDULE_TICK: Duration = Duration::from_secs(15);
+
+/// Spawns a thread, which calls `f` with current local time right away and on each tick.
+///
+/// Callback is expected to compare state against the clock (not to count
+/// ticks), so that missed ticks, clock jumps and DST changes do no harm.
+pub fn start<F>(tick: Duration, mut f: F) -> thread::JoinHandle<()>
+where F: FnMut(&DateTime<Local>) + Send + 'static {
+    thread::spawn(move || {
+        loop {
+            f(&Local::now());
+
+            thread::sleep(tick);
+        }
+    })
+}
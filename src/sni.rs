@@ -0,0 +1,212 @@
+use libc;
+use std::net::Ipv4Addr;
+
+
+const IPV4_MIN_HEADER_LEN: usize = 20;
+const TCP_MIN_HEADER_LEN: usize = 20;
+
+const TLS_HANDSHAKE: u8 = 0x16;
+const TLS_CLIENT_HELLO: u8 = 0x01;
+const TLS_EXT_SERVER_NAME: u16 = 0;
+const TLS_SERVER_NAME_HOST: u8 = 0;
+
+
+/// TCP flow of the packet, as seen in original direction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Flow {
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    pub sport: u16,
+    pub dport: u16,
+}
+
+/// Splits IPv4 TCP packet into flow and TCP payload.
+pub fn parse_packet(packet: &[u8]) -> Option<(Flow, &[u8])> {
+    if packet.len() < IPV4_MIN_HEADER_LEN || packet[0] >> 4 != 4 || packet[9] != libc::IPPROTO_TCP as u8 {
+        return None;
+    }
+
+    let ip_len = (packet[0] & 0x0f) as usize * 4;
+    // Total length, trailing padding is not payload
+    let total_len = (u16::from_be_bytes([packet[2], packet[3]]) as usize).min(packet.len());
+    let tcp = packet.get(ip_len..total_len)?;
+
+    if tcp.len() < TCP_MIN_HEADER_LEN {
+        return None;
+    }
+
+    let tcp_len = (tcp[12] >> 4) as usize * 4;
+
+    let flow = Flow {
+        src: Ipv4Addr::new(packet[12], packet[13], packet[14], packet[15]),
+        dst: Ipv4Addr::new(packet[16], packet[17], packet[18], packet[19]),
+        sport: u16::from_be_bytes([tcp[0], tcp[1]]),
+        dport: u16::from_be_bytes([tcp[2], tcp[3]]),
+    };
+
+    Some((flow, tcp.get(tcp_len..)?))
+}
+
+// Bounds checked reader over TLS message
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let bytes = self.bytes(3)?;
+        Some((bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize)
+    }
+
+    // Vector with length prefix of given size
+    fn vec8(&mut self) -> Option<&'a [u8]> {
+        let len = self.u8()? as usize;
+        self.bytes(len)
+    }
+
+    fn vec16(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        self.bytes(len)
+    }
+
+    // Vector with 16-bit length prefix, cut at the end of buffer
+    fn vec16_partial(&mut self) -> Option<&'a [u8]> {
+        let len = self.u16()? as usize;
+        let end = (self.pos + len).min(self.buf.len());
+        let bytes = &self.buf[self.pos..end];
+        self.pos = end;
+        Some(bytes)
+    }
+}
+
+/// Extracts server name from TLS ClientHello, which starts in given payload.
+pub fn parse_sni(payload: &[u8]) -> Option<String> {
+    let mut record = Reader { buf: payload, pos: 0 };
+
+    if record.u8()? != TLS_HANDSHAKE {
+        return None;
+    }
+
+    // Record version, then length, which might exceed single segment
+    record.u16()?;
+    record.u16()?;
+
+    if record.u8()? != TLS_CLIENT_HELLO {
+        return None;
+    }
+
+    record.u24()?;
+    // Client version and random
+    record.bytes(2 + 32)?;
+    // Session id, cipher suites and compression methods
+    record.vec8()?;
+    record.vec16()?;
+    record.vec8()?;
+
+    // Large key shares push the rest of ClientHello into next segments, extensions in this one
+    // are walked until the first cut one
+    let mut extensions = Reader { buf: record.vec16_partial()?, pos: 0 };
+
+    while let (Some(kind), Some(data)) = (extensions.u16(), extensions.vec16()) {
+        if kind != TLS_EXT_SERVER_NAME {
+            continue;
+        }
+
+        let mut names = Reader { buf: data, pos: 0 };
+        let mut list = Reader { buf: names.vec16()?, pos: 0 };
+
+        while let (Some(name_type), Some(name)) = (list.u8(), list.vec16()) {
+            if name_type == TLS_SERVER_NAME_HOST {
+                return String::from_utf8(name.to_vec()).ok().map(|name| name.to_ascii_lowercase());
+            }
+        }
+    }
+
+    None
+}
+
+/// Extracts host from HTTP request header, port is stripped.
+pub fn parse_host(payload: &[u8]) -> Option<String> {
+    let text = std::str::from_utf8(payload.get(..payload.len().min(4096))?).ok()?;
+    let mut lines = text.split("\r\n");
+
+    // "GET / HTTP/1.1"
+    if !lines.next()?.split(' ').last()?.starts_with("HTTP/") {
+        return None;
+    }
+
+    for line in lines.take_while(|line| !line.is_empty()) {
+        if let Some((key, value)) = line.split_once(':') {
+            if key.trim().eq_ignore_ascii_case("host") {
+                let host = value.trim();
+                let host = host.rsplit_once(':').map_or(host, |(host, _)| host);
+
+                return Some(host.to_ascii_lowercase());
+            }
+        }
+    }
+
+    None
+}
+
+/// Finds domain, which the first payload of the flow is addressed to.
+pub fn classify(payload: &[u8]) -> Option<String> {
+    parse_sni(payload).or_else(|| parse_host(payload))
+}
+
+
+#[test]
+fn corpus_test() {
+    // Raw IPv4 (LINKTYPE_RAW) captures, with expected name of the first packet
+    let corpus = [
+        ("testdata/sni/tls_client_hello.pcap", Some("www.netflix.com")),
+        ("testdata/sni/tls_no_sni.pcap", None),
+        // ClientHello with post-quantum key share, split into two segments
+        ("testdata/sni/tls_split_hello.pcap", Some("chatgpt.com")),
+        ("testdata/sni/http_get.pcap", Some("example.com")),
+        ("testdata/sni/tcp_ack.pcap", None),
+    ];
+
+    for (path, expected) in corpus.iter() {
+        let pcap = std::fs::read(path).unwrap();
+
+        // Global header, then record header of the first packet
+        assert_eq!(&pcap[..4], &[0xd4, 0xc3, 0xb2, 0xa1]);
+        let len = u32::from_le_bytes([pcap[32], pcap[33], pcap[34], pcap[35]]) as usize;
+        let packet = &pcap[40..40 + len];
+
+        let (flow, payload) = parse_packet(packet).unwrap();
+
+        assert!(flow.dport == 443 || flow.dport == 80, "{}", path);
+        assert_eq!(classify(payload).as_deref(), *expected, "{}", path);
+    }
+}
+
+#[test]
+fn truncated_test() {
+    let pcap = std::fs::read("testdata/sni/tls_client_hello.pcap").unwrap();
+    let (_, payload) = parse_packet(&pcap[40..]).unwrap();
+
+    // Cut anywhere, parser gives up instead of panicking
+    for len in 0..payload.len() {
+        let _ = classify(&payload[..len]);
+    }
+
+    assert_eq!(parse_host(b"GET / HTTP/1.1\r\nHost: example.com:8080\r\n\r\n").as_deref(), Some("example.com"));
+}
@@ -1,81 +1,108 @@
-
 use timer;
 use chrono;
 use std::{
     fmt,
     sync::Arc,
     sync::Mutex,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 
-type Callback<'a> = Box<dyn Fn() + Sync + 'a>;
+type Callback = Arc<dyn Fn() + Send + Sync + 'static>;
 
-pub struct ConnTimer<'a> {
+pub struct ConnTimer {
     timer: Option<timer::Timer>,
     guard: Option<timer::Guard>,
     target_secs: u64,
     current_secs: Arc<Mutex<u64>>,
     active: bool,
-    cb: Option<Box<Callback<'a>>>,
+    cb: Arc<Mutex<Option<Callback>>>,
+    // Activity mode: seconds are counted only while packets flow
+    idle: Option<Duration>,
+    last_activity: Arc<Mutex<Option<Instant>>>,
 }
 
-impl fmt::Debug for ConnTimer<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {        
+impl fmt::Debug for ConnTimer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("ConnTimer")
             .field("target", &self.target_secs)
             .field("current", &self.current_secs)
             .field("active", &self.active)
-            .field("has_callback", &self.cb.is_some())
+            .field("idle", &self.idle)
+            .field("has_callback", &self.cb.lock().unwrap().is_some())
             .finish()
     }
 }
 
-// unsafe impl Send for ConnTimer<'_> {}
-unsafe impl Sync for ConnTimer<'_> {}
-
-impl<'a> ConnTimer<'a> {
+impl ConnTimer {
     pub fn new(target: &Duration) -> ConnTimer {
         let dur_cp = target.clone();
-        
+
         ConnTimer {
             timer: None,
             guard: None,
             target_secs: dur_cp.as_secs() as u64,
             current_secs: Arc::new(Mutex::new(0 as u64)),
             active: false,
-            cb: None,
+            cb: Arc::new(Mutex::new(None)),
+            idle: None,
+            last_activity: Arc::new(Mutex::new(None)),
         }
     }
 
-    pub fn set_callback(&self, cb: Callback<'a>) {
-        let cb_box = Box::new(cb);
-        self.cb = Some(cb_box);
+    /// Switches timer to activity mode: clock pauses after `idle` without activity.
+    pub fn set_idle(&mut self, idle: &Duration) {
+        self.idle = Some(idle.clone());
+    }
+
+    /// Marks activity moment, meaningful in activity mode only.
+    pub fn touch(&self) {
+        *self.last_activity.lock().unwrap() = Some(Instant::now());
+    }
+
+    pub fn set_callback(&mut self, cb: Callback) {
+        *self.cb.lock().unwrap() = Some(cb);
+    }
+
+    pub fn clear_callback(&mut self) {
+        *self.cb.lock().unwrap() = None;
     }
 
-    pub fn clear_callback(&self) {
-        self.cb = None;
+    pub fn is_active(&self) -> bool {
+        self.active
     }
 
-    pub fn start(&self) {
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_secs(*self.current_secs.lock().unwrap())
+    }
+
+    pub fn start(&mut self) {
+        if self.active {
+            return;
+        }
+
         let timer = timer::Timer::new();
         let guard = {
             let count = self.current_secs.clone();
+            let cb = self.cb.clone();
+            let target = self.target_secs;
+            let idle = self.idle;
+            let last_activity = self.last_activity.clone();
+            // Seconds counted since last activity, given back when idle gap is reached
+            let mut tail: u64 = 0;
 
             timer.schedule_repeating(chrono::Duration::seconds(1), move || {
-                *count.lock().unwrap() += 1;
-
-                if *count.lock().unwrap() >= self.target_secs {
-                    match self.cb {
-                        Some(cb) => {
-                            // unsafe {
-                            //     cb();
-                            // }
-                        },
-                        None => (),
-                    }
-
-                    self.stop();
+                let since = last_activity.lock().unwrap().map(|last| last.elapsed());
+
+                if !tick(&mut count.lock().unwrap(), target, idle, since, &mut tail) {
+                    return;
+                }
+
+                // Callback takes locks of its own, thus none of timer ones is held meanwhile
+                let cb = cb.lock().unwrap().clone();
+
+                if let Some(cb) = cb {
+                    cb();
                 }
             })
         };
@@ -85,7 +112,7 @@ impl<'a> ConnTimer<'a> {
         self.active = true;
     }
 
-    pub fn stop(&self) {
+    pub fn stop(&mut self) {
         self.guard = None;
         self.timer = None;
         self.active = false;
@@ -96,3 +123,77 @@ impl<'a> ConnTimer<'a> {
         *count.lock().unwrap() = 0;
     }
 }
+
+// Counts one second of the clock, unless activity mode is idle (`since` is time since
+// last activity). Returns true, when the second reaches the target
+fn tick(count: &mut u64, target: u64, idle: Option<Duration>, since: Option<Duration>, tail: &mut u64) -> bool {
+    // Target already reached, waiting for stop() or reset()
+    if *count >= target {
+        return false;
+    }
+
+    if let Some(idle) = idle {
+        let since = match since {
+            Some(since) => since,
+            None => return false,
+        };
+
+        if since > idle {
+            // Idle gap is not usage, thus pause and undo its seconds
+            *count -= (*tail).min(*count);
+            *tail = 0;
+            return false;
+        }
+
+        if since >= Duration::from_secs(1) {
+            *tail += 1;
+        } else {
+            *tail = 0;
+        }
+    }
+
+    *count += 1;
+
+    *count >= target
+}
+
+
+#[test]
+fn tick_test() {
+    let secs = Duration::from_secs;
+    let (mut count, mut tail) = (0, 0);
+
+    // Session clock counts every second up to the target
+    assert!(!tick(&mut count, 3, None, None, &mut tail));
+    assert!(!tick(&mut count, 3, None, None, &mut tail));
+    assert!(tick(&mut count, 3, None, None, &mut tail));
+    assert!(!tick(&mut count, 3, None, None, &mut tail));
+    assert_eq!(count, 3);
+
+    // Activity clock does not start before first packet
+    let (mut count, mut tail) = (0, 0);
+    assert!(!tick(&mut count, 100, Some(secs(5)), None, &mut tail));
+    assert_eq!(count, 0);
+
+    // Seconds after last packet are counted, while the gap is short
+    assert!(!tick(&mut count, 100, Some(secs(5)), Some(Duration::from_millis(200)), &mut tail));
+    for since in 1..=5 {
+        assert!(!tick(&mut count, 100, Some(secs(5)), Some(secs(since)), &mut tail));
+    }
+    assert_eq!((count, tail), (6, 5));
+
+    // Gap is over idle, its seconds are given back and clock pauses
+    assert!(!tick(&mut count, 100, Some(secs(5)), Some(secs(6)), &mut tail));
+    assert_eq!((count, tail), (1, 0));
+    assert!(!tick(&mut count, 100, Some(secs(5)), Some(secs(7)), &mut tail));
+    assert_eq!(count, 1);
+
+    // New packet resumes the clock and resets the tail
+    assert!(!tick(&mut count, 100, Some(secs(5)), Some(secs(2)), &mut tail));
+    assert!(!tick(&mut count, 100, Some(secs(5)), Some(Duration::from_millis(100)), &mut tail));
+    assert_eq!((count, tail), (3, 0));
+
+    // Target is reached by activity too
+    let (mut count, mut tail) = (1, 0);
+    assert!(tick(&mut count, 2, Some(secs(5)), Some(Duration::from_millis(100)), &mut tail));
+}